@@ -0,0 +1,573 @@
+//! Serial loop running on a tokio runtime, so other tasks (network servers,
+//! file watchers) can share the thread with the port instead of blocking on it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, trace, warn};
+use tokio::time::MissedTickBehavior;
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+use crate::clock::SystemClock;
+use crate::connection::{Connection, ConnectionState, StateLog};
+use crate::control::Control;
+use crate::dto::dto::{Configuration, InMessage, OutMessage};
+use crate::reload::Reloader;
+use crate::repeated_log::RepeatedLog;
+use crate::self_test;
+use crate::settings::Settings;
+use crate::shared;
+use crate::shutdown::Shutdown;
+use crate::source::DataSource;
+use crate::stats;
+use crate::transport::Resynchronizer;
+use crate::{
+    find_port_name, handle_error, handle_message, logged_json, parse_message,
+    resend_unacknowledged, shutdown_messages, Error, RetryBudget,
+};
+
+/// Longest line accepted from the firmware before the frame is rejected.
+const MAX_FRAME_LENGTH: usize = 4096;
+
+/// How often reloads, control commands, page switches and overdue acks are
+/// checked, so they go out even while the firmware is quiet.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+type Frames = Framed<SerialStream, LinesCodec>;
+
+impl From<LinesCodecError> for Error {
+    fn from(error: LinesCodecError) -> Self {
+        match error {
+            LinesCodecError::Io(error) => {
+                return Error::IO(error);
+            }
+            LinesCodecError::MaxLineLengthExceeded => {
+                return Error::IO(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "frame exceeds maximum length",
+                ));
+            }
+        }
+    }
+}
+
+pub fn run(
+    settings: &Settings,
+    configuration: &mut Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start tokio runtime");
+
+    // Only returns on shutdown or once out of reconnects.
+    runtime.block_on(serve_forever(
+        settings,
+        configuration,
+        reloader,
+        control,
+        shutdown,
+        source,
+    ));
+}
+
+async fn serve_forever(
+    settings: &Settings,
+    configuration: &mut Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+) {
+    let mut reconnect_log = RepeatedLog::new();
+    let mut states = StateLog::new();
+
+    while !shutdown.requested() {
+        match open_port(settings, &mut states).await {
+            Some(port) => {
+                reconnect_log.flush();
+                if let Err(error) = serve_port(
+                    port,
+                    settings,
+                    configuration,
+                    reloader,
+                    control,
+                    shutdown,
+                    source,
+                    &mut states,
+                )
+                .await
+                {
+                    error!("Abandoning port: {}", error);
+                }
+                states.enter(ConnectionState::Disconnected);
+
+                if shutdown.requested() || states.out_of_reconnects(settings) {
+                    return;
+                }
+            }
+            None => {
+                states.enter(ConnectionState::Searching);
+                reconnect_log.log("Waiting for port...");
+                stats::report_if_due(settings.stats_interval());
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn open_port(settings: &Settings, states: &mut StateLog) -> Option<SerialStream> {
+    let port_name = find_port_name(settings)?;
+
+    match tokio_serial::new(&port_name, settings.baud_rate).open_native_async() {
+        Ok(mut port) => {
+            states.enter(ConnectionState::Opening);
+
+            for (level, pause) in settings.dtr_sequence() {
+                if let Err(error) = port.write_data_terminal_ready(level) {
+                    error!("Error activating port: {}", error);
+                    return None;
+                }
+                tokio::time::sleep(pause).await;
+            }
+
+            info!("Port {} opened", port_name);
+            return Some(port);
+        }
+        Err(error) => {
+            error!("Failed to open port {}: {}", port_name, error);
+            return None;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_port(
+    port: SerialStream,
+    settings: &Settings,
+    configuration: &mut Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+    states: &mut StateLog,
+) -> Result<(), Error> {
+    let mut frames = Framed::new(port, LinesCodec::new_with_max_length(MAX_FRAME_LENGTH));
+    let mut retry_budget = RetryBudget::new(settings.io_retries);
+    let mut resynchronizer = Resynchronizer::new(settings.resync);
+    let mut connection = Connection::new(configuration, settings, Arc::new(SystemClock));
+    let mut updates = tokio::time::interval(UPDATE_INTERVAL);
+    updates.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_inbound = tokio::time::Instant::now();
+    states.start_session();
+
+    if let Some(timeout) = settings.ready_timeout() {
+        if !wait_until_ready(&mut frames, timeout).await {
+            warn!("Firmware not ready after {:?}; Abandoning port...", timeout);
+            return Ok(());
+        }
+    }
+
+    // Same as the blocking loop: the firmware gets its configuration as
+    // soon as the port is up, without waiting to be asked.
+    let begin = InMessage::NeedGaugeConfig {};
+    respond(
+        &mut frames,
+        &begin,
+        settings,
+        configuration,
+        &mut connection,
+        source,
+    )
+    .await?;
+
+    if settings.self_test {
+        info!("Running self-test");
+
+        let interval = self_test::frame_interval(settings.self_test_duration());
+        for data in self_test::sweep(configuration) {
+            write_message(&mut frames, OutMessage::Data { message: data }, settings).await?;
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    loop {
+        if shutdown.requested() {
+            info!("Shutting down");
+            // Sending flushes, so nothing is left buffered once these are out.
+            for out_message in shutdown_messages(settings, configuration, &connection) {
+                if let Err(error) = write_message(&mut frames, out_message, settings).await {
+                    warn!("Failed to send shutdown messages: {}", error);
+                }
+            }
+            return Ok(());
+        }
+
+        if retry_budget.failures > 0 {
+            states.enter(ConnectionState::Degraded);
+        } else {
+            states.enter(ConnectionState::Connected);
+        }
+        stats::report_if_due(settings.stats_interval());
+
+        // Reading is cancel-safe, so a tick, the keepalive or a shutdown
+        // request can cut the wait short without losing part of a frame.
+        let keepalive = settings.keepalive();
+        let next_frame = tokio::select! {
+            next_frame = frames.next() => next_frame,
+            _ = updates.tick() => {
+                poll_updates(
+                    &mut frames,
+                    settings,
+                    configuration,
+                    reloader,
+                    control,
+                    &mut connection,
+                    source,
+                    &mut retry_budget,
+                )
+                .await?;
+                continue;
+            }
+            () = expire(keepalive.map(|keepalive| last_inbound + keepalive)) => {
+                warn!(
+                    "No message from firmware for {:?}; Abandoning port...",
+                    keepalive.unwrap()
+                );
+                return Ok(());
+            }
+            () = shutdown.wait() => continue,
+        };
+
+        let Some(frame) = next_frame else {
+            break;
+        };
+
+        last_inbound = tokio::time::Instant::now();
+        let line = match frame {
+            Ok(line) => line,
+            Err(error) => {
+                handle_error(Error::from(error), &mut retry_budget)?;
+                continue;
+            }
+        };
+
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse_message(line) {
+            Ok(message) => {
+                stats::message_in();
+                debug!("InMessage: {}", message);
+                if !resynchronizer.accept(&message) {
+                    continue;
+                }
+
+                match respond(
+                    &mut frames,
+                    &message,
+                    settings,
+                    configuration,
+                    &mut connection,
+                    source,
+                )
+                .await
+                {
+                    Ok(()) => retry_budget.reset(),
+                    Err(error) => handle_error(error, &mut retry_budget)?,
+                }
+            }
+            Err(error) => {
+                resynchronizer.failed(&error);
+                handle_error(error, &mut retry_budget)?;
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/// Sends a configuration that was reloaded, switched or asked for, one
+/// whose acknowledgement is overdue, and the next pages once they are due.
+#[allow(clippy::too_many_arguments)]
+async fn poll_updates(
+    frames: &mut Frames,
+    settings: &Settings,
+    configuration: &mut Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    connection: &mut Connection,
+    source: &mut dyn DataSource,
+    retry_budget: &mut RetryBudget,
+) -> Result<(), Error> {
+    let mut replaced = reloader.poll();
+    let requests = control.poll(configuration);
+    if let Some(profile) = &requests.profile {
+        replaced = reloader.switch_profile(profile).or(replaced);
+    }
+    let resend = replaced.is_some() || requests.resend;
+    if let Some(mut reloaded) = replaced {
+        // Day or night carries over, as the headlights haven't changed.
+        reloaded.set_night(configuration.is_night());
+        *configuration = reloaded;
+        *connection = Connection::new(configuration, settings, Arc::clone(&connection.clock));
+    }
+    if resend {
+        let reload = InMessage::NeedGaugeConfig {};
+        if let Err(error) =
+            respond(frames, &reload, settings, configuration, connection, source).await
+        {
+            handle_error(error, retry_budget)?;
+        }
+    }
+
+    let mut out_messages = resend_unacknowledged(settings, configuration, connection, source);
+    out_messages.extend(connection.pages.poll(configuration, connection.clock.now()));
+    for out_message in out_messages {
+        if let Err(error) = write_message(frames, out_message, settings).await {
+            handle_error(error, retry_budget)?;
+        }
+    }
+
+    return Ok(());
+}
+
+/// Completes at `deadline`, or never without one.
+async fn expire(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits up to `timeout` for a frame that decodes, as the blocking loop
+/// does, dropping the frame itself.
+async fn wait_until_ready(frames: &mut Frames, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let line = match tokio::time::timeout_at(deadline, frames.next()).await {
+            Ok(Some(Ok(line))) => line,
+            Ok(Some(Err(error))) => {
+                warn!("Error while waiting for firmware: {}", Error::from(error));
+                return false;
+            }
+            Ok(None) | Err(_) => {
+                return false;
+            }
+        };
+
+        match parse_message(line) {
+            Ok(message) => {
+                info!("Firmware ready ({})", message);
+                return true;
+            }
+            Err(error) => debug!("Waiting for firmware: {}", error),
+        }
+    }
+}
+
+async fn respond(
+    frames: &mut Frames,
+    message: &InMessage,
+    settings: &Settings,
+    configuration: &mut Configuration,
+    connection: &mut Connection,
+    source: &mut dyn DataSource,
+) -> Result<(), Error> {
+    for out_message in handle_message(message, settings, configuration, connection, source) {
+        write_message(frames, out_message, settings).await?;
+    }
+
+    return Ok(());
+}
+
+/// Gives up after the port timeout, so firmware that stopped reading
+/// costs a retry instead of stalling the loop forever.
+async fn write_message(
+    frames: &mut Frames,
+    message: OutMessage,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let json = serde_json::to_string(&message).unwrap();
+    debug!("OutMessage: {}", message);
+    shared::publish(&message);
+    stats::message_out();
+    trace!(
+        "OutMessage JSON: {}",
+        logged_json(&message, &json, settings)
+    );
+
+    match tokio::time::timeout(settings.timeout(), frames.send(json)).await {
+        Ok(sent) => sent?,
+        Err(_) => {
+            return Err(Error::IO(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "firmware stopped reading",
+            )));
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::source::RandomSource;
+
+    /// Next frame from the backend as JSON, failing the test if none comes.
+    async fn next_frame(firmware: &mut Frames) -> serde_json::Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), firmware.next())
+            .await
+            .expect("backend answered in time")
+            .expect("port still open")
+            .unwrap();
+
+        return serde_json::from_str(&line).unwrap();
+    }
+
+    #[test]
+    fn serves_config_and_data_over_a_loopback() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let settings = Settings::default();
+        let mut configuration = crate::default_configuration();
+        let reloader = Reloader::new(None, None, &settings);
+        let control = Control::new(false);
+        let shutdown = Shutdown::new();
+        let mut source = RandomSource::new(1.0, Some(7));
+        let mut states = StateLog::new();
+
+        runtime.block_on(async {
+            let (firmware, port) = SerialStream::pair().expect("pty pair");
+            let mut firmware = Framed::new(firmware, LinesCodec::new());
+
+            let backend = serve_port(
+                port,
+                &settings,
+                &mut configuration,
+                &reloader,
+                &control,
+                &shutdown,
+                &mut source,
+                &mut states,
+            );
+            let firmware = async {
+                let configuration = next_frame(&mut firmware).await;
+                firmware.send("{\"type\":2}").await.unwrap();
+                let data = next_frame(&mut firmware).await;
+                shutdown.request();
+
+                (configuration, data)
+            };
+
+            let (served, (configuration, data)) = tokio::join!(backend, firmware);
+            assert!(served.is_ok());
+            assert_eq!(configuration["type"], 1);
+            assert_eq!(
+                configuration["message"]["display2"]["gauges"][0]["id"],
+                "oil"
+            );
+            assert_eq!(data["type"], 2);
+            assert_eq!(
+                data["message"]["display1"]["gauges"]
+                    .as_array()
+                    .unwrap()
+                    .len(),
+                1
+            );
+        });
+    }
+
+    /// The first two frames sent to firmware that never says anything,
+    /// calling `between` after the first.
+    fn frames_to_quiet_firmware(
+        settings: &Settings,
+        reloader: &Reloader,
+        between: impl FnOnce(),
+    ) -> (serde_json::Value, serde_json::Value) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut configuration = crate::default_configuration();
+        let control = Control::new(false);
+        let shutdown = Shutdown::new();
+        let mut source = RandomSource::new(1.0, Some(7));
+        let mut states = StateLog::new();
+
+        return runtime.block_on(async {
+            let (firmware, port) = SerialStream::pair().expect("pty pair");
+            let mut firmware = Framed::new(firmware, LinesCodec::new());
+
+            let backend = serve_port(
+                port,
+                settings,
+                &mut configuration,
+                reloader,
+                &control,
+                &shutdown,
+                &mut source,
+                &mut states,
+            );
+            let firmware = async {
+                let first = next_frame(&mut firmware).await;
+                between();
+                let second = next_frame(&mut firmware).await;
+                shutdown.request();
+
+                (first, second)
+            };
+
+            let (served, frames) = tokio::join!(backend, firmware);
+            assert!(served.is_ok());
+            frames
+        });
+    }
+
+    #[test]
+    fn overdue_ack_resends_without_a_request() {
+        let settings = Settings {
+            config_ack_timeout_ms: 200,
+            ..Settings::default()
+        };
+        let reloader = Reloader::new(None, None, &settings);
+
+        let (first, second) = frames_to_quiet_firmware(&settings, &reloader, || {});
+
+        assert_eq!(first["type"], 1);
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn changed_config_file_is_pushed_without_a_request() {
+        let config = include_str!("default_config.toml");
+        let path =
+            std::env::temp_dir().join(format!("gauges-{}-async-watch.toml", std::process::id()));
+        std::fs::write(&path, config).unwrap();
+        let settings = Settings::default();
+        let reloader = Reloader::new(Some(path.clone()), None, &settings);
+
+        let (first, second) = frames_to_quiet_firmware(&settings, &reloader, || {
+            std::fs::write(&path, config.replace("\"COOLANT\"", "\"WATER\"")).unwrap();
+        });
+
+        std::fs::remove_file(&path).unwrap();
+        let first_name = |frame: &serde_json::Value| {
+            return frame["message"]["display1"]["gauges"][0]["name"].clone();
+        };
+        assert_eq!(first_name(&first), "COOLANT");
+        assert_eq!(second["type"], 1);
+        assert_eq!(first_name(&second), "WATER");
+    }
+}
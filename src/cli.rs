@@ -0,0 +1,350 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::color::ColorDepth;
+use crate::config::ConfigFormat;
+use crate::source::SourceKind;
+use crate::transport::Resync;
+use crate::units::UnitSystem;
+
+/// Environment variables standing in for flags that weren't given, e.g.
+/// from a systemd unit.
+const ENV_VARS: [(&str, &str); 3] = [
+    ("GAUGES_PORT", "--port"),
+    ("GAUGES_BAUD", "--baud"),
+    ("GAUGES_CONFIG", "--config"),
+];
+
+fn env_help() -> String {
+    let variables: Vec<String> = ENV_VARS
+        .iter()
+        .map(|(variable, flag)| format!("  {:<15} {}", variable, flag))
+        .collect();
+
+    return format!(
+        "Environment:\n{}\n\nEach setting comes from the first of: the command line, these \
+         environment variables, the [settings] table of the config file, the built-in default.",
+        variables.join("\n")
+    );
+}
+
+/// Value of an environment variable, none when unset or empty.
+fn env_var(variable: &str) -> Result<Option<String>, String> {
+    match std::env::var(variable) {
+        Ok(value) if value.is_empty() => {
+            return Ok(None);
+        }
+        Ok(value) => {
+            return Ok(Some(value));
+        }
+        Err(std::env::VarError::NotPresent) => {
+            return Ok(None);
+        }
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return Err(format!("{}: not valid UTF-8", variable));
+        }
+    }
+}
+
+/// Serves gauge configuration and data to the gauge display firmware.
+#[derive(Parser)]
+#[command(version, after_help = env_help())]
+pub struct Cli {
+    /// Configuration file (TOML, or JSON/YAML for .json/.yaml files) with settings and gauge layout
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Format of the configuration file, overriding its extension
+    #[arg(long, value_enum)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Profile of the config file to start with, e.g. [profile.track]
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Serial port to use instead of the first one found; repeat to serve several ports at once
+    #[arg(long)]
+    pub port: Vec<String>,
+
+    /// Serial baud rate
+    #[arg(long)]
+    pub baud: Option<u32>,
+
+    /// Serial read/write timeout in milliseconds
+    #[arg(long)]
+    pub timeout_ms: Option<u64>,
+
+    /// Log level (off, error, warn, info, debug, trace); RUST_LOG takes precedence
+    #[arg(long)]
+    pub log_level: Option<log::LevelFilter>,
+
+    /// Pretty-print message JSON in trace logs
+    #[arg(long)]
+    pub pretty_log_json: bool,
+
+    /// Only log errors, ignoring RUST_LOG and --log-level
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log debug messages, or trace with -vv, ignoring RUST_LOG and --log-level
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Where gauge values come from
+    #[arg(long, value_enum)]
+    pub source: Option<SourceKind>,
+
+    /// Show every gauge offline if the source can't be opened, instead of exiting
+    #[arg(long)]
+    pub safe_mode: bool,
+
+    /// Swing of the random source, from 0 (steady) to 1 (whole range)
+    #[arg(long)]
+    pub random_noise: Option<f32>,
+
+    /// Seed for the random source, giving the same values on every run
+    #[arg(long)]
+    pub random_seed: Option<u64>,
+
+    /// Serial port of the OBD-II adapter for the obd2 source
+    #[arg(long)]
+    pub obd2_port: Option<String>,
+
+    /// Baud rate of the OBD-II adapter
+    #[arg(long)]
+    pub obd2_baud: Option<u32>,
+
+    /// SocketCAN interface for the can source
+    #[arg(long)]
+    pub can_interface: Option<String>,
+
+    /// Serial port of the GPS module for the gps source
+    #[arg(long)]
+    pub gps_port: Option<String>,
+
+    /// Baud rate of the GPS module
+    #[arg(long)]
+    pub gps_baud: Option<u32>,
+
+    /// Unix domain socket the socket source listens on
+    #[arg(long)]
+    pub socket_path: Option<PathBuf>,
+
+    /// Most data messages sent per second (0 = no limit)
+    #[arg(long)]
+    pub max_data_rate: Option<f64>,
+
+    /// Needle animation time in milliseconds sent with each data message (0 = off)
+    #[arg(long)]
+    pub tween_ms: Option<u64>,
+
+    /// Extrapolate gauges with update_ms between reads instead of repeating the last value
+    #[arg(long)]
+    pub predict: bool,
+
+    /// Color encoding the firmware expects
+    #[arg(long, value_enum)]
+    pub color_depth: Option<ColorDepth>,
+
+    /// Units the gauges are shown in, unless they set their own
+    #[arg(long, value_enum)]
+    pub unit_system: Option<UnitSystem>,
+
+    /// Display brightness from 0 (dimmest) to 255 (brightest)
+    #[arg(long)]
+    pub brightness: Option<i64>,
+
+    /// Reconnect after this many milliseconds without a firmware message (0 = never)
+    #[arg(long)]
+    pub keepalive_ms: Option<u64>,
+
+    /// Consecutive transient IO errors tolerated before abandoning the port
+    #[arg(long)]
+    pub io_retries: Option<u32>,
+
+    /// Exit with an error after losing the connection this many times
+    #[arg(long)]
+    pub max_reconnects: Option<u32>,
+
+    /// Resend the configuration if the firmware hasn't acknowledged it after this many milliseconds (0 = no acknowledgements)
+    #[arg(long)]
+    pub config_ack_timeout_ms: Option<u64>,
+
+    /// Resends of an unacknowledged or rejected configuration
+    #[arg(long)]
+    pub config_retries: Option<u32>,
+
+    /// Send every gauge offline before exiting on Ctrl-C or SIGTERM
+    #[arg(long)]
+    pub offline_on_exit: bool,
+
+    /// Read "hide <gauge id>", "show <gauge id>", "snapshot", "readings" and other commands from stdin
+    #[arg(long)]
+    pub control_stdin: bool,
+
+    /// Don't reload the config file when it changes (SIGHUP still reloads)
+    #[arg(long)]
+    pub no_watch_config: bool,
+
+    /// Log uptime, reconnects and message counts every this many milliseconds
+    #[arg(long)]
+    pub stats_interval_ms: Option<u64>,
+
+    /// How to recover from a frame that can't be decoded
+    #[arg(long, value_enum)]
+    pub resync: Option<Resync>,
+
+    /// Milliseconds to wait after asserting DTR before the first message
+    #[arg(long)]
+    pub startup_delay_ms: Option<u64>,
+
+    /// Wait up to this many milliseconds for a frame from the firmware before sending the configuration
+    #[arg(long)]
+    pub ready_timeout_ms: Option<u64>,
+
+    /// Pulse DTR low before asserting it, to reset boards that reset on DTR
+    #[arg(long)]
+    pub dtr_toggle: bool,
+
+    /// Sweep every gauge from min to max and back after connecting
+    #[arg(long)]
+    pub self_test: bool,
+
+    /// Duration of the self-test sweep in milliseconds
+    #[arg(long)]
+    pub self_test_ms: Option<u64>,
+
+    /// Print the replies to a config and a data request and exit, without opening a port
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Send the configuration and one data sample to the port, then exit
+    #[arg(long)]
+    pub once: bool,
+
+    /// List available serial ports with device details and exit
+    #[arg(long)]
+    pub list_ports: bool,
+
+    /// Print the JSON Schema of the wire protocol and exit
+    #[arg(long)]
+    pub print_schema: bool,
+
+    /// Print the settings and configuration in effect, after the config
+    /// file and command line are applied, and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Check the configuration file and exit without opening a port
+    #[arg(long)]
+    pub validate_config: bool,
+
+    /// Write the default settings and built-in gauges to a config file and exit
+    #[arg(long, value_name = "PATH")]
+    pub init_config: Option<PathBuf>,
+
+    /// Let --init-config overwrite an existing file
+    #[arg(long, requires = "init_config")]
+    pub force: bool,
+
+    /// Use the blocking serial loop instead of the async one
+    #[cfg(feature = "async")]
+    #[arg(long)]
+    pub sync: bool,
+}
+
+impl Cli {
+    /// Fills `--port`, `--baud` and `--config` from `ENV_VARS` when they
+    /// weren't given, rejecting values that would only fail later.
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if self.port.is_empty() {
+            if let Some(ports) = env_var("GAUGES_PORT")? {
+                // Comma-separated to serve several ports, like repeated --port.
+                self.port = ports
+                    .split(',')
+                    .map(|port| port.trim().to_string())
+                    .collect();
+            }
+        }
+
+        if self.baud.is_none() {
+            if let Some(baud) = env_var("GAUGES_BAUD")? {
+                match baud.trim().parse::<u32>() {
+                    Ok(baud) if baud > 0 => self.baud = Some(baud),
+                    _ => {
+                        return Err(format!(
+                            "GAUGES_BAUD: invalid baud rate \"{}\", expected a positive number",
+                            baud
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.config.is_none() {
+            if let Some(config) = env_var("GAUGES_CONFIG")? {
+                let path = PathBuf::from(config);
+                if !path.is_file() {
+                    return Err(format!(
+                        "GAUGES_CONFIG: {} does not exist or is not a file",
+                        path.display()
+                    ));
+                }
+                self.config = Some(path);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Log level forced by -q or -v, which win over RUST_LOG and the
+    /// configured level.
+    pub fn verbosity(&self) -> Option<log::LevelFilter> {
+        if self.quiet {
+            return Some(log::LevelFilter::Error);
+        }
+
+        match self.verbose {
+            0 => {
+                return None;
+            }
+            1 => {
+                return Some(log::LevelFilter::Debug);
+            }
+            _ => {
+                return Some(log::LevelFilter::Trace);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn verbosity(args: &[&str]) -> Option<log::LevelFilter> {
+        let cli =
+            Cli::try_parse_from(std::iter::once("car_pc").chain(args.iter().copied())).unwrap();
+        return cli.verbosity();
+    }
+
+    #[test]
+    fn verbose_flags_escalate() {
+        assert_eq!(verbosity(&[]), None);
+        assert_eq!(verbosity(&["-v"]), Some(log::LevelFilter::Debug));
+        assert_eq!(verbosity(&["-vv"]), Some(log::LevelFilter::Trace));
+        assert_eq!(
+            verbosity(&["-v", "-v", "-v"]),
+            Some(log::LevelFilter::Trace)
+        );
+    }
+
+    #[test]
+    fn quiet_means_errors_only() {
+        assert_eq!(verbosity(&["-q"]), Some(log::LevelFilter::Error));
+        assert_eq!(verbosity(&["--quiet"]), Some(log::LevelFilter::Error));
+    }
+}
@@ -0,0 +1,77 @@
+//! Time as seen by the serving code. Rate limits, page rotation, keepalive
+//! and data timestamps all read it, so they can be driven by a clock that
+//! is moved by hand instead of by waiting on the real one.
+
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for measuring intervals.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock milliseconds since the Unix epoch, for timestamps.
+    fn unix_ms(&self) -> u64;
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+
+    fn unix_ms(&self) -> u64 {
+        return SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+    }
+}
+
+/// A clock that stands still until advanced, making timing-dependent
+/// behaviour repeatable.
+#[cfg(test)]
+pub struct MockClock {
+    start: Instant,
+    start_unix_ms: u64,
+    elapsed: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(start_unix_ms: u64) -> MockClock {
+        return MockClock {
+            start: Instant::now(),
+            start_unix_ms,
+            elapsed: Mutex::new(Duration::ZERO),
+        };
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self
+            .elapsed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        return *self
+            .elapsed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        return self.start + self.elapsed();
+    }
+
+    fn unix_ms(&self) -> u64 {
+        return self.start_unix_ms + self.elapsed().as_millis() as u64;
+    }
+}
@@ -0,0 +1,280 @@
+//! 24-bit colors and their packing into the integers the firmware expects.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Serialize};
+
+use crate::dto::dto::NAMED_COLORS;
+
+/// Encoding used for every color sent to the firmware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorDepth {
+    /// 16-bit `0bRRRRRGGGGGGBBBBB`, understood by all firmware versions.
+    Rgb565,
+    /// 24-bit `0xRRGGBB`, for displays that can show it.
+    Rgb888,
+}
+
+static EMIT_RGB888: AtomicBool = AtomicBool::new(false);
+
+/// Selects the encoding for all subsequently serialized colors.
+pub fn set_color_depth(depth: ColorDepth) {
+    EMIT_RGB888.store(depth == ColorDepth::Rgb888, Ordering::Relaxed);
+}
+
+fn color_depth() -> ColorDepth {
+    if EMIT_RGB888.load(Ordering::Relaxed) {
+        return ColorDepth::Rgb888;
+    }
+
+    return ColorDepth::Rgb565;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// Scales `value` from `0..=from_max` to `0..=to_max`, rounding to nearest.
+const fn rescale(value: u32, from_max: u32, to_max: u32) -> u32 {
+    return (value * to_max + from_max / 2) / from_max;
+}
+
+impl Color {
+    pub const fn from_rgb565(value: u16) -> Color {
+        let value = value as u32;
+
+        return Color {
+            r: rescale((value >> 11) & 0x1F, 0x1F, 0xFF) as u8,
+            g: rescale((value >> 5) & 0x3F, 0x3F, 0xFF) as u8,
+            b: rescale(value & 0x1F, 0x1F, 0xFF) as u8,
+        };
+    }
+
+    pub const fn to_rgb565(self) -> u16 {
+        let r = rescale(self.r as u32, 0xFF, 0x1F);
+        let g = rescale(self.g as u32, 0xFF, 0x3F);
+        let b = rescale(self.b as u32, 0xFF, 0x1F);
+
+        return ((r << 11) | (g << 5) | b) as u16;
+    }
+
+    pub const fn to_rgb888(self) -> u32 {
+        return ((self.r as u32) << 16) | ((self.g as u32) << 8) | self.b as u32;
+    }
+
+    /// Perceived brightness, 0 for black to 1 for white.
+    pub fn luminance(self) -> f32 {
+        return (0.299 * self.r as f32 + 0.587 * self.g as f32 + 0.114 * self.b as f32) / 255.0;
+    }
+
+    /// Linear blend from `from` (at 0) to `to` (at 1).
+    pub fn lerp(from: Color, to: Color, t: f32) -> Color {
+        let blend = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+
+        return Color {
+            r: blend(from.r, to.r),
+            g: blend(from.g, to.g),
+            b: blend(from.b, to.b),
+        };
+    }
+
+    pub fn encode(self, depth: ColorDepth) -> u32 {
+        match depth {
+            ColorDepth::Rgb565 => {
+                return self.to_rgb565() as u32;
+            }
+            ColorDepth::Rgb888 => {
+                return self.to_rgb888();
+            }
+        }
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        return s.serialize_u32(self.encode(color_depth()));
+    }
+}
+
+/// A color as written in the config file, before it is checked: a bare
+/// RGB565 integer, `#RRGGBB` or a name from `NAMED_COLORS`.
+#[derive(Clone, Debug)]
+pub enum ColorSpec {
+    Packed(u64),
+    Text(String),
+}
+
+impl ColorSpec {
+    pub fn resolve(&self) -> Result<Color, String> {
+        match self {
+            ColorSpec::Packed(value) => match u16::try_from(*value) {
+                Ok(value) => {
+                    return Ok(Color::from_rgb565(value));
+                }
+                Err(_) => {
+                    return Err(format!(
+                        "color {} is out of the RGB565 range, use \"#RRGGBB\" for 24-bit colors",
+                        value
+                    ));
+                }
+            },
+            ColorSpec::Text(text) => {
+                return text.parse();
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Color {
+    type Err = String;
+
+    /// `#RRGGBB` or a name from `NAMED_COLORS`, in any case.
+    fn from_str(text: &str) -> Result<Color, String> {
+        let text = text.trim();
+
+        if let Some(hex) = text.strip_prefix('#') {
+            if hex.len() == 6 {
+                if let Ok(value) = u32::from_str_radix(hex, 16) {
+                    return Ok(Color {
+                        r: (value >> 16) as u8,
+                        g: (value >> 8) as u8,
+                        b: value as u8,
+                    });
+                }
+            }
+        }
+
+        for (name, value) in NAMED_COLORS {
+            if text.eq_ignore_ascii_case(name) {
+                return Ok(Color::from_rgb565(value));
+            }
+        }
+
+        let names: Vec<&str> = NAMED_COLORS.iter().map(|(name, _)| *name).collect();
+        return Err(format!(
+            "invalid color \"{}\", expected \"#RRGGBB\", an RGB565 integer or one of {}",
+            text,
+            names.join(", ")
+        ));
+    }
+}
+
+struct ColorSpecVisitor;
+
+impl<'de> Visitor<'de> for ColorSpecVisitor {
+    type Value = ColorSpec;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "an RGB565 integer, \"#RRGGBB\" or a color name");
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<ColorSpec, E> {
+        return Ok(ColorSpec::Packed(value));
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<ColorSpec, E> {
+        match u64::try_from(value) {
+            Ok(value) => {
+                return Ok(ColorSpec::Packed(value));
+            }
+            Err(_) => {
+                return Err(E::custom(format!("color {} is negative", value)));
+            }
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<ColorSpec, E> {
+        return Ok(ColorSpec::Text(text.to_string()));
+    }
+}
+
+impl<'de> Deserialize<'de> for ColorSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        return d.deserialize_any(ColorSpecVisitor);
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    /// Bare integers are RGB565, as in configurations written before
+    /// colors were stored with 24 bits; strings are `#RRGGBB` or a name.
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        return ColorSpec::deserialize(d)?
+            .resolve()
+            .map_err(de::Error::custom);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> Color {
+        return Color { r, g, b };
+    }
+
+    #[test]
+    fn rgb888_packs_to_rgb565() {
+        assert_eq!(rgb(0, 0, 0).to_rgb565(), 0x0000);
+        assert_eq!(rgb(255, 255, 255).to_rgb565(), 0xFFFF);
+        assert_eq!(rgb(255, 0, 0).to_rgb565(), 0xF800);
+        assert_eq!(rgb(0, 255, 0).to_rgb565(), 0x07E0);
+        assert_eq!(rgb(0, 0, 255).to_rgb565(), 0x001F);
+        assert_eq!(rgb(128, 128, 128).to_rgb565(), 0x8410);
+    }
+
+    #[test]
+    fn packing_rounds_to_nearest() {
+        // 4 of 255 is nearer 0 of 31, 5 of 255 nearer 1 of 31.
+        assert_eq!(rgb(4, 0, 0).to_rgb565(), 0x0000);
+        assert_eq!(rgb(5, 0, 0).to_rgb565(), 0x0800);
+        // Green has twice the steps: 2 rounds down, 3 up.
+        assert_eq!(rgb(0, 2, 0).to_rgb565(), 0x0000);
+        assert_eq!(rgb(0, 3, 0).to_rgb565(), 0x0020);
+    }
+
+    #[test]
+    fn encodes_at_either_depth() {
+        let orange = rgb(0xFF, 0x88, 0x00);
+
+        assert_eq!(orange.encode(ColorDepth::Rgb565), 0xFC40);
+        assert_eq!(orange.encode(ColorDepth::Rgb888), 0xFF8800);
+    }
+
+    #[test]
+    fn every_rgb565_value_round_trips() {
+        for value in 0..=u16::MAX {
+            assert_eq!(Color::from_rgb565(value).to_rgb565(), value);
+        }
+    }
+
+    /// What a config file color goes out to the firmware as.
+    fn sent_as(config: serde_json::Value) -> serde_json::Value {
+        let color: Color = serde_json::from_value(config).unwrap();
+        return serde_json::to_value(color).unwrap();
+    }
+
+    #[test]
+    fn config_colors_round_trip_to_rgb565() {
+        assert_eq!(sent_as(serde_json::json!("#FF0000")), 0xF800);
+        assert_eq!(sent_as(serde_json::json!("#FFFFFF")), 0xFFFF);
+        assert_eq!(sent_as(serde_json::json!("#ff8800")), 0xFC40);
+        assert_eq!(sent_as(serde_json::json!("RED")), 0xF800);
+        // Bare integers are already RGB565.
+        assert_eq!(sent_as(serde_json::json!(0xFC00)), 0xFC00);
+    }
+
+    #[test]
+    fn invalid_colors_are_refused() {
+        for text in ["#FF00", "#GG0000", "chartreuse", ""] {
+            assert!(text.parse::<Color>().is_err(), "{}", text);
+        }
+        assert!(ColorSpec::Packed(0x1_0000).resolve().is_err());
+        assert!(serde_json::from_value::<Color>(serde_json::json!(-1)).is_err());
+    }
+}
@@ -0,0 +1,724 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use serde::de::{self, DeserializeOwned, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+
+use crate::dto::dto::Configuration;
+use crate::settings::Settings;
+use crate::Error;
+
+/// Contents of the config file: an optional `[settings]` table next to the
+/// gauge configuration, laid out like the `Configuration` message, and
+/// any number of `[profile.<name>]` alternatives to it.
+pub struct ConfigFile {
+    pub settings: Settings,
+    pub configuration: Configuration,
+    /// Named layouts, e.g. street and track, each with its own displays.
+    /// A profile without a theme or night theme uses the top-level one.
+    pub profiles: BTreeMap<String, Configuration>,
+}
+
+/// Layout of config files this backend reads and writes. Files without a
+/// `schema_version` predate it and have the first layout, with the theme's
+/// keys at the top level of the file; the second moved them into `[theme]`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Upgrades of older layouts, applied to the parsed file before it is
+/// read into the DTOs: `MIGRATIONS[n]` takes version n + 1 to n + 2,
+/// renaming or restructuring whatever changed and leaving new fields to
+/// their defaults.
+const MIGRATIONS: [fn(&mut serde_json::Value); SCHEMA_VERSION as usize - 1] = [nest_theme];
+
+/// Keys of `[theme]` that version 1 files had at the top level.
+const FLAT_THEME_KEYS: [&str; 8] = [
+    "preset",
+    "ok_color",
+    "low_color",
+    "high_color",
+    "alert_color",
+    "background_color",
+    "accent_color",
+    "gradient",
+];
+
+/// Version 1 to 2: theme keys move from the top level, of the file and of
+/// each profile, into the `theme` table. Keys already in the table win.
+fn nest_theme(file: &mut serde_json::Value) {
+    fn nest(layout: &mut serde_json::Value) {
+        let Some(table) = layout.as_object_mut() else {
+            return;
+        };
+
+        let moved: Vec<(String, serde_json::Value)> = FLAT_THEME_KEYS
+            .iter()
+            .filter_map(|key| Some((key.to_string(), table.remove(*key)?)))
+            .collect();
+        if moved.is_empty() {
+            return;
+        }
+
+        let theme = table
+            .entry("theme")
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let Some(theme) = theme.as_object_mut() {
+            for (key, value) in moved {
+                theme.entry(key).or_insert(value);
+            }
+        }
+    }
+
+    nest(file);
+    if let Some(profiles) = file
+        .get_mut("profile")
+        .and_then(|profiles| profiles.as_object_mut())
+    {
+        for profile in profiles.values_mut() {
+            nest(profile);
+        }
+    }
+}
+
+/// Just the version of a file, read ahead of the rest so a newer layout is
+/// reported as such instead of as whatever field it trips over.
+#[derive(Deserialize)]
+struct VersionProbe {
+    schema_version: Option<u32>,
+}
+
+struct RawConfigFile {
+    settings: Settings,
+    /// Parsed once the top-level theme is known.
+    profile: BTreeMap<String, serde_json::Value>,
+    configuration: Configuration,
+}
+
+impl<'de> Deserialize<'de> for RawConfigFile {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct RawConfigFileVisitor;
+
+        impl<'de> Visitor<'de> for RawConfigFileVisitor {
+            type Value = RawConfigFile;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                return write!(f, "settings, a theme and display1, display2, ... tables");
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<RawConfigFile, A::Error> {
+                let mut settings = None;
+                let mut profile = None;
+
+                let configuration = Configuration::visit_layout(
+                    map,
+                    &[
+                        "schema_version",
+                        "settings",
+                        "theme",
+                        "night_theme",
+                        "displayN",
+                        "profile",
+                    ],
+                    |key, map| {
+                        match key {
+                            // Checked by `parse` through `VersionProbe`.
+                            "schema_version" => {
+                                map.next_value::<de::IgnoredAny>()?;
+                            }
+                            "settings" => {
+                                settings = Some(map.next_value()?);
+                            }
+                            "profile" => {
+                                profile = Some(map.next_value()?);
+                            }
+                            _ => {
+                                return Ok(false);
+                            }
+                        }
+
+                        return Ok(true);
+                    },
+                )?;
+
+                return Ok(RawConfigFile {
+                    settings: settings.unwrap_or_default(),
+                    profile: profile.unwrap_or_default(),
+                    configuration,
+                });
+            }
+        }
+
+        return d.deserialize_map(RawConfigFileVisitor);
+    }
+}
+
+/// Name of the top-level layout when switching profiles.
+pub const DEFAULT_PROFILE: &str = "default";
+
+impl ConfigFile {
+    fn from_raw(raw: RawConfigFile) -> Result<ConfigFile, String> {
+        let mut profiles = BTreeMap::new();
+
+        for (name, value) in raw.profile {
+            let has_theme = value.get("theme").is_some();
+            let mut profile = match Configuration::deserialize(value) {
+                Ok(profile) => profile,
+                Err(error) => {
+                    return Err(format!("profile \"{}\": {}", name, error));
+                }
+            };
+
+            if !has_theme {
+                profile.theme = raw.configuration.theme.clone();
+            }
+            if profile.night_theme.is_none() {
+                profile.night_theme = raw.configuration.night_theme.clone();
+            }
+            profiles.insert(name, profile);
+        }
+
+        return Ok(ConfigFile {
+            settings: raw.settings,
+            configuration: raw.configuration,
+            profiles,
+        });
+    }
+
+    /// The layout of the named profile, or the top-level one for none or
+    /// `DEFAULT_PROFILE`.
+    pub fn layout(&self, profile: Option<&str>) -> Result<Configuration, String> {
+        match profile {
+            None | Some(DEFAULT_PROFILE) => {
+                return Ok(self.configuration.clone());
+            }
+            Some(name) => match self.profiles.get(name) {
+                Some(layout) => {
+                    return Ok(layout.clone());
+                }
+                None => {
+                    let mut known = vec![DEFAULT_PROFILE];
+                    known.extend(self.profiles.keys().map(String::as_str));
+                    return Err(format!(
+                        "no profile \"{}\", expected one of {}",
+                        name,
+                        known.join(", ")
+                    ));
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The given format, else JSON for `.json` files, YAML for `.yaml` and
+    /// `.yml` files and TOML otherwise.
+    fn resolve(format: Option<ConfigFormat>, path: &Path) -> ConfigFormat {
+        if let Some(format) = format {
+            return format;
+        }
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => {
+                return ConfigFormat::Json;
+            }
+            Some(extension)
+                if extension.eq_ignore_ascii_case("yaml")
+                    || extension.eq_ignore_ascii_case("yml") =>
+            {
+                return ConfigFormat::Yaml;
+            }
+            _ => {
+                return ConfigFormat::Toml;
+            }
+        }
+    }
+}
+
+/// Reads a file of any known layout, upgrading older ones through
+/// `MIGRATIONS`.
+fn parse(text: &str, format: ConfigFormat) -> Result<RawConfigFile, String> {
+    let version = match from_text::<VersionProbe>(text, format) {
+        Ok(probe) => probe.schema_version.unwrap_or(1),
+        // A file that doesn't parse at all gets its error, with location,
+        // from the full read below.
+        Err(_) if !declares_version(text, format) => 1,
+        Err(_) => return Err(String::from("schema_version must be a positive integer")),
+    };
+
+    if version == 0 {
+        return Err(String::from("schema_version starts at 1"));
+    }
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "config written by a newer backend: schema_version {}, this one reads up to {}",
+            version, SCHEMA_VERSION
+        ));
+    }
+
+    if version == SCHEMA_VERSION {
+        return from_text::<RawConfigFile>(text, format);
+    }
+
+    let value = from_text::<serde_json::Value>(text, format)?;
+
+    let mut upgraded = value.clone();
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(&mut upgraded);
+    }
+    // Unversioned files that already have the current layout are read from
+    // the text, so errors keep their line and column.
+    if upgraded == value {
+        return from_text::<RawConfigFile>(text, format);
+    }
+    if let Some(table) = upgraded.as_object_mut() {
+        table.insert(String::from("schema_version"), SCHEMA_VERSION.into());
+    }
+
+    return RawConfigFile::deserialize(upgraded).map_err(|error| {
+        format!(
+            "invalid after upgrading from schema_version {}: {}",
+            version, error
+        )
+    });
+}
+
+/// Whether `text` has a top-level `schema_version`, whatever its value.
+fn declares_version(text: &str, format: ConfigFormat) -> bool {
+    return from_text::<serde_json::Value>(text, format)
+        .map(|value| value.get("schema_version").is_some())
+        .unwrap_or(false);
+}
+
+/// `text` parsed as `format`, with errors saying which format was expected.
+fn from_text<T: DeserializeOwned>(text: &str, format: ConfigFormat) -> Result<T, String> {
+    match format {
+        // TOML errors end with a newline after the source excerpt.
+        ConfigFormat::Toml => {
+            return toml::from_str::<T>(text)
+                .map_err(|error| format!("invalid TOML: {}", error.to_string().trim_end()));
+        }
+        ConfigFormat::Json => {
+            return serde_json::from_str::<T>(text)
+                .map_err(|error| format!("invalid JSON: {}", error));
+        }
+        ConfigFormat::Yaml => {
+            return serde_yaml::from_str::<T>(text)
+                .map_err(|error| format!("invalid YAML: {}", error));
+        }
+    }
+}
+
+pub fn load(path: &Path, format: Option<ConfigFormat>) -> Result<ConfigFile, Error> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) => {
+            return Err(Error::Config {
+                path: path.to_path_buf(),
+                message: error.to_string(),
+            });
+        }
+    };
+
+    match parse(&text, ConfigFormat::resolve(format, path)).and_then(ConfigFile::from_raw) {
+        Ok(mut config_file) => {
+            // A file with only settings (or only a theme) keeps the built-in gauges.
+            if config_file.configuration.displays.is_empty() {
+                config_file.configuration.displays = crate::default_configuration().displays;
+            }
+
+            return Ok(config_file);
+        }
+        Err(message) => {
+            return Err(Error::Config {
+                path: path.to_path_buf(),
+                message,
+            });
+        }
+    }
+}
+
+/// Top of files written by `--init-config`.
+const INIT_HEADER: &str = "\
+# Gauges backend configuration: every setting at its default, and the
+# built-in gauges. Values given on the command line take precedence over
+# the [settings] table. Colors may be written as \"#RRGGBB\", a name such
+# as \"warm\" or an RGB565 integer.
+
+";
+
+/// End of files written by `--init-config`: what the built-in gauges
+/// leave out.
+const INIT_OPTIONAL_FIELDS: &str = "
+# Optional, in [settings]:
+#   port = \"/dev/ttyUSB0\"         the first available port when unset
+#   brightness = 200              0-255, sent after every configuration
+#   max_reconnects = 10           exit after losing the connection this often
+#   profile = \"track\"             one of the [profile.<name>] layouts
+#   random_seed = 1
+#   obd2_port = \"/dev/ttyUSB1\"
+#   gps_port = \"/dev/ttyACM0\"
+#
+# Optional, per [theme]:
+#   preset = \"classic\"            starting colors, overridden by any set here
+#   background_color, accent_color
+#   gradient = [\"blue\", \"red\"]    needle color from min to max
+#
+# Optional [night_theme], laid out like [theme]: used instead while the
+# firmware reports the headlights on.
+#
+# Optional, per [displayN]:
+#   pages = [{ gauges = [...] }]  further gauge sets, rotated through
+#   page_interval_ms = 5000       required with pages
+#   enabled = false               powers the display down
+#   brightness = 128              0-255, this display only
+#   priority = true               keeps refreshing while max_data_rate throttles
+#
+# Optional, per [[displayN.gauges]]:
+#   display_units = \"°C\"          units as shown
+#   unit_system = \"imperial\"      overrides the unit_system setting
+#   redline = 7000.0              alert at and above
+#   zones = [{ start = 0.0, end = 50.0, color = \"blue\" }]
+#   label_color, value_color
+#   ok_color, low_color, high_color, alert_color
+#   alert_blink = true
+#   alert_blink_ms = 500
+#   auto_scale = true             grow min/max to fit the values seen
+#   deadband = 0.5                changes up to this size aren't sent
+#   update_ms = 1000              read the sensor at most this often
+#   inverted = true               for senders reading high when low
+#   critical = { below = 1.0, when_gauge = \"rpm\", when_above = 3000.0 }
+#   fuel = { table = [[0.0, 0.0], [90.0, 100.0]], low_fuel = 10.0 }
+#   initial_value = 0.0           shown until the first reading
+#
+# Alternative layouts, picked with --profile or the profile command:
+#   [[profile.track.display1.gauges]]
+";
+
+#[derive(Serialize)]
+struct InitConfigFile<'a> {
+    schema_version: u32,
+    settings: &'a Settings,
+    #[serde(flatten)]
+    configuration: &'a Configuration,
+}
+
+/// Writes the default settings and the built-in configuration to `path`
+/// as a starting point, refusing to replace an existing file unless
+/// `force`.
+pub fn init(path: &Path, force: bool) -> Result<(), Error> {
+    let config_error = |message: String| Error::Config {
+        path: path.to_path_buf(),
+        message,
+    };
+
+    if path.exists() && !force {
+        return Err(config_error(String::from(
+            "already exists, pass --force to overwrite it",
+        )));
+    }
+
+    let settings = Settings::default();
+    let configuration = crate::default_configuration();
+    let file = InitConfigFile {
+        schema_version: SCHEMA_VERSION,
+        settings: &settings,
+        configuration: &configuration,
+    };
+
+    let body = match toml::to_string(&file) {
+        Ok(body) => body,
+        Err(error) => {
+            return Err(config_error(format!("failed to serialize: {}", error)));
+        }
+    };
+
+    // What is written has to load back as what it was written from, or
+    // the file would start users off with something else.
+    let loaded = toml::from_str::<RawConfigFile>(&body)
+        .map_err(|error| error.to_string())
+        .and_then(ConfigFile::from_raw);
+    let round_trips = match loaded {
+        Ok(loaded) => {
+            serde_json::to_value(&loaded.settings).ok() == serde_json::to_value(&settings).ok()
+                && serde_json::to_value(&loaded.configuration).ok()
+                    == serde_json::to_value(&configuration).ok()
+        }
+        Err(_) => false,
+    };
+    if !round_trips {
+        return Err(config_error(String::from(
+            "generated configuration doesn't load back the same",
+        )));
+    }
+
+    let text = format!("{}{}{}", INIT_HEADER, body, INIT_OPTIONAL_FIELDS);
+    if let Err(error) = std::fs::write(path, text) {
+        return Err(config_error(error.to_string()));
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TOML_CONFIG: &str = r#"
+[settings]
+baud_rate = 57600
+
+[theme]
+preset = "racing"
+
+[[display1.gauges]]
+id = "boost"
+name = "BOOST"
+units = "bar"
+format = "%.1f"
+min = -1.0
+max = 2.0
+low_value = -0.5
+high_value = 1.5
+
+[[profile.track.display1.gauges]]
+id = "rpm"
+name = "RPM"
+units = "rpm"
+format = "%.0f"
+min = 0.0
+max = 8000.0
+low_value = 800.0
+high_value = 7000.0
+"#;
+
+    const JSON_CONFIG: &str = r#"{
+  "settings": { "baud_rate": 57600 },
+  "theme": { "preset": "racing" },
+  "display1": {
+    "gauges": [
+      { "id": "boost", "name": "BOOST", "units": "bar", "format": "%.1f",
+        "min": -1.0, "max": 2.0, "low_value": -0.5, "high_value": 1.5 }
+    ]
+  },
+  "profile": {
+    "track": {
+      "display1": {
+        "gauges": [
+          { "id": "rpm", "name": "RPM", "units": "rpm", "format": "%.0f",
+            "min": 0.0, "max": 8000.0, "low_value": 800.0, "high_value": 7000.0 }
+        ]
+      }
+    }
+  }
+}"#;
+
+    /// A file in the temp directory, unique to this call so tests running
+    /// in parallel never share one.
+    fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "gauges-config-{}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+
+        return path;
+    }
+
+    /// Loads `contents` from a file called `name`.
+    fn load_file(
+        name: &str,
+        contents: &str,
+        format: Option<ConfigFormat>,
+    ) -> Result<ConfigFile, String> {
+        let path = temp_file(name, contents);
+        let config_file = load(&path, format);
+        let _ = std::fs::remove_file(&path);
+
+        return config_file.map_err(|error| error.to_string());
+    }
+
+    /// Everything a config file was read into, comparable.
+    fn as_json(config_file: &ConfigFile) -> serde_json::Value {
+        return serde_json::json!({
+            "settings": config_file.settings,
+            "configuration": config_file.configuration,
+            "profiles": config_file.profiles,
+        });
+    }
+
+    #[test]
+    fn toml_and_json_files_load_the_same() {
+        let toml = load_file("same.toml", TOML_CONFIG, None).unwrap();
+        let json = load_file("same.json", JSON_CONFIG, None).unwrap();
+
+        assert_eq!(as_json(&toml), as_json(&json));
+        assert_eq!(json.settings.baud_rate, 57600);
+        assert_eq!(json.configuration.displays[0].gauges[0].id, "boost");
+        assert_eq!(json.profiles["track"].displays[0].gauges[0].id, "rpm");
+    }
+
+    #[test]
+    fn format_flag_overrides_the_extension() {
+        let json = load_file("flagged.cfg", JSON_CONFIG, Some(ConfigFormat::Json)).unwrap();
+        let toml = load_file("same.toml", TOML_CONFIG, None).unwrap();
+
+        assert_eq!(as_json(&json), as_json(&toml));
+    }
+
+    #[test]
+    fn content_in_another_format_is_reported() {
+        let error = load_file("wrong.json", TOML_CONFIG, None).err().unwrap();
+        assert!(error.contains("invalid JSON"), "{}", error);
+
+        let error = load_file("wrong.toml", JSON_CONFIG, None).err().unwrap();
+        assert!(error.contains("invalid TOML"), "{}", error);
+    }
+
+    /// A file from before `schema_version`, with the theme at the top level.
+    const V1_CONFIG: &str = include_str!("fixtures/config_v1.toml");
+
+    /// `V1_CONFIG` as written today.
+    const V2_CONFIG: &str = include_str!("fixtures/config_v2.toml");
+
+    #[test]
+    fn every_schema_version_loads_the_same() {
+        let v1 = load_file("v1.toml", V1_CONFIG, None).unwrap();
+        let v2 = load_file("v2.toml", V2_CONFIG, None).unwrap();
+
+        assert_eq!(as_json(&v1), as_json(&v2));
+        let settings = Settings::default();
+        for name in [None, Some("track")] {
+            let layout = v1.layout(name).unwrap();
+            assert!(layout.validate(settings.max_label_length).is_ok());
+        }
+
+        // The flat keys took effect rather than being ignored.
+        let theme = |config_file: &ConfigFile, profile: &str| {
+            return serde_json::to_value(&config_file.layout(Some(profile)).unwrap().theme)
+                .unwrap();
+        };
+        let racing = load_file("racing.toml", "[theme]\npreset = \"racing\"", None).unwrap();
+        assert_ne!(theme(&v1, DEFAULT_PROFILE), theme(&racing, DEFAULT_PROFILE));
+        assert_ne!(theme(&v1, "track"), theme(&v1, DEFAULT_PROFILE));
+    }
+
+    #[test]
+    fn theme_table_wins_over_flat_keys() {
+        let mixed = "ok_color = \"red\"\n[theme]\nok_color = \"#00FF00\"\npreset = \"racing\"\n";
+        let nested = "[theme]\nok_color = \"#00FF00\"\npreset = \"racing\"\n";
+
+        let mixed = load_file("mixed.toml", mixed, None).unwrap();
+        let nested = load_file("nested.toml", nested, None).unwrap();
+
+        assert_eq!(as_json(&mixed), as_json(&nested));
+    }
+
+    #[test]
+    fn newer_schema_version_is_refused() {
+        let error = load_file("v3.toml", "schema_version = 3", None)
+            .err()
+            .unwrap();
+
+        assert!(
+            error.contains(
+                "config written by a newer backend: schema_version 3, this one reads up to 2"
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn malformed_schema_version_is_refused() {
+        for (name, contents) in [
+            ("quoted.toml", "schema_version = \"2\""),
+            ("negative.toml", "schema_version = -2"),
+            ("fraction.toml", "schema_version = 1.5"),
+            ("word.json", "{ \"schema_version\": \"two\" }"),
+            ("word.yaml", "schema_version: two"),
+        ] {
+            let error = load_file(name, contents, None).err().unwrap();
+
+            assert!(
+                error.contains("schema_version must be a positive integer"),
+                "{}: {}",
+                name,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn unversioned_current_layout_keeps_error_locations() {
+        let error = load_file(
+            "located.toml",
+            &TOML_CONFIG.replace("57600", "\"fast\""),
+            None,
+        )
+        .err()
+        .unwrap();
+
+        assert!(error.contains("line 3"), "{}", error);
+    }
+
+    const YAML_CONFIG: &str = r#"
+settings:
+  baud_rate: 57600
+theme:
+  preset: racing
+display1:
+  gauges:
+    - id: boost
+      name: BOOST
+      units: bar
+      format: "%.1f"
+      min: -1.0
+      max: 2.0
+      low_value: -0.5
+      high_value: 1.5
+profile:
+  track:
+    display1:
+      gauges:
+        - id: rpm
+          name: RPM
+          units: rpm
+          format: "%.0f"
+          min: 0.0
+          max: 8000.0
+          low_value: 800.0
+          high_value: 7000.0
+"#;
+
+    #[test]
+    fn yaml_files_load_the_same_as_toml() {
+        let toml = load_file("yaml-reference.toml", TOML_CONFIG, None).unwrap();
+
+        for name in ["same.yaml", "same.yml"] {
+            let yaml = load_file(name, YAML_CONFIG, None).unwrap();
+            assert_eq!(as_json(&yaml), as_json(&toml), "{}", name);
+        }
+    }
+
+    #[test]
+    fn yaml_errors_name_the_file_and_location() {
+        let broken = YAML_CONFIG.replace("min: -1.0", "min: low");
+
+        let error = load_file("broken.yaml", &broken, None).err().unwrap();
+
+        assert!(error.contains("broken.yaml"), "{}", error);
+        assert!(error.contains("invalid YAML"), "{}", error);
+        assert!(error.contains("line 12 column 12"), "{}", error);
+    }
+}
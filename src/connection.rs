@@ -0,0 +1,412 @@
+//! State kept for one firmware connection, started afresh whenever the
+//! port is reopened or the configuration reloaded.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::clock::Clock;
+use crate::dto::dto::{Configuration, Data, GaugeData};
+use crate::pages::PageRotator;
+use crate::rate_limit::TokenBucket;
+use crate::settings::Settings;
+use crate::stats;
+
+/// Where the link to the firmware stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No usable port found yet.
+    Searching,
+    /// Port open, running the DTR sequence.
+    Opening,
+    Connected,
+    /// Retrying after IO errors.
+    Degraded,
+    /// Port abandoned, about to search again.
+    Disconnected,
+}
+
+/// Current `ConnectionState`, logging every change.
+pub struct StateLog {
+    state: Option<ConnectionState>,
+    /// Short random id of the current connection, so log lines of one
+    /// connection can be told apart from those of the next.
+    session: Option<String>,
+    /// Connections lost so far.
+    disconnects: u32,
+}
+
+impl StateLog {
+    pub fn new() -> StateLog {
+        return StateLog {
+            state: None,
+            session: None,
+            disconnects: 0,
+        };
+    }
+
+    /// Gives the connection just opened a new session id.
+    pub fn start_session(&mut self) {
+        let session = format!("{:08x}", rand::random::<u32>());
+        info!("Session {} started", session);
+        self.session = Some(session);
+    }
+
+    pub fn enter(&mut self, state: ConnectionState) {
+        let session = match &self.session {
+            Some(session) => format!(" [{}]", session),
+            None => String::new(),
+        };
+
+        match self.state {
+            Some(previous) if previous == state => {}
+            Some(previous) => info!("Connection{}: {:?} -> {:?}", session, previous, state),
+            None => info!("Connection{}: {:?}", session, state),
+        }
+
+        self.state = Some(state);
+        if state == ConnectionState::Disconnected {
+            self.session = None;
+            self.disconnects += 1;
+            stats::reconnect();
+        }
+    }
+
+    /// Whether `max_reconnects` connections have been lost, after which
+    /// the process exits for its supervisor to deal with.
+    pub fn out_of_reconnects(&self, settings: &Settings) -> bool {
+        let Some(max_reconnects) = settings.max_reconnects else {
+            return false;
+        };
+
+        if self.disconnects < max_reconnects {
+            return false;
+        }
+
+        error!("Lost the connection {} times; Giving up", self.disconnects);
+        return true;
+    }
+}
+
+/// A configuration sent but not yet acknowledged.
+struct PendingAck {
+    sent_at: Instant,
+    /// Sends so far, the first one included.
+    attempts: u32,
+}
+
+pub struct Connection {
+    pub clock: Arc<dyn Clock>,
+    pub pages: PageRotator,
+    /// Limits `Data` replies; configurations are never held back.
+    pub data_limit: TokenBucket,
+    /// Last value sent for each gauge id, for the deadband.
+    last_sent: HashMap<String, f32>,
+    /// Gauge ids that have had a real reading, past their `initial_value`.
+    read_once: HashSet<String>,
+    /// Last data sent, repeated for displays skipped while throttled.
+    last_data: Option<Data>,
+    ack_timeout: Option<Duration>,
+    config_retries: u32,
+    pending_ack: Option<PendingAck>,
+}
+
+impl Connection {
+    pub fn new(
+        configuration: &Configuration,
+        settings: &Settings,
+        clock: Arc<dyn Clock>,
+    ) -> Connection {
+        let now = clock.now();
+        return Connection {
+            clock,
+            pages: PageRotator::new(configuration, now),
+            data_limit: TokenBucket::new(settings.max_data_rate, now),
+            last_sent: HashMap::new(),
+            read_once: HashSet::new(),
+            last_data: None,
+            ack_timeout: settings.config_ack_timeout(),
+            config_retries: settings.config_retries,
+            pending_ack: None,
+        };
+    }
+
+    /// Starts waiting for the firmware to acknowledge a configuration just
+    /// sent. Sends that follow an unacknowledged one count as retries.
+    pub fn expect_ack(&mut self, now: Instant) {
+        if self.ack_timeout.is_none() {
+            return;
+        }
+
+        let attempts = match &self.pending_ack {
+            Some(pending) => pending.attempts + 1,
+            None => 1,
+        };
+        self.pending_ack = Some(PendingAck {
+            sent_at: now,
+            attempts,
+        });
+    }
+
+    /// Handles the firmware's acknowledgement, returning whether the
+    /// configuration should be sent again.
+    pub fn config_acknowledged(&mut self, ok: bool, error: Option<&str>) -> bool {
+        let Some(pending) = self.pending_ack.take() else {
+            return false;
+        };
+
+        if ok {
+            info!("Firmware applied the configuration");
+            return false;
+        }
+
+        error!(
+            "Firmware rejected the configuration: {}",
+            error.unwrap_or("no reason given")
+        );
+        return self.may_retry(pending);
+    }
+
+    /// Whether the configuration should be sent again because its
+    /// acknowledgement is overdue.
+    pub fn ack_overdue(&mut self, now: Instant) -> bool {
+        let Some(timeout) = self.ack_timeout else {
+            return false;
+        };
+        let Some(pending) = &self.pending_ack else {
+            return false;
+        };
+        if now.duration_since(pending.sent_at) <= timeout {
+            return false;
+        }
+
+        warn!("Configuration not acknowledged after {:?}", timeout);
+        let pending = self.pending_ack.take().unwrap();
+        return self.may_retry(pending);
+    }
+
+    fn may_retry(&mut self, pending: PendingAck) -> bool {
+        if pending.attempts > self.config_retries {
+            error!(
+                "Configuration not applied after {} attempts; Giving up",
+                pending.attempts
+            );
+            return false;
+        }
+
+        // Kept so the resend counts as the next attempt.
+        self.pending_ack = Some(pending);
+        return true;
+    }
+
+    /// Starts the session over for a firmware that asked for the
+    /// configuration again, most likely after resetting: pages go back to
+    /// the first and the deadband forgets what was sent, so the next data
+    /// repaints every gauge. The rate limit, any auto-scaled ranges and
+    /// which gauges have been read carry on, since they don't depend on
+    /// what the firmware shows.
+    pub fn restart(&mut self, now: Instant) {
+        self.pages.reset(now);
+        self.last_sent.clear();
+    }
+
+    /// Fills the displays left out of a throttled sample with what they
+    /// were last sent, or offline if their gauges changed since. `view` is
+    /// the full configuration the data stands for.
+    pub fn repeat_skipped(&self, view: &Configuration, data: &mut Data) {
+        for (index, (display, display_data)) in view
+            .displays
+            .iter()
+            .zip(data.displays.iter_mut())
+            .enumerate()
+        {
+            if display.priority {
+                continue;
+            }
+
+            let last = self
+                .last_data
+                .as_ref()
+                .and_then(|last| last.displays.get(index))
+                .filter(|last| last.gauges.len() == display.gauges.len());
+
+            display_data.gauges = match last {
+                Some(last) => last.gauges.clone(),
+                None => display
+                    .gauges
+                    .iter()
+                    .map(|_| GaugeData::new(GaugeData::OFFLINE_VALUE))
+                    .collect(),
+            };
+        }
+    }
+
+    pub fn remember(&mut self, data: &Data) {
+        self.last_data = Some(data.clone());
+    }
+
+    /// Shows each gauge's `initial_value` instead of offline until its
+    /// first real reading; a gauge going offline later stays offline.
+    pub fn apply_initial_values(&mut self, view: &Configuration, data: &mut Data) {
+        for (display, display_data) in view.displays.iter().zip(data.displays.iter_mut()) {
+            for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter_mut()) {
+                if value.current_value != GaugeData::OFFLINE_VALUE {
+                    if !self.read_once.contains(&gauge.id) {
+                        self.read_once.insert(gauge.id.clone());
+                    }
+                    continue;
+                }
+
+                if self.read_once.contains(&gauge.id) {
+                    continue;
+                }
+
+                if let Some(initial_value) = gauge.initial_value {
+                    value.current_value = initial_value;
+                }
+            }
+        }
+    }
+
+    /// Repeats the last sent value of each gauge whose new value is within
+    /// its deadband, unless the change moves the gauge into another state.
+    /// `view` is the configuration `data` was sampled with.
+    pub fn apply_deadband(&mut self, view: &Configuration, data: &mut Data) {
+        for (display, display_data) in view.displays.iter().zip(data.displays.iter_mut()) {
+            for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter_mut()) {
+                let Some(deadband) = gauge.deadband else {
+                    continue;
+                };
+
+                if value.current_value == GaugeData::OFFLINE_VALUE {
+                    continue;
+                }
+
+                if let Some(last) = self.last_sent.get(&gauge.id) {
+                    let jitter = (value.current_value - last).abs() <= deadband;
+                    if jitter && gauge.state(value.current_value) == gauge.state(*last) {
+                        value.current_value = *last;
+                        continue;
+                    }
+                }
+
+                self.last_sent.insert(gauge.id.clone(), value.current_value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::dto::dto::DisplayData;
+
+    /// One display with a 0-100 gauge, low below 10 and high above 90.
+    fn configuration(extra: &str) -> Configuration {
+        return toml::from_str(&format!(
+            "[[display1.gauges]]\nid = \"oil\"\nname = \"OIL\"\nunits = \"%\"\nformat = \"%.1f\"\nmin = 0.0\nmax = 100.0\nlow_value = 10.0\nhigh_value = 90.0\n{}",
+            extra
+        ))
+        .unwrap();
+    }
+
+    fn connection(configuration: &Configuration) -> Connection {
+        return Connection::new(
+            configuration,
+            &Settings::default(),
+            Arc::new(MockClock::new(0)),
+        );
+    }
+
+    /// What each of `values` goes out as, sent one after the other.
+    fn sent(connection: &mut Connection, view: &Configuration, values: &[f32]) -> Vec<f32> {
+        return values
+            .iter()
+            .map(|value| {
+                let mut data = Data {
+                    displays: vec![DisplayData {
+                        gauges: vec![GaugeData::new(*value)],
+                    }],
+                    timestamp: None,
+                    tween_ms: None,
+                };
+                connection.apply_deadband(view, &mut data);
+                data.displays[0].gauges[0].current_value
+            })
+            .collect();
+    }
+
+    #[test]
+    fn deadband_holds_jitter_and_passes_real_changes() {
+        let view = configuration("deadband = 1.0");
+        let mut connection = connection(&view);
+
+        assert_eq!(
+            sent(
+                &mut connection,
+                &view,
+                &[50.0, 50.4, 49.2, 50.9, 52.5, 52.0]
+            ),
+            [50.0, 50.0, 50.0, 50.0, 52.5, 52.5]
+        );
+    }
+
+    #[test]
+    fn state_change_passes_within_the_deadband() {
+        let view = configuration("deadband = 1.0");
+        let mut connection = connection(&view);
+
+        assert_eq!(
+            sent(&mut connection, &view, &[10.5, 9.8, 10.2]),
+            [10.5, 9.8, 10.2]
+        );
+    }
+
+    #[test]
+    fn without_a_deadband_every_value_passes() {
+        let view = configuration("");
+        let mut connection = connection(&view);
+
+        assert_eq!(
+            sent(&mut connection, &view, &[50.0, 50.1, 49.9]),
+            [50.0, 50.1, 49.9]
+        );
+    }
+
+    /// Session id of one connection, as it is logged.
+    fn connect(states: &mut StateLog) -> (String, Vec<String>) {
+        let logged = crate::test_log::capture(|| {
+            states.start_session();
+            states.enter(ConnectionState::Connected);
+        });
+        let session = states.session.clone().unwrap();
+        states.enter(ConnectionState::Disconnected);
+
+        return (
+            session,
+            logged.into_iter().map(|(_, message)| message).collect(),
+        );
+    }
+
+    #[test]
+    fn each_connection_gets_its_own_session_id() {
+        let mut states = StateLog::new();
+
+        let (first, first_logged) = connect(&mut states);
+        let (second, second_logged) = connect(&mut states);
+
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 8);
+        for (session, logged) in [(first, first_logged), (second, second_logged)] {
+            assert!(logged.contains(&format!("Session {} started", session)));
+            assert!(logged
+                .iter()
+                .any(|message| message.starts_with(&format!("Connection [{}]: ", session))));
+        }
+        // Cleared with the connection.
+        assert!(states.session.is_none());
+    }
+}
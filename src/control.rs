@@ -0,0 +1,340 @@
+//! Commands typed on stdin while running, such as hiding a gauge whose
+//! sensor misbehaves without editing the config file, switching to the
+//! track day profile or dimming a display, or printing what was last sent.
+
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+
+use crate::dto::dto::Configuration;
+use crate::shared;
+
+enum Command {
+    Hide(String),
+    Show(String),
+    Profile(String),
+    Brightness(usize, i64),
+    /// Print the latest snapshot as JSON on stdout.
+    Snapshot,
+    /// Print the latest values with the display and position of each
+    /// gauge as JSON on stdout.
+    Readings,
+}
+
+/// What the commands polled ask of the serving loop beyond what they
+/// applied to the configuration themselves.
+#[derive(Default)]
+pub struct Requests {
+    /// Profile to switch to.
+    pub profile: Option<String>,
+    /// The configuration changed in a way the firmware has to be sent.
+    pub resend: bool,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next(), words.next()) {
+        (Some("hide"), Some(id), None) => {
+            return Ok(Command::Hide(id.to_string()));
+        }
+        (Some("show"), Some(id), None) => {
+            return Ok(Command::Show(id.to_string()));
+        }
+        (Some("profile"), Some(name), None) => {
+            return Ok(Command::Profile(name.to_string()));
+        }
+        (Some("snapshot"), None, None) => {
+            return Ok(Command::Snapshot);
+        }
+        (Some("readings"), None, None) => {
+            return Ok(Command::Readings);
+        }
+        (Some("brightness"), Some(display), Some(level)) if words.next().is_none() => {
+            match (display.parse(), level.parse()) {
+                (Ok(display), Ok(level)) => {
+                    return Ok(Command::Brightness(display, level));
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid command \"{}\", expected \"brightness <display> <0-255>\"",
+                        line.trim()
+                    ));
+                }
+            }
+        }
+        _ => {
+            return Err(format!(
+                "unknown command \"{}\", expected \"hide <gauge id>\", \"show <gauge id>\", \"profile <name>\", \"brightness <display> <0-255>\", \"snapshot\" or \"readings\"",
+                line.trim()
+            ));
+        }
+    }
+}
+
+fn print_snapshot() {
+    match serde_json::to_string(&*shared::snapshot()) {
+        Ok(json) => println!("{}", json),
+        Err(error) => warn!("Failed to serialize snapshot: {}", error),
+    }
+}
+
+fn print_readings() {
+    match serde_json::to_string(&shared::snapshot().readings()) {
+        Ok(json) => println!("{}", json),
+        Err(error) => warn!("Failed to serialize readings: {}", error),
+    }
+}
+
+pub struct Control {
+    commands: Option<Receiver<String>>,
+    /// Every line read goes to each of these, one per `Control`.
+    listeners: Arc<Mutex<Vec<Sender<String>>>>,
+}
+
+impl Control {
+    /// Reads commands from stdin on a thread of its own when `enabled`.
+    pub fn new(enabled: bool) -> Control {
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+        if !enabled {
+            return Control {
+                commands: None,
+                listeners,
+            };
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        listeners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(sender);
+
+        let thread_listeners = Arc::clone(&listeners);
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+
+                // Answered here, once, as the snapshot is shared by all ports.
+                match parse_command(&line) {
+                    Ok(Command::Snapshot) => {
+                        print_snapshot();
+                        continue;
+                    }
+                    Ok(Command::Readings) => {
+                        print_readings();
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                let mut listeners = thread_listeners
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                listeners.retain(|listener| listener.send(line.clone()).is_ok());
+                if listeners.is_empty() {
+                    break;
+                }
+            }
+        });
+
+        return Control {
+            commands: Some(receiver),
+            listeners,
+        };
+    }
+
+    /// Another receiver of the same commands, for the worker of another
+    /// port, which applies them to its own copy of the configuration.
+    pub fn subscribe(&self) -> Control {
+        if self.commands.is_none() {
+            return Control {
+                commands: None,
+                listeners: Arc::clone(&self.listeners),
+            };
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        self.listeners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(sender);
+
+        return Control {
+            commands: Some(receiver),
+            listeners: Arc::clone(&self.listeners),
+        };
+    }
+
+    /// Applies the commands received since the last call, returning what
+    /// is left for the caller to do.
+    pub fn poll(&self, configuration: &mut Configuration) -> Requests {
+        let mut requests = Requests::default();
+        let Some(commands) = &self.commands else {
+            return requests;
+        };
+
+        for line in commands.try_iter() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (id, hidden) = match parse_command(&line) {
+                Ok(Command::Hide(id)) => (id, true),
+                Ok(Command::Show(id)) => (id, false),
+                Ok(Command::Profile(name)) => {
+                    requests.profile = Some(name);
+                    continue;
+                }
+                // Answered by the stdin thread, never forwarded.
+                Ok(Command::Snapshot | Command::Readings) => {
+                    continue;
+                }
+                Ok(Command::Brightness(display, level)) => {
+                    match configuration.set_brightness(display, level) {
+                        Ok(()) => {
+                            info!("Display {} brightness set to {}", display, level);
+                            requests.resend = true;
+                        }
+                        Err(error) => warn!("{}", error),
+                    }
+                    continue;
+                }
+                Err(error) => {
+                    warn!("{}", error);
+                    continue;
+                }
+            };
+
+            if configuration.set_hidden(&id, hidden) {
+                info!("Gauge {} {}", id, if hidden { "hidden" } else { "shown" });
+            } else {
+                warn!("No gauge with id \"{}\"", id);
+            }
+        }
+
+        return requests;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_takes_no_arguments() {
+        assert!(matches!(parse_command("snapshot"), Ok(Command::Snapshot)));
+        assert!(matches!(
+            parse_command("  snapshot \n"),
+            Ok(Command::Snapshot)
+        ));
+        assert!(parse_command("snapshot now").is_err());
+    }
+
+    #[test]
+    fn readings_takes_no_arguments() {
+        assert!(matches!(parse_command("readings"), Ok(Command::Readings)));
+        assert!(parse_command("readings 1").is_err());
+    }
+
+    /// A control reading `lines` as if typed on stdin.
+    fn typed(lines: &[&str]) -> Control {
+        let (sender, receiver) = mpsc::channel();
+        for line in lines {
+            sender.send(line.to_string()).unwrap();
+        }
+
+        return Control {
+            commands: Some(receiver),
+            listeners: Arc::new(Mutex::new(vec![sender])),
+        };
+    }
+
+    fn configuration() -> Configuration {
+        return toml::from_str(
+            r#"
+            [[display1.gauges]]
+            id = "oil"
+            name = "OIL"
+            units = "bar"
+            format = "%.1f"
+            min = 0.0
+            max = 10.0
+            low_value = 1.0
+            high_value = 8.0
+
+            [[display1.gauges]]
+            id = "rpm"
+            name = "RPM"
+            units = "rpm"
+            format = "%.0f"
+            min = 0.0
+            max = 8000.0
+            low_value = 800.0
+            high_value = 7000.0
+            "#,
+        )
+        .unwrap();
+    }
+
+    /// Reads 5 for every gauge.
+    struct Five;
+
+    impl crate::source::DataSource for Five {
+        fn read(
+            &mut self,
+            _: usize,
+            _: usize,
+            _: &crate::dto::dto::GaugeConfig,
+        ) -> Result<f32, String> {
+            return Ok(5.0);
+        }
+    }
+
+    fn values(configuration: &Configuration) -> Vec<f32> {
+        return crate::source::sample(&mut Five, configuration).displays[0]
+            .gauges
+            .iter()
+            .map(|gauge| gauge.current_value)
+            .collect();
+    }
+
+    #[test]
+    fn hidden_gauge_reads_offline_until_shown() {
+        let offline = crate::dto::dto::GaugeData::OFFLINE_VALUE;
+        let mut configuration = configuration();
+
+        typed(&["hide oil"]).poll(&mut configuration);
+        assert_eq!(values(&configuration), [offline, 5.0]);
+
+        typed(&["show oil"]).poll(&mut configuration);
+        assert_eq!(values(&configuration), [5.0, 5.0]);
+    }
+
+    #[test]
+    fn hiding_an_unknown_gauge_is_reported() {
+        let mut configuration = configuration();
+
+        let logged = crate::test_log::capture(|| {
+            typed(&["hide boost"]).poll(&mut configuration);
+        });
+
+        assert_eq!(values(&configuration), [5.0, 5.0]);
+        assert!(logged.contains(&(log::Level::Warn, String::from("No gauge with id \"boost\""))));
+    }
+
+    #[test]
+    fn brightness_command_resends_the_configuration() {
+        let control = typed(&["brightness 1 40", "brightness 1 300"]);
+        let mut configuration = configuration();
+
+        let requests = control.poll(&mut configuration);
+
+        assert!(requests.resend);
+        assert_eq!(configuration.displays[0].brightness, Some(40));
+        assert!(!typed(&["brightness 9 40"]).poll(&mut configuration).resend);
+    }
+}
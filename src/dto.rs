@@ -1,149 +1,2667 @@
-pub mod dto {
-    use std::fmt;
-
-    use serde::{ser::SerializeStruct, Serialize};
-    use serde_json::Value;
-
-    const OLED_COLOR_BLACK: u16 = 0x0000;
-    const OLED_COLOR_BLUE: u16 = 0x001F;
-    const OLED_COLOR_RED: u16 = 0xF800;
-    const OLED_COLOR_GREEN: u16 = 0x07E0;
-    const OLED_COLOR_CYAN: u16 = 0x07FF;
-    const OLED_COLOR_MAGENTA: u16 = 0xF81F;
-    const OLED_COLOR_YELLOW: u16 = 0xFFE0;
-    const OLED_COLOR_WARM: u16 = 0xFC00;
-    const OLED_COLOR_WHITE: u16 = 0xFFFF;
-
-    #[derive(Serialize)]
-    pub struct GaugeTheme {
-        ok_color: u16,
-        low_color: u16,
-        high_color: u16,
-        alert_color: u16,
-    }
-
-    impl Default for GaugeTheme {
-        fn default() -> GaugeTheme {
-            GaugeTheme {
-                ok_color: OLED_COLOR_WARM,
-                low_color: OLED_COLOR_BLUE,
-                high_color: OLED_COLOR_RED,
-                alert_color: OLED_COLOR_RED,
-            }
-        }
-    }
-
-    #[derive(Serialize)]
-    pub struct GaugeConfig {
-        pub name: String,
-        pub units: String,
-        pub format: String,
-        pub min: f32,
-        pub max: f32,
-        pub low_value: f32,
-        pub high_value: f32,
-    }
-
-    #[derive(Serialize)]
-    pub struct GaugeData {
-        pub current_value: f32,
-    }
-
-    impl GaugeData {
-        const OFFLINE_VALUE: f32 = f32::MAX;
-    }
-
-    type DisplayConfigurationGauges = Vec<GaugeConfig>;
-
-    #[derive(Serialize)]
-    pub struct DisplayConfiguration {
-        pub gauges: DisplayConfigurationGauges,
-    }
-
-    #[derive(Serialize)]
-    pub struct Configuration {
-        pub theme: GaugeTheme,
-        pub display1: DisplayConfiguration,
-        pub display2: DisplayConfiguration,
-        pub display3: DisplayConfiguration,
-    }
-
-    type DisplayDataGauges = Vec<GaugeData>;
-
-    #[derive(Serialize)]
-    pub struct DisplayData {
-        pub gauges: DisplayDataGauges,
-    }
-
-    #[derive(Serialize)]
-    pub struct Data {
-        pub display1: DisplayData,
-        pub display2: DisplayData,
-        pub display3: DisplayData,
-    }
-
-    pub enum OutMessage {
-        Configuration { message: Configuration },
-        Data { message: Data },
-    }
-
-    impl serde::Serialize for OutMessage {
-        fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
-            // 3 is the number of fields in the struct.
-            let mut state = s.serialize_struct("OutMessage", 2)?;
-            match self {
-                Self::Configuration { message } => {
-                    state.serialize_field("type", &1)?;
-                    state.serialize_field("message", &message)?;
-                }
-                Self::Data { message } => {
-                    state.serialize_field("type", &2)?;
-                    state.serialize_field("message", &message)?;
-                }
-            }
-
-            return state.end();
-        }
-    }
-
-    pub enum InMessage {
-        NeedGaugeConfig {},
-        NeedGaugeData {},
-        Debug { message: String },
-    }
-
-    impl<'de> serde::Deserialize<'de> for InMessage {
-        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
-            let value = Value::deserialize(d)?;
-
-            Ok(match value.get("type").and_then(Value::as_u64).unwrap() {
-                1 => InMessage::NeedGaugeConfig {},
-                2 => InMessage::NeedGaugeData {},
-                3 => InMessage::Debug {
-                    message: value
-                        .get("message")
-                        .and_then(|v| Some(v.to_string()))
-                        .or(Some(String::new()))
-                        .unwrap(),
-                },
-                type_ => panic!("unsupported type {:?}", type_),
-            })
-        }
-    }
-
-    impl fmt::Display for InMessage {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            match self {
-                Self::NeedGaugeConfig {} => {
-                    return write!(f, "NeedGaugeConfig");
-                }
-                Self::NeedGaugeData {} => {
-                    return write!(f, "NeedGaugeData");
-                }
-                Self::Debug { message } => {
-                    return write!(f, "Debug: {}", message);
-                }
-            }
-        }
-    }
-}
+pub mod dto {
+    use std::fmt;
+
+    use std::collections::{BTreeMap, HashMap};
+
+    use schemars::JsonSchema;
+    use serde::{
+        de::{self, MapAccess, Visitor},
+        ser::{SerializeMap, SerializeStruct},
+        Deserialize, Serialize,
+    };
+    use serde_json::Value;
+
+    use crate::color::{Color, ColorSpec};
+    use crate::format::FloatFormat;
+    use crate::units::{Conversion, UnitSystem};
+
+    pub const OLED_COLOR_BLACK: u16 = 0x0000;
+    pub const OLED_COLOR_BLUE: u16 = 0x001F;
+    pub const OLED_COLOR_RED: u16 = 0xF800;
+    pub const OLED_COLOR_GREEN: u16 = 0x07E0;
+    pub const OLED_COLOR_CYAN: u16 = 0x07FF;
+    pub const OLED_COLOR_MAGENTA: u16 = 0xF81F;
+    pub const OLED_COLOR_YELLOW: u16 = 0xFFE0;
+    pub const OLED_COLOR_WARM: u16 = 0xFC00;
+    pub const OLED_COLOR_WHITE: u16 = 0xFFFF;
+    pub const OLED_COLOR_DIM_RED: u16 = 0x7800;
+    pub const OLED_COLOR_DIM_BLUE: u16 = 0x0010;
+
+    /// The RGB565 constants above by lowercase name, for configurations
+    /// that spell colors out.
+    pub const NAMED_COLORS: [(&str, u16); 11] = [
+        ("black", OLED_COLOR_BLACK),
+        ("blue", OLED_COLOR_BLUE),
+        ("red", OLED_COLOR_RED),
+        ("green", OLED_COLOR_GREEN),
+        ("cyan", OLED_COLOR_CYAN),
+        ("magenta", OLED_COLOR_MAGENTA),
+        ("yellow", OLED_COLOR_YELLOW),
+        ("warm", OLED_COLOR_WARM),
+        ("white", OLED_COLOR_WHITE),
+        ("dim_red", OLED_COLOR_DIM_RED),
+        ("dim_blue", OLED_COLOR_DIM_BLUE),
+    ];
+
+    /// Built-in themes by name, as ok/low/high/alert colors.
+    const THEME_PRESETS: [(&str, [u16; 4]); 3] = [
+        (
+            "classic",
+            [
+                OLED_COLOR_WARM,
+                OLED_COLOR_BLUE,
+                OLED_COLOR_RED,
+                OLED_COLOR_RED,
+            ],
+        ),
+        (
+            "racing",
+            [
+                OLED_COLOR_WHITE,
+                OLED_COLOR_CYAN,
+                OLED_COLOR_YELLOW,
+                OLED_COLOR_RED,
+            ],
+        ),
+        (
+            "night",
+            [
+                OLED_COLOR_DIM_RED,
+                OLED_COLOR_DIM_BLUE,
+                OLED_COLOR_WARM,
+                OLED_COLOR_RED,
+            ],
+        ),
+    ];
+
+    #[derive(Clone, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    #[serde(try_from = "ThemeSpec")]
+    pub struct GaugeTheme {
+        ok_color: Color,
+        low_color: Color,
+        high_color: Color,
+        alert_color: Color,
+        /// Sent only when changed from black, for firmware that predates it.
+        #[serde(skip_serializing_if = "is_default_background")]
+        background_color: Color,
+        /// Sent only when changed from white, for firmware that predates it.
+        #[serde(skip_serializing_if = "is_default_accent")]
+        accent_color: Color,
+        /// Evenly spaced color stops from `min` to `max`. When set, each
+        /// value is sent with its own interpolated color instead of the
+        /// firmware picking one of the discrete colors above.
+        #[serde(default, skip_serializing)]
+        gradient: Vec<Color>,
+    }
+
+    const DEFAULT_BACKGROUND: Color = Color::from_rgb565(OLED_COLOR_BLACK);
+    const DEFAULT_ACCENT: Color = Color::from_rgb565(OLED_COLOR_WHITE);
+
+    /// Least luminance difference between background and accent that is
+    /// still readable on the OLEDs.
+    const MIN_CONTRAST: f32 = 0.25;
+
+    fn is_default_background(color: &Color) -> bool {
+        return *color == DEFAULT_BACKGROUND;
+    }
+
+    fn is_default_accent(color: &Color) -> bool {
+        return *color == DEFAULT_ACCENT;
+    }
+
+    impl Default for GaugeTheme {
+        fn default() -> GaugeTheme {
+            return GaugeTheme::preset("classic").unwrap();
+        }
+    }
+
+    /// The `[theme]` table as written: an optional preset, with any color
+    /// given explicitly taking precedence over the preset's.
+    #[derive(Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    #[serde(deny_unknown_fields)]
+    struct ThemeSpec {
+        preset: Option<String>,
+        #[cfg_attr(feature = "camel-case", serde(alias = "ok_color"))]
+        ok_color: Option<ColorSpec>,
+        #[cfg_attr(feature = "camel-case", serde(alias = "low_color"))]
+        low_color: Option<ColorSpec>,
+        #[cfg_attr(feature = "camel-case", serde(alias = "high_color"))]
+        high_color: Option<ColorSpec>,
+        #[cfg_attr(feature = "camel-case", serde(alias = "alert_color"))]
+        alert_color: Option<ColorSpec>,
+        #[cfg_attr(feature = "camel-case", serde(alias = "background_color"))]
+        background_color: Option<ColorSpec>,
+        #[cfg_attr(feature = "camel-case", serde(alias = "accent_color"))]
+        accent_color: Option<ColorSpec>,
+        #[serde(default)]
+        gradient: Vec<ColorSpec>,
+    }
+
+    /// `spec` checked, or `default` when the theme leaves it out. Errors
+    /// name the key, which is lost by the time serde reports them.
+    fn theme_color(field: &str, spec: &Option<ColorSpec>, default: Color) -> Result<Color, String> {
+        match spec {
+            Some(spec) => {
+                return spec
+                    .resolve()
+                    .map_err(|error| format!("theme.{}: {}", field, error));
+            }
+            None => {
+                return Ok(default);
+            }
+        }
+    }
+
+    impl TryFrom<ThemeSpec> for GaugeTheme {
+        type Error = String;
+
+        fn try_from(spec: ThemeSpec) -> Result<GaugeTheme, String> {
+            let base = match &spec.preset {
+                Some(name) => match GaugeTheme::preset(name) {
+                    Some(theme) => theme,
+                    None => {
+                        let names: Vec<&str> =
+                            THEME_PRESETS.iter().map(|(name, _)| *name).collect();
+                        return Err(format!(
+                            "unknown theme preset \"{}\", expected one of: {}",
+                            name,
+                            names.join(", ")
+                        ));
+                    }
+                },
+                None => GaugeTheme::default(),
+            };
+
+            let mut gradient = Vec::with_capacity(spec.gradient.len());
+            for (index, color) in spec.gradient.iter().enumerate() {
+                match color.resolve() {
+                    Ok(color) => gradient.push(color),
+                    Err(error) => {
+                        return Err(format!("theme.gradient[{}]: {}", index, error));
+                    }
+                }
+            }
+
+            return Ok(GaugeTheme {
+                ok_color: theme_color("ok_color", &spec.ok_color, base.ok_color)?,
+                low_color: theme_color("low_color", &spec.low_color, base.low_color)?,
+                high_color: theme_color("high_color", &spec.high_color, base.high_color)?,
+                alert_color: theme_color("alert_color", &spec.alert_color, base.alert_color)?,
+                background_color: theme_color(
+                    "background_color",
+                    &spec.background_color,
+                    base.background_color,
+                )?,
+                accent_color: theme_color("accent_color", &spec.accent_color, base.accent_color)?,
+                gradient,
+            });
+        }
+    }
+
+    impl GaugeTheme {
+        pub fn preset(name: &str) -> Option<GaugeTheme> {
+            let (_, [ok, low, high, alert]) =
+                THEME_PRESETS.iter().find(|(preset, _)| *preset == name)?;
+
+            return Some(GaugeTheme {
+                ok_color: Color::from_rgb565(*ok),
+                low_color: Color::from_rgb565(*low),
+                high_color: Color::from_rgb565(*high),
+                alert_color: Color::from_rgb565(*alert),
+                background_color: DEFAULT_BACKGROUND,
+                accent_color: DEFAULT_ACCENT,
+                gradient: Vec::new(),
+            });
+        }
+
+        /// Problems with the theme's own colors.
+        pub fn validate(&self) -> Vec<String> {
+            let mut errors = Vec::new();
+
+            let contrast =
+                (self.background_color.luminance() - self.accent_color.luminance()).abs();
+            if contrast < MIN_CONTRAST {
+                errors.push(String::from(
+                    "theme background_color and accent_color are too similar to read",
+                ));
+            }
+
+            return errors;
+        }
+
+        /// Gradient color at `fraction` (0 at `min`, 1 at `max`) of a gauge's
+        /// range, or `None` in discrete mode.
+        pub fn gradient_color(&self, fraction: f32) -> Option<Color> {
+            let last = self.gradient.len().checked_sub(1)?;
+            if last == 0 {
+                return Some(self.gradient[0]);
+            }
+
+            let position = fraction.clamp(0.0, 1.0) * last as f32;
+            let index = (position.floor() as usize).min(last - 1);
+
+            return Some(Color::lerp(
+                self.gradient[index],
+                self.gradient[index + 1],
+                position - index as f32,
+            ));
+        }
+    }
+
+    #[derive(Clone, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    #[serde(deny_unknown_fields)]
+    pub struct GaugeConfig {
+        /// Stable key for the gauge, independent of its position or `name`.
+        pub id: String,
+        pub name: String,
+        pub units: String,
+        /// Units as shown, e.g. "°C" for `units` "C"; firmware that knows
+        /// the field prefers it, and network consumers get it in place of
+        /// `units`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "display_units"))]
+        pub display_units: Option<String>,
+        /// Units shown by this gauge, overriding the `unit_system` setting,
+        /// e.g. psi for boost next to °C for coolant.
+        #[serde(default, skip_serializing)]
+        #[cfg_attr(feature = "camel-case", serde(alias = "unit_system"))]
+        pub unit_system: Option<UnitSystem>,
+        pub format: String,
+        pub min: f32,
+        pub max: f32,
+        #[cfg_attr(feature = "camel-case", serde(alias = "low_value"))]
+        pub low_value: f32,
+        #[cfg_attr(feature = "camel-case", serde(alias = "high_value"))]
+        pub high_value: f32,
+        /// Hard limit above which the gauge is in alert, e.g. an RPM redline.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub redline: Option<f32>,
+        /// Colored bands drawn along the arc, in increasing value order.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub zones: Vec<GaugeZone>,
+        /// Color of the name and units text.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "label_color"))]
+        pub label_color: Option<Color>,
+        /// Color of the numeric value text.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "value_color"))]
+        pub value_color: Option<Color>,
+        /// Theme colors for this gauge only, e.g. to tell oil pressure from
+        /// coolant at a glance; the theme's apply when unset.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "ok_color"))]
+        pub ok_color: Option<Color>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "low_color"))]
+        pub low_color: Option<Color>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "high_color"))]
+        pub high_color: Option<Color>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "alert_color"))]
+        pub alert_color: Option<Color>,
+        /// Blink the gauge while it is in the alert state.
+        #[serde(default, skip_serializing_if = "is_false")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "alert_blink"))]
+        pub alert_blink: bool,
+        /// Full on/off cycle of the alert blink; the firmware default when unset.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "alert_blink_ms"))]
+        pub alert_blink_ms: Option<u32>,
+        /// Let `min`/`max` grow to take in observed values, for sensors
+        /// whose range isn't known up front.
+        #[serde(default, skip_serializing)]
+        #[cfg_attr(feature = "camel-case", serde(alias = "auto_scale"))]
+        pub auto_scale: bool,
+        /// Changes up to this size are not sent, the previous value is
+        /// repeated instead, unless the gauge changes state.
+        #[serde(default, skip_serializing)]
+        pub deadband: Option<f32>,
+        /// Read the sensor at most this often, repeating the last value
+        /// in between; every request when unset.
+        #[serde(default, skip_serializing)]
+        #[cfg_attr(feature = "camel-case", serde(alias = "update_ms"))]
+        pub update_ms: Option<u64>,
+        /// Flip readings end to end within `min..max`, for senders that read
+        /// high when the physical value is low.
+        #[serde(default, skip_serializing)]
+        pub inverted: bool,
+        /// Condition under which the gauge is flagged critical and drawn in
+        /// the alert color, whatever its zones say.
+        #[serde(default, skip_serializing)]
+        pub critical: Option<CriticalRule>,
+        /// Fuel level sender mapping and low fuel warning; the gauge then
+        /// shows percent full.
+        #[serde(default, skip_serializing)]
+        pub fuel: Option<FuelSender>,
+        /// Shown until the source first delivers a reading, instead of
+        /// offline.
+        #[serde(default, skip_serializing)]
+        #[cfg_attr(feature = "camel-case", serde(alias = "initial_value"))]
+        pub initial_value: Option<f32>,
+        /// Turned on and off at runtime, until the next reload; a hidden
+        /// gauge isn't read and shows as offline.
+        #[serde(skip)]
+        pub hidden: bool,
+        /// Set once the gauge was converted to imperial units; readings
+        /// are converted with it as they are sampled.
+        #[serde(skip)]
+        pub conversion: Option<Conversion>,
+    }
+
+    /// Sender readings mapped to percent full through points measured on
+    /// the actual tank, since odd tank shapes make the sender far from
+    /// linear near empty.
+    #[derive(Clone, Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    pub struct FuelSender {
+        /// `[reading, percent]` points in increasing reading order, joined
+        /// by straight lines. Senders that read high when empty simply list
+        /// falling percents.
+        pub table: Vec<[f32; 2]>,
+        /// Percent at or below which the gauge is flagged critical.
+        pub low_fuel: Option<f32>,
+        /// Logged instead of the generic critical warning while low.
+        pub low_fuel_message: Option<String>,
+    }
+
+    impl FuelSender {
+        /// Percent full for a sender reading, held at the table's ends.
+        pub fn percent(&self, reading: f32) -> f32 {
+            let (Some(first), Some(last)) = (self.table.first(), self.table.last()) else {
+                return reading;
+            };
+
+            if reading <= first[0] {
+                return first[1];
+            }
+
+            for pair in self.table.windows(2) {
+                let ([from_reading, from_percent], [to_reading, to_percent]) = (pair[0], pair[1]);
+
+                if reading <= to_reading {
+                    let t = (reading - from_reading) / (to_reading - from_reading);
+                    return from_percent + (to_percent - from_percent) * t;
+                }
+            }
+
+            return last[1];
+        }
+
+        pub fn is_low(&self, percent: f32) -> bool {
+            return self.low_fuel.is_some_and(|low_fuel| percent <= low_fuel);
+        }
+
+        fn validate(&self) -> Vec<String> {
+            let mut errors = Vec::new();
+
+            if self.table.len() < 2 {
+                errors.push(String::from("fuel table needs at least two points"));
+            }
+            if self.table.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+                errors.push(String::from(
+                    "fuel table readings must be strictly increasing",
+                ));
+            }
+
+            return errors;
+        }
+    }
+
+    /// Critical when the gauge's own value is past `below`/`above`, and,
+    /// if `when_gauge` is given, that other gauge is past `when_below` /
+    /// `when_above` at the same time; e.g. low oil pressure at high RPM.
+    #[derive(Clone, Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    pub struct CriticalRule {
+        pub below: Option<f32>,
+        pub above: Option<f32>,
+        pub when_gauge: Option<String>,
+        pub when_below: Option<f32>,
+        pub when_above: Option<f32>,
+    }
+
+    fn is_past(value: f32, below: Option<f32>, above: Option<f32>) -> bool {
+        return below.is_some_and(|below| value < below)
+            || above.is_some_and(|above| value > above);
+    }
+
+    impl CriticalRule {
+        /// `other` looks up the current value of another gauge by id.
+        pub fn is_met(&self, value: f32, other: impl Fn(&str) -> Option<f32>) -> bool {
+            if !is_past(value, self.below, self.above) {
+                return false;
+            }
+
+            match &self.when_gauge {
+                Some(id) => {
+                    return other(id)
+                        .is_some_and(|value| is_past(value, self.when_below, self.when_above));
+                }
+                None => {
+                    return true;
+                }
+            }
+        }
+
+        fn validate(&self) -> Vec<String> {
+            let mut errors = Vec::new();
+
+            if self.below.is_none() && self.above.is_none() {
+                errors.push(String::from("critical needs below or above"));
+            }
+            if self.when_gauge.is_some() && self.when_below.is_none() && self.when_above.is_none() {
+                errors.push(String::from(
+                    "critical when_gauge needs when_below or when_above",
+                ));
+            }
+
+            return errors;
+        }
+    }
+
+    fn is_false(value: &bool) -> bool {
+        return !*value;
+    }
+
+    fn is_true(value: &bool) -> bool {
+        return *value;
+    }
+
+    fn default_true() -> bool {
+        return true;
+    }
+
+    /// Brightness levels the panels accept, dimmest to brightest.
+    const BRIGHTNESS_RANGE: std::ops::RangeInclusive<i64> = 0..=u8::MAX as i64;
+
+    /// Blink periods the firmware can show without flicker or looking static.
+    const ALERT_BLINK_MS_RANGE: std::ops::RangeInclusive<u32> = 100..=5000;
+
+    #[derive(Clone, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    #[serde(deny_unknown_fields)]
+    pub struct GaugeZone {
+        pub start: f32,
+        pub end: f32,
+        pub color: Color,
+    }
+
+    /// Color zone a gauge value currently falls into, matching the
+    /// `GaugeTheme` colors the firmware picks from.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum GaugeState {
+        Ok,
+        Low,
+        High,
+        Alert,
+    }
+
+    impl GaugeConfig {
+        /// `display_units`, falling back to `units`.
+        pub fn shown_units(&self) -> &str {
+            return self.display_units.as_deref().unwrap_or(&self.units);
+        }
+
+        pub fn state(&self, value: f32) -> GaugeState {
+            // Crossing the redline is an immediate alert, it is never
+            // softened by the low/high warning bands.
+            if self.redline.is_some_and(|redline| value >= redline) {
+                return GaugeState::Alert;
+            }
+
+            if value < self.low_value {
+                return GaugeState::Low;
+            }
+
+            if value > self.high_value {
+                return GaugeState::High;
+            }
+
+            return GaugeState::Ok;
+        }
+
+        /// Problems with this gauge's own settings, without location.
+        /// Problems that would make the firmware draw garbage. `name` and
+        /// units longer than `max_label_length` characters don't fit.
+        pub fn validate(&self, max_label_length: usize) -> Vec<String> {
+            let mut errors = Vec::new();
+
+            if !self.min.is_finite() || !self.max.is_finite() || self.min >= self.max {
+                errors.push(format!("min {} must be below max {}", self.min, self.max));
+            }
+
+            let thresholds_in_order = self.min <= self.low_value
+                && self.low_value <= self.high_value
+                && self.high_value <= self.max;
+            if !thresholds_in_order {
+                errors.push(format!(
+                    "low_value {} and high_value {} must be in order within min {} and max {}",
+                    self.low_value, self.high_value, self.min, self.max
+                ));
+            }
+
+            let labels = [
+                ("name", Some(&self.name)),
+                ("units", Some(&self.units)),
+                ("display_units", self.display_units.as_ref()),
+            ];
+            for (field, text) in labels {
+                let Some(text) = text else {
+                    continue;
+                };
+                if text.chars().count() > max_label_length {
+                    errors.push(format!(
+                        "{} \"{}\" is longer than {} characters",
+                        field, text, max_label_length
+                    ));
+                }
+            }
+
+            if let Err(error) = FloatFormat::parse(&self.format) {
+                errors.push(format!("format \"{}\": {}", self.format, error));
+            }
+
+            if let Some(critical) = &self.critical {
+                errors.extend(critical.validate());
+            }
+
+            if let Some(fuel) = &self.fuel {
+                errors.extend(fuel.validate());
+            }
+
+            if let Some(period) = self.alert_blink_ms {
+                if !ALERT_BLINK_MS_RANGE.contains(&period) {
+                    errors.push(format!(
+                        "alert_blink_ms {} is outside {}-{}",
+                        period,
+                        ALERT_BLINK_MS_RANGE.start(),
+                        ALERT_BLINK_MS_RANGE.end()
+                    ));
+                }
+            }
+
+            if self
+                .deadband
+                .is_some_and(|deadband| deadband < 0.0 || deadband.is_nan())
+            {
+                errors.push(String::from("deadband must not be negative"));
+            }
+
+            let mut previous_end = self.min;
+            for (index, zone) in self.zones.iter().enumerate() {
+                if zone.start >= zone.end {
+                    errors.push(format!("zone {} starts at or after its end", index));
+                }
+                if zone.start < previous_end {
+                    errors.push(format!(
+                        "zone {} starts before {} (gauge min or previous zone end)",
+                        index, previous_end
+                    ));
+                }
+                if zone.end > self.max {
+                    errors.push(format!("zone {} ends after gauge max {}", index, self.max));
+                }
+                previous_end = zone.end;
+            }
+
+            return errors;
+        }
+
+        /// The displayed value for a reading from the data source.
+        pub fn displayed_value(&self, reading: f32) -> f32 {
+            let reading = match &self.fuel {
+                Some(fuel) => fuel.percent(reading),
+                None => reading,
+            };
+
+            let reading = match &self.conversion {
+                Some(conversion) if reading != GaugeData::OFFLINE_VALUE => {
+                    conversion.apply(reading)
+                }
+                _ => reading,
+            };
+
+            if !self.inverted || reading == GaugeData::OFFLINE_VALUE {
+                return reading;
+            }
+
+            return self.min + self.max - reading;
+        }
+
+        /// `min` and `max` in the units the source measures in, before any
+        /// conversion for display.
+        pub fn measured_range(&self) -> (f32, f32) {
+            match &self.conversion {
+                Some(conversion) => {
+                    return (conversion.revert(self.min), conversion.revert(self.max));
+                }
+                None => {
+                    return (self.min, self.max);
+                }
+            }
+        }
+
+        /// Converts the range and thresholds to imperial units when that is
+        /// what the gauge shows and its `units` have an imperial
+        /// counterpart, returning the conversion if it did. Fuel gauges show
+        /// percent and are left alone.
+        pub fn convert_units(&mut self, default: UnitSystem) -> Option<Conversion> {
+            if self.unit_system.unwrap_or(default) != UnitSystem::Imperial
+                || self.conversion.is_some()
+                || self.fuel.is_some()
+            {
+                return None;
+            }
+
+            let conversion = Conversion::for_units(&self.units)?;
+
+            self.units = conversion.units.to_string();
+            if self.display_units.is_some() {
+                self.display_units = Some(conversion.display_units.to_string());
+            }
+
+            self.min = conversion.apply(self.min);
+            self.max = conversion.apply(self.max);
+            self.low_value = conversion.apply(self.low_value);
+            self.high_value = conversion.apply(self.high_value);
+            self.redline = self.redline.map(|redline| conversion.apply(redline));
+            self.initial_value = self.initial_value.map(|value| conversion.apply(value));
+            self.deadband = self
+                .deadband
+                .map(|deadband| conversion.apply_delta(deadband));
+            for zone in &mut self.zones {
+                zone.start = conversion.apply(zone.start);
+                zone.end = conversion.apply(zone.end);
+            }
+            if let Some(critical) = &mut self.critical {
+                critical.below = critical.below.map(|below| conversion.apply(below));
+                critical.above = critical.above.map(|above| conversion.apply(above));
+            }
+
+            self.conversion = Some(conversion);
+            return Some(conversion);
+        }
+
+        /// Widens the range of an auto-scaled gauge to take in `value`,
+        /// returning whether it changed. The new bound gets up to a tenth
+        /// of the span as headroom, rounded to a power of ten, so small
+        /// overshoots don't reconfigure the firmware on every sample.
+        pub fn expand_range(&mut self, value: f32) -> bool {
+            if !self.auto_scale || value == GaugeData::OFFLINE_VALUE || !value.is_finite() {
+                return false;
+            }
+
+            if value >= self.min && value <= self.max {
+                return false;
+            }
+
+            let span = self.max.max(value) - self.min.min(value);
+            let step = 10f32.powf((span / 10.0).log10().floor());
+
+            if value > self.max {
+                self.max = ((value / step).floor() + 1.0) * step;
+            } else {
+                self.min = ((value / step).ceil() - 1.0) * step;
+            }
+
+            return true;
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    pub struct GaugeData {
+        #[cfg_attr(feature = "camel-case", serde(alias = "current_value"))]
+        pub current_value: f32,
+        /// Color to draw the gauge with, overriding the theme's zone colors.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub color: Option<Color>,
+        /// A configured critical condition holds.
+        #[serde(default, skip_serializing_if = "is_false")]
+        pub critical: bool,
+    }
+
+    impl GaugeData {
+        /// Sent for gauges whose value can't be read.
+        pub const OFFLINE_VALUE: f32 = f32::MAX;
+
+        pub fn new(current_value: f32) -> GaugeData {
+            return GaugeData {
+                current_value,
+                color: None,
+                critical: false,
+            };
+        }
+    }
+
+    type DisplayConfigurationGauges = Vec<GaugeConfig>;
+
+    #[derive(Clone, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    #[serde(deny_unknown_fields)]
+    pub struct DisplayConfiguration {
+        pub gauges: DisplayConfigurationGauges,
+        /// Further gauge sets the display rotates through after `gauges`,
+        /// each with the same number of gauges.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub pages: Vec<DisplayPage>,
+        /// Time each page is shown before switching to the next.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "camel-case", serde(alias = "page_interval_ms"))]
+        pub page_interval_ms: Option<u64>,
+        /// OLED contrast of this display from 0 to 255, e.g. dimmer for a
+        /// display close to the driver's eyes; the global brightness when
+        /// unset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub brightness: Option<i64>,
+        /// A disabled display is powered down by the firmware and gets no
+        /// data. Only sent when false, for firmware that predates it.
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        pub enabled: bool,
+        /// Keep refreshing this display while `max_data_rate` throttles
+        /// data; the others repeat their last values until a request gets
+        /// through the limit.
+        #[serde(default, skip_serializing)]
+        pub priority: bool,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    #[serde(deny_unknown_fields)]
+    pub struct DisplayPage {
+        pub gauges: DisplayConfigurationGauges,
+    }
+
+    impl DisplayConfiguration {
+        /// Number of pages, counting `gauges` as the first one.
+        pub fn page_count(&self) -> usize {
+            return 1 + self.pages.len();
+        }
+
+        pub fn page(&self, page: usize) -> &DisplayConfigurationGauges {
+            match page.checked_sub(1) {
+                Some(index) => {
+                    return &self.pages[index].gauges;
+                }
+                None => {
+                    return &self.gauges;
+                }
+            }
+        }
+    }
+
+    /// Key of the n-th display in the wire format. The firmware predates
+    /// display lists and expects `display1`, `display2`, ... fields.
+    fn display_key(index: usize) -> String {
+        return format!("display{}", index + 1);
+    }
+
+    /// Inverse of `display_key`.
+    fn parse_display_key(key: &str) -> Option<usize> {
+        let number = key.strip_prefix("display")?.parse::<usize>().ok()?;
+
+        return number.checked_sub(1);
+    }
+
+    /// Orders displays read from `displayN` keys, rejecting gaps so a typo
+    /// can't silently shift every following display.
+    fn collect_displays<T, E: de::Error>(displays: BTreeMap<usize, T>) -> Result<Vec<T>, E> {
+        for (position, index) in displays.keys().enumerate() {
+            if position != *index {
+                return Err(E::custom(format!(
+                    "missing field `{}`",
+                    display_key(position)
+                )));
+            }
+        }
+
+        return Ok(displays.into_values().collect());
+    }
+
+    #[derive(Clone)]
+    pub struct Configuration {
+        /// The theme in use, and the one sent to the firmware.
+        pub theme: GaugeTheme,
+        pub displays: Vec<DisplayConfiguration>,
+        /// Theme switched to while the headlights are on; only `theme` is
+        /// sent.
+        pub night_theme: Option<GaugeTheme>,
+        /// The day theme, put aside while `night_theme` is in use.
+        day_theme: Option<GaugeTheme>,
+    }
+
+    impl serde::Serialize for Configuration {
+        fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            let mut state = s.serialize_map(Some(1 + self.displays.len()))?;
+            state.serialize_entry("theme", &self.theme)?;
+            for (index, display) in self.displays.iter().enumerate() {
+                state.serialize_entry(&display_key(index), display)?;
+            }
+
+            return state.end();
+        }
+    }
+
+    impl<'de> serde::Deserialize<'de> for Configuration {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            struct ConfigurationVisitor;
+
+            impl<'de> Visitor<'de> for ConfigurationVisitor {
+                type Value = Configuration;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    return write!(f, "a theme and display1, display2, ... tables");
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Configuration, A::Error> {
+                    return Configuration::visit_layout(
+                        map,
+                        &["theme", "night_theme", "displayN"],
+                        |_, _| Ok(false),
+                    );
+                }
+            }
+
+            return d.deserialize_map(ConfigurationVisitor);
+        }
+    }
+
+    impl Configuration {
+        /// Reads the theme, night theme and `displayN` entries of `map`,
+        /// handing any other key to `other`, which takes its value and
+        /// returns whether it knew the key. For config files, which keep
+        /// more next to the layout; flattening it instead would cost errors
+        /// within it their location.
+        pub fn visit_layout<'de, A: MapAccess<'de>>(
+            mut map: A,
+            expected: &'static [&'static str],
+            mut other: impl FnMut(&str, &mut A) -> Result<bool, A::Error>,
+        ) -> Result<Configuration, A::Error> {
+            let mut theme = None;
+            let mut night_theme = None;
+            let mut displays = BTreeMap::new();
+
+            while let Some(key) = map.next_key::<String>()? {
+                if key == "theme" {
+                    theme = Some(map.next_value()?);
+                } else if key == "night_theme" {
+                    night_theme = Some(map.next_value()?);
+                } else if let Some(index) = parse_display_key(&key) {
+                    displays.insert(index, map.next_value()?);
+                } else if !other(&key, &mut map)? {
+                    return Err(de::Error::unknown_field(&key, expected));
+                }
+            }
+
+            return Ok(Configuration {
+                theme: theme.unwrap_or_default(),
+                displays: collect_displays(displays)?,
+                night_theme,
+                day_theme: None,
+            });
+        }
+
+        /// Checks that `data` has one entry per configured display.
+        pub fn check_data(&self, data: &Data) -> Result<(), String> {
+            if self.displays.len() != data.displays.len() {
+                return Err(format!(
+                    "data has {} displays but configuration has {}",
+                    data.displays.len(),
+                    self.displays.len()
+                ));
+            }
+
+            return Ok(());
+        }
+
+        /// Pads (with offline values) or truncates each display's data so it
+        /// lines up with the configured gauges, describing every fix made.
+        pub fn conform_data(&self, data: &mut Data) -> Vec<String> {
+            let mut errors = Vec::new();
+
+            for (index, (display, display_data)) in self
+                .displays
+                .iter()
+                .zip(data.displays.iter_mut())
+                .enumerate()
+            {
+                let expected = display.gauges.len();
+                let actual = display_data.gauges.len();
+
+                if expected == actual {
+                    continue;
+                }
+
+                errors.push(format!(
+                    "display {} has {} values for {} gauges",
+                    index + 1,
+                    actual,
+                    expected
+                ));
+
+                display_data
+                    .gauges
+                    .resize_with(expected, || GaugeData::new(GaugeData::OFFLINE_VALUE));
+            }
+
+            return errors;
+        }
+
+        /// Flags gauges whose critical rule holds, or that are low on fuel,
+        /// and forces them to the alert color. Gauges a rule refers to are
+        /// looked up in `data`.
+        pub fn apply_critical(&self, data: &mut Data) {
+            let mut values = std::collections::HashMap::new();
+            for (display, display_data) in self.displays.iter().zip(data.displays.iter()) {
+                for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter()) {
+                    if value.current_value != GaugeData::OFFLINE_VALUE {
+                        values.insert(gauge.id.as_str(), value.current_value);
+                    }
+                }
+            }
+
+            for (display, display_data) in self.displays.iter().zip(data.displays.iter_mut()) {
+                for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter_mut()) {
+                    if value.current_value == GaugeData::OFFLINE_VALUE {
+                        continue;
+                    }
+
+                    let is_critical = gauge.critical.as_ref().is_some_and(|rule| {
+                        rule.is_met(value.current_value, |id| values.get(id).copied())
+                    });
+                    let is_low_fuel = gauge
+                        .fuel
+                        .as_ref()
+                        .is_some_and(|fuel| fuel.is_low(value.current_value));
+
+                    if is_critical || is_low_fuel {
+                        value.critical = true;
+                        value.color = Some(gauge.alert_color.unwrap_or(self.theme.alert_color));
+                    }
+                }
+            }
+        }
+
+        /// Rounds every value to the precision of its gauge's format.
+        pub fn round_data(&self, data: &mut Data) {
+            for (display, display_data) in self.displays.iter().zip(data.displays.iter_mut()) {
+                for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter_mut()) {
+                    if value.current_value == GaugeData::OFFLINE_VALUE {
+                        continue;
+                    }
+
+                    if let Ok(format) = FloatFormat::parse(&gauge.format) {
+                        value.current_value = format.round(value.current_value);
+                    }
+                }
+            }
+        }
+
+        /// Colors every value according to the theme gradient, if any.
+        pub fn apply_gradient(&self, data: &mut Data) {
+            for (display, display_data) in self.displays.iter().zip(data.displays.iter_mut()) {
+                for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter_mut()) {
+                    if value.current_value == GaugeData::OFFLINE_VALUE {
+                        continue;
+                    }
+
+                    let fraction = (value.current_value - gauge.min) / (gauge.max - gauge.min);
+                    value.color = self.theme.gradient_color(fraction);
+                }
+            }
+        }
+
+        /// Widens auto-scaled gauges whose values in `data` fell outside
+        /// their range, returning whether any range changed. `view` is the
+        /// configuration `data` was sampled with; gauges are matched by id.
+        pub fn auto_scale(&mut self, view: &Configuration, data: &Data) -> bool {
+            let mut changed = false;
+
+            for (display, display_data) in view.displays.iter().zip(data.displays.iter()) {
+                for (gauge, value) in display.gauges.iter().zip(display_data.gauges.iter()) {
+                    if !gauge.auto_scale {
+                        continue;
+                    }
+
+                    let target = self
+                        .displays
+                        .iter_mut()
+                        .flat_map(|display| {
+                            let pages = display.pages.iter_mut().map(|page| &mut page.gauges);
+                            std::iter::once(&mut display.gauges).chain(pages)
+                        })
+                        .flatten()
+                        .find(|target| target.id == gauge.id);
+
+                    if let Some(target) = target {
+                        changed |= target.expand_range(value.current_value);
+                    }
+                }
+            }
+
+            return changed;
+        }
+
+        /// The configuration as seen by the data path while the given page
+        /// of each display is shown. Disabled displays have no gauges.
+        pub fn with_pages(&self, active_pages: &[usize]) -> Configuration {
+            let displays = self
+                .displays
+                .iter()
+                .zip(active_pages)
+                .map(|(display, page)| DisplayConfiguration {
+                    gauges: if display.enabled {
+                        display.page(*page).clone()
+                    } else {
+                        Vec::new()
+                    },
+                    pages: Vec::new(),
+                    page_interval_ms: None,
+                    brightness: display.brightness,
+                    enabled: display.enabled,
+                    priority: display.priority,
+                })
+                .collect();
+
+            return Configuration {
+                theme: self.theme.clone(),
+                displays,
+                night_theme: None,
+                day_theme: None,
+            };
+        }
+
+        pub fn is_night(&self) -> bool {
+            return self.day_theme.is_some();
+        }
+
+        /// Switches between the day theme and `night_theme`, returning
+        /// whether the theme in use changed. Without a night theme the day
+        /// theme stays.
+        pub fn set_night(&mut self, night: bool) -> bool {
+            if night == self.is_night() {
+                return false;
+            }
+
+            if night {
+                let Some(night_theme) = &self.night_theme else {
+                    return false;
+                };
+                self.day_theme = Some(std::mem::replace(&mut self.theme, night_theme.clone()));
+            } else if let Some(day_theme) = self.day_theme.take() {
+                self.theme = day_theme;
+            }
+
+            return true;
+        }
+
+        /// Sets the brightness of the 1-based `display` until the next
+        /// reload.
+        pub fn set_brightness(&mut self, display: usize, brightness: i64) -> Result<(), String> {
+            if !BRIGHTNESS_RANGE.contains(&brightness) {
+                return Err(format!(
+                    "brightness {} is outside {}-{}",
+                    brightness,
+                    BRIGHTNESS_RANGE.start(),
+                    BRIGHTNESS_RANGE.end()
+                ));
+            }
+
+            let count = self.displays.len();
+            match display
+                .checked_sub(1)
+                .and_then(|index| self.displays.get_mut(index))
+            {
+                Some(display) => {
+                    display.brightness = Some(brightness);
+                    return Ok(());
+                }
+                None => {
+                    return Err(format!(
+                        "no display {}, there are displays 1-{}",
+                        display, count
+                    ));
+                }
+            }
+        }
+
+        /// This configuration with only the gauges of priority displays.
+        pub fn priority_only(&self) -> Configuration {
+            let mut result = self.clone();
+
+            for display in result
+                .displays
+                .iter_mut()
+                .filter(|display| !display.priority)
+            {
+                display.gauges.clear();
+            }
+
+            return result;
+        }
+
+        /// Hides or shows every gauge with the given id (one per page at
+        /// most), returning whether there was any.
+        pub fn set_hidden(&mut self, id: &str, hidden: bool) -> bool {
+            let mut found = false;
+
+            for display in &mut self.displays {
+                let pages = display.pages.iter_mut().map(|page| &mut page.gauges);
+                for gauges in std::iter::once(&mut display.gauges).chain(pages) {
+                    for gauge in gauges.iter_mut().filter(|gauge| gauge.id == id) {
+                        gauge.hidden = hidden;
+                        found = true;
+                    }
+                }
+            }
+
+            return found;
+        }
+
+        /// Converts every gauge showing imperial units, by default or its
+        /// own `unit_system`, including the thresholds other gauges'
+        /// critical rules hold it to. Call once per loaded configuration.
+        pub fn convert_units(&mut self, default: UnitSystem) {
+            let mut converted = HashMap::new();
+
+            for display in &mut self.displays {
+                let pages = display.pages.iter_mut().map(|page| &mut page.gauges);
+                for gauges in std::iter::once(&mut display.gauges).chain(pages) {
+                    for gauge in gauges.iter_mut() {
+                        if let Some(conversion) = gauge.convert_units(default) {
+                            converted.insert(gauge.id.clone(), conversion);
+                        }
+                    }
+                }
+            }
+
+            for display in &mut self.displays {
+                let pages = display.pages.iter_mut().map(|page| &mut page.gauges);
+                for gauges in std::iter::once(&mut display.gauges).chain(pages) {
+                    for critical in gauges
+                        .iter_mut()
+                        .filter_map(|gauge| gauge.critical.as_mut())
+                    {
+                        let conversion = critical
+                            .when_gauge
+                            .as_ref()
+                            .and_then(|id| converted.get(id))
+                            .copied();
+                        if let Some(conversion) = conversion {
+                            critical.when_below =
+                                critical.when_below.map(|value| conversion.apply(value));
+                            critical.when_above =
+                                critical.when_above.map(|value| conversion.apply(value));
+                        }
+                    }
+                }
+            }
+        }
+
+        pub fn validate(&self, max_label_length: usize) -> Result<(), Vec<String>> {
+            let mut errors = self.theme.validate();
+            if let Some(night_theme) = &self.night_theme {
+                errors.extend(
+                    night_theme
+                        .validate()
+                        .into_iter()
+                        .map(|error| format!("night_theme: {}", error)),
+                );
+            }
+            let mut ids = std::collections::HashSet::new();
+
+            for (display_index, display) in self.displays.iter().enumerate() {
+                if !display.pages.is_empty() && display.page_interval_ms.unwrap_or(0) == 0 {
+                    errors.push(format!(
+                        "display {}: pages need a non-zero page_interval_ms",
+                        display_index + 1
+                    ));
+                }
+
+                if let Some(brightness) = display.brightness {
+                    if !BRIGHTNESS_RANGE.contains(&brightness) {
+                        errors.push(format!(
+                            "display {}: brightness {} is outside {}-{}",
+                            display_index + 1,
+                            brightness,
+                            BRIGHTNESS_RANGE.start(),
+                            BRIGHTNESS_RANGE.end()
+                        ));
+                    }
+                }
+
+                for (page_index, page) in display.pages.iter().enumerate() {
+                    if page.gauges.len() != display.gauges.len() {
+                        errors.push(format!(
+                            "display {} page {}: has {} gauges, expected {} like the first page",
+                            display_index + 1,
+                            page_index + 1,
+                            page.gauges.len(),
+                            display.gauges.len()
+                        ));
+                    }
+                }
+
+                for page in 0..display.page_count() {
+                    for (gauge_index, gauge) in display.page(page).iter().enumerate() {
+                        let location = if page == 0 {
+                            format!("display {} gauge {}", display_index + 1, gauge_index)
+                        } else {
+                            format!(
+                                "display {} page {} gauge {}",
+                                display_index + 1,
+                                page,
+                                gauge_index
+                            )
+                        };
+
+                        if gauge.id.is_empty() {
+                            errors.push(format!("{}: id must not be empty", location));
+                        } else if !ids.insert(gauge.id.as_str()) {
+                            errors.push(format!("{}: duplicate id \"{}\"", location, gauge.id));
+                        }
+
+                        for error in gauge.validate(max_label_length) {
+                            errors.push(format!("{}: {}", location, error));
+                        }
+                    }
+                }
+            }
+
+            // Checked once every id is known, so rules can refer forward.
+            for display in &self.displays {
+                for page in 0..display.page_count() {
+                    for gauge in display.page(page) {
+                        let Some(id) = gauge
+                            .critical
+                            .as_ref()
+                            .and_then(|rule| rule.when_gauge.as_ref())
+                        else {
+                            continue;
+                        };
+
+                        if !ids.contains(id.as_str()) {
+                            errors.push(format!(
+                                "gauge \"{}\": critical when_gauge \"{}\" does not exist",
+                                gauge.id, id
+                            ));
+                        }
+                    }
+                }
+            }
+
+            if errors.is_empty() {
+                return Ok(());
+            }
+
+            return Err(errors);
+        }
+    }
+
+    type DisplayDataGauges = Vec<GaugeData>;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    pub struct DisplayData {
+        pub gauges: DisplayDataGauges,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Data {
+        pub displays: Vec<DisplayData>,
+        /// Milliseconds since the Unix epoch when the sample was taken.
+        /// Firmware ignores it; it is for consumers that need sample timing.
+        pub timestamp: Option<u64>,
+        /// How long the firmware should animate needles toward the new
+        /// values, in milliseconds. Older firmware ignores it.
+        pub tween_ms: Option<u64>,
+    }
+
+    /// Key of `tween_ms`, named like the derived fields.
+    #[cfg(not(feature = "camel-case"))]
+    pub const TWEEN_MS_KEY: &str = "tween_ms";
+    #[cfg(feature = "camel-case")]
+    pub const TWEEN_MS_KEY: &str = "tweenMs";
+
+    impl serde::Serialize for Data {
+        fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            let mut state = s.serialize_map(None)?;
+            for (index, display) in self.displays.iter().enumerate() {
+                state.serialize_entry(&display_key(index), display)?;
+            }
+            if let Some(timestamp) = self.timestamp {
+                state.serialize_entry("timestamp", &timestamp)?;
+            }
+            if let Some(tween_ms) = self.tween_ms {
+                state.serialize_entry(TWEEN_MS_KEY, &tween_ms)?;
+            }
+
+            return state.end();
+        }
+    }
+
+    impl<'de> serde::Deserialize<'de> for Data {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            struct DataVisitor;
+
+            impl<'de> Visitor<'de> for DataVisitor {
+                type Value = Data;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    return write!(f, "display1, display2, ... values");
+                }
+
+                fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Data, A::Error> {
+                    let mut timestamp = None;
+                    let mut tween_ms = None;
+                    let mut displays = BTreeMap::new();
+
+                    while let Some(key) = map.next_key::<String>()? {
+                        if key == "timestamp" {
+                            timestamp = Some(map.next_value()?);
+                        } else if key == TWEEN_MS_KEY {
+                            tween_ms = Some(map.next_value()?);
+                        } else if let Some(index) = parse_display_key(&key) {
+                            displays.insert(index, map.next_value()?);
+                        } else {
+                            return Err(de::Error::unknown_field(
+                                &key,
+                                &["displayN", "timestamp", TWEEN_MS_KEY],
+                            ));
+                        }
+                    }
+
+                    return Ok(Data {
+                        displays: collect_displays(displays)?,
+                        timestamp,
+                        tween_ms,
+                    });
+                }
+            }
+
+            return d.deserialize_map(DataVisitor);
+        }
+    }
+
+    /// Display brightness for all OLEDs. The firmware writes `level` to the
+    /// panel contrast register: 0 is the dimmest setting (not off) and 255
+    /// the brightest.
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    pub struct Brightness {
+        pub level: u8,
+    }
+
+    impl Brightness {
+        /// Clamps any requested level into the range the panels accept.
+        pub fn clamped(level: i64) -> Brightness {
+            return Brightness {
+                level: level.clamp(0, u8::MAX as i64) as u8,
+            };
+        }
+    }
+
+    /// Tells the firmware which page of a display to show. `display` is
+    /// 1-based like the `displayN` keys; page 0 is the display's `gauges`.
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    pub struct ShowPage {
+        pub display: usize,
+        pub page: usize,
+    }
+
+    /// Configuration of one display, for firmware refreshing a single
+    /// panel. `display` is 1-based like the `displayN` keys.
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+    pub struct SingleDisplay {
+        pub display: usize,
+        pub configuration: DisplayConfiguration,
+    }
+
+    pub enum OutMessage {
+        Configuration { message: Configuration },
+        Data { message: Data },
+        Brightness { message: Brightness },
+        ShowPage { message: ShowPage },
+        DisplayConfiguration { message: SingleDisplay },
+    }
+
+    impl serde::Serialize for OutMessage {
+        fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            // Every message is its type and the message itself.
+            let mut state = s.serialize_struct("OutMessage", 2)?;
+            match self {
+                Self::Configuration { message } => {
+                    state.serialize_field("type", &1)?;
+                    state.serialize_field("message", &message)?;
+                }
+                Self::Data { message } => {
+                    state.serialize_field("type", &2)?;
+                    state.serialize_field("message", &message)?;
+                }
+                Self::Brightness { message } => {
+                    state.serialize_field("type", &3)?;
+                    state.serialize_field("message", &message)?;
+                }
+                Self::ShowPage { message } => {
+                    state.serialize_field("type", &4)?;
+                    state.serialize_field("message", &message)?;
+                }
+                Self::DisplayConfiguration { message } => {
+                    state.serialize_field("type", &5)?;
+                    state.serialize_field("message", &message)?;
+                }
+            }
+
+            return state.end();
+        }
+    }
+
+    pub enum InMessage {
+        NeedGaugeConfig {},
+        NeedGaugeData {},
+        /// Configuration of one display only, 1-based.
+        NeedDisplayConfig {
+            display: usize,
+        },
+        /// Firmware log line, at `level` (debug when not given).
+        Debug {
+            message: String,
+            level: log::Level,
+        },
+        /// Sent by the firmware once it applied a configuration, or with
+        /// `ok` false and the reason in `error` when it couldn't.
+        ConfigAck {
+            ok: bool,
+            error: Option<String>,
+        },
+        /// The headlight input changed, switching to the night theme while
+        /// `on`.
+        Headlights {
+            on: bool,
+        },
+    }
+
+    /// Reads back what the backend sent, for loopback and test tools.
+    /// Colors are read as RGB565, so the round trip is only exact at the
+    /// default color depth.
+    impl<'de> serde::Deserialize<'de> for OutMessage {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            let value = Value::deserialize(d)?;
+            let Some(message) = value.get("message").cloned() else {
+                return Err(de::Error::missing_field("message"));
+            };
+
+            let result = match value.get("type").and_then(Value::as_u64) {
+                Some(1) => Configuration::deserialize(message)
+                    .map(|message| OutMessage::Configuration { message }),
+                Some(2) => Data::deserialize(message).map(|message| OutMessage::Data { message }),
+                Some(3) => Brightness::deserialize(message)
+                    .map(|message| OutMessage::Brightness { message }),
+                Some(4) => {
+                    ShowPage::deserialize(message).map(|message| OutMessage::ShowPage { message })
+                }
+                Some(5) => SingleDisplay::deserialize(message)
+                    .map(|message| OutMessage::DisplayConfiguration { message }),
+                type_ => {
+                    return Err(de::Error::custom(format!("unsupported type {:?}", type_)));
+                }
+            };
+
+            return result.map_err(de::Error::custom);
+        }
+    }
+
+    impl<'de> serde::Deserialize<'de> for InMessage {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            let value = Value::deserialize(d)?;
+            let Some(type_) = value.get("type").and_then(Value::as_u64) else {
+                return Err(de::Error::missing_field("type"));
+            };
+
+            // Requests carry no message, so a message with a request type,
+            // or a type only the backend sends, is one of our own frames
+            // coming back: a loopback or swapped wiring during bring-up.
+            let echoed = match (type_, value.get("message")) {
+                (1, Some(_)) => Some("Configuration"),
+                (2, Some(_)) => Some("Data"),
+                (4, Some(_)) => Some("ShowPage"),
+                (5, _) => Some("DisplayConfiguration"),
+                _ => None,
+            };
+            if let Some(name) = echoed {
+                return Err(de::Error::custom(format!(
+                    "received a backend {} message; check for a loopback or swapped TX/RX",
+                    name
+                )));
+            }
+
+            Ok(match type_ {
+                1 => InMessage::NeedGaugeConfig {},
+                2 => InMessage::NeedGaugeData {},
+                3 => InMessage::Debug {
+                    message: value
+                        .get("message")
+                        .map(|v| v.to_string())
+                        .unwrap_or_default(),
+                    level: value
+                        .get("level")
+                        .and_then(Value::as_str)
+                        .and_then(|level| level.parse().ok())
+                        .unwrap_or(log::Level::Debug),
+                },
+                4 => InMessage::NeedDisplayConfig {
+                    display: match value.get("display").and_then(Value::as_u64) {
+                        Some(display) => display as usize,
+                        None => {
+                            return Err(de::Error::missing_field("display"));
+                        }
+                    },
+                },
+                6 => InMessage::ConfigAck {
+                    ok: match value.get("ok").and_then(Value::as_bool) {
+                        Some(ok) => ok,
+                        None => {
+                            return Err(de::Error::missing_field("ok"));
+                        }
+                    },
+                    error: value.get("error").and_then(Value::as_str).map(String::from),
+                },
+                7 => InMessage::Headlights {
+                    on: match value.get("on").and_then(Value::as_bool) {
+                        Some(on) => on,
+                        None => {
+                            return Err(de::Error::missing_field("on"));
+                        }
+                    },
+                },
+                type_ => {
+                    return Err(de::Error::custom(format!("unsupported type {}", type_)));
+                }
+            })
+        }
+    }
+
+    impl fmt::Display for InMessage {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::NeedGaugeConfig {} => {
+                    return write!(f, "NeedGaugeConfig");
+                }
+                Self::NeedGaugeData {} => {
+                    return write!(f, "NeedGaugeData");
+                }
+                Self::NeedDisplayConfig { display } => {
+                    return write!(f, "NeedDisplayConfig(display {})", display);
+                }
+                Self::Debug { message, level } => {
+                    return write!(f, "Debug ({}): {}", level, message);
+                }
+                Self::ConfigAck { ok: true, .. } => {
+                    return write!(f, "ConfigAck(ok)");
+                }
+                Self::ConfigAck { ok: false, error } => {
+                    return write!(
+                        f,
+                        "ConfigAck(failed: {})",
+                        error.as_deref().unwrap_or("no reason given")
+                    );
+                }
+                Self::Headlights { on: true } => {
+                    return write!(f, "Headlights(on)");
+                }
+                Self::Headlights { on: false } => {
+                    return write!(f, "Headlights(off)");
+                }
+            }
+        }
+    }
+
+    /// Short summary for logs, without the full JSON.
+    impl fmt::Display for OutMessage {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Self::Configuration { message } => {
+                    let gauges: usize = message
+                        .displays
+                        .iter()
+                        .map(|display| display.gauges.len())
+                        .sum();
+                    return write!(
+                        f,
+                        "Configuration({} displays, {} gauges)",
+                        message.displays.len(),
+                        gauges
+                    );
+                }
+                Self::Data { message } => {
+                    let values: usize = message
+                        .displays
+                        .iter()
+                        .map(|display| display.gauges.len())
+                        .sum();
+                    return write!(f, "Data({} values)", values);
+                }
+                Self::Brightness { message } => {
+                    return write!(f, "Brightness({})", message.level);
+                }
+                Self::ShowPage { message } => {
+                    return write!(
+                        f,
+                        "ShowPage(display {}, page {})",
+                        message.display, message.page
+                    );
+                }
+                Self::DisplayConfiguration { message } => {
+                    return write!(
+                        f,
+                        "DisplayConfiguration(display {}, {} gauges)",
+                        message.display,
+                        message.configuration.gauges.len()
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// A coolant gauge with `extra` TOML lines added or overriding.
+        fn gauge(extra: &str) -> GaugeConfig {
+            let mut fields: BTreeMap<String, toml::Value> = toml::from_str(
+                r#"
+                id = "coolant"
+                name = "COOLANT"
+                units = "C"
+                format = "%.0f"
+                min = 0.0
+                max = 130.0
+                low_value = 60.0
+                high_value = 100.0
+                "#,
+            )
+            .unwrap();
+            let extra: BTreeMap<String, toml::Value> = toml::from_str(extra).unwrap();
+            fields.extend(extra);
+
+            return toml::Value::try_from(fields).unwrap().try_into().unwrap();
+        }
+
+        #[test]
+        fn value_at_redline_is_alert() {
+            let tachometer = gauge("redline = 110.0\nhigh_value = 120.0");
+
+            assert_eq!(tachometer.state(105.0), GaugeState::Ok);
+            assert_eq!(tachometer.state(110.0), GaugeState::Alert);
+            assert_eq!(tachometer.state(125.0), GaugeState::Alert);
+        }
+
+        #[test]
+        fn redline_is_omitted_when_unset() {
+            let json = serde_json::to_value(gauge("")).unwrap();
+            assert!(json.get("redline").is_none());
+
+            let json = serde_json::to_value(gauge("redline = 110.0")).unwrap();
+            assert_eq!(json["redline"], 110.0);
+        }
+
+        fn configuration(toml: &str) -> Configuration {
+            return toml::from_str(toml).unwrap();
+        }
+
+        #[test]
+        fn duplicate_ids_fail_validation() {
+            let configuration = configuration(
+                r#"
+                [[display1.gauges]]
+                id = "oil"
+                name = "OIL"
+                units = "bar"
+                format = "%.1f"
+                min = 0.0
+                max = 10.0
+                low_value = 1.0
+                high_value = 8.0
+
+                [[display2.gauges]]
+                id = "oil"
+                name = "OIL T"
+                units = "C"
+                format = "%.0f"
+                min = 0.0
+                max = 150.0
+                low_value = 60.0
+                high_value = 130.0
+                "#,
+            );
+
+            let errors = configuration.validate(16).unwrap_err();
+            assert_eq!(errors, ["display 2 gauge 0: duplicate id \"oil\""]);
+        }
+
+        /// TOML for the given displays, one gauge each.
+        fn displays_toml(displays: &[usize]) -> String {
+            return displays
+                .iter()
+                .map(|display| {
+                    format!(
+                        "[[display{0}.gauges]]\nid = \"gauge{0}\"\nname = \"G{0}\"\nunits = \"%\"\nformat = \"%.0f\"\nmin = 0.0\nmax = 100.0\nlow_value = 10.0\nhigh_value = 90.0\n",
+                        display
+                    )
+                })
+                .collect();
+        }
+
+        #[test]
+        fn one_display_configuration() {
+            let configuration = configuration(&displays_toml(&[1]));
+
+            assert_eq!(configuration.displays.len(), 1);
+            assert!(configuration.validate(16).is_ok());
+
+            let json = serde_json::to_value(&configuration).unwrap();
+            let keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+            assert_eq!(keys, ["display1", "theme"]);
+        }
+
+        #[test]
+        fn four_display_configuration() {
+            let configuration = configuration(&displays_toml(&[1, 2, 3, 4]));
+
+            assert_eq!(configuration.displays.len(), 4);
+            assert!(configuration.validate(16).is_ok());
+
+            let json = serde_json::to_value(&configuration).unwrap();
+            for display in 1..=4 {
+                assert_eq!(
+                    json[format!("display{}", display)]["gauges"][0]["id"],
+                    format!("gauge{}", display)
+                );
+            }
+            assert!(json.get("display5").is_none());
+        }
+
+        #[test]
+        fn gap_in_displays_is_rejected() {
+            let error = toml::from_str::<Configuration>(&displays_toml(&[1, 3]))
+                .err()
+                .unwrap();
+
+            assert!(error.to_string().contains("missing field `display2`"));
+        }
+
+        fn data(displays: &[&[f32]]) -> Data {
+            return Data {
+                displays: displays
+                    .iter()
+                    .map(|values| DisplayData {
+                        gauges: values.iter().map(|value| GaugeData::new(*value)).collect(),
+                    })
+                    .collect(),
+                timestamp: None,
+                tween_ms: None,
+            };
+        }
+
+        fn values(data: &Data) -> Vec<Vec<f32>> {
+            return data
+                .displays
+                .iter()
+                .map(|display| {
+                    display
+                        .gauges
+                        .iter()
+                        .map(|gauge| gauge.current_value)
+                        .collect()
+                })
+                .collect();
+        }
+
+        #[test]
+        fn data_for_the_wrong_number_of_displays_is_refused() {
+            let configuration = configuration(&displays_toml(&[1, 2]));
+
+            assert!(configuration.check_data(&data(&[&[1.0], &[2.0]])).is_ok());
+            assert_eq!(
+                configuration.check_data(&data(&[&[1.0]])),
+                Err(String::from("data has 1 displays but configuration has 2"))
+            );
+            assert_eq!(
+                configuration.check_data(&data(&[&[1.0], &[2.0], &[3.0]])),
+                Err(String::from("data has 3 displays but configuration has 2"))
+            );
+        }
+
+        #[test]
+        fn undersized_display_data_is_padded_offline() {
+            let configuration = configuration(&displays_toml(&[1, 2]));
+            let mut data = data(&[&[], &[2.0]]);
+
+            let errors = configuration.conform_data(&mut data);
+
+            assert_eq!(errors, ["display 1 has 0 values for 1 gauges"]);
+            assert_eq!(values(&data), [[GaugeData::OFFLINE_VALUE], [2.0]]);
+        }
+
+        #[test]
+        fn oversized_display_data_is_truncated() {
+            let configuration = configuration(&displays_toml(&[1, 2]));
+            let mut data = data(&[&[1.0], &[2.0, 3.0]]);
+
+            let errors = configuration.conform_data(&mut data);
+
+            assert_eq!(errors, ["display 2 has 2 values for 1 gauges"]);
+            assert_eq!(values(&data), [[1.0], [2.0]]);
+        }
+
+        fn theme(toml: &str) -> GaugeTheme {
+            return toml::from_str(toml).unwrap();
+        }
+
+        #[test]
+        fn gradient_endpoints_match_the_stops() {
+            let theme = theme(r##"gradient = ["#00FF00", "#FFFF00", "#FF0000"]"##);
+
+            assert_eq!(theme.gradient_color(0.0), Some("#00FF00".parse().unwrap()));
+            assert_eq!(theme.gradient_color(1.0), Some("#FF0000".parse().unwrap()));
+            // Values outside the range stay at the end stops.
+            assert_eq!(theme.gradient_color(-0.5), Some("#00FF00".parse().unwrap()));
+            assert_eq!(theme.gradient_color(1.5), Some("#FF0000".parse().unwrap()));
+        }
+
+        #[test]
+        fn gradient_midpoint_blends_the_stops() {
+            let two_stops = theme(r##"gradient = ["#00FF00", "#FF0000"]"##);
+            let three_stops = theme(r##"gradient = ["#00FF00", "#FFFF00", "#FF0000"]"##);
+
+            assert_eq!(
+                two_stops.gradient_color(0.5),
+                Some("#808000".parse().unwrap())
+            );
+            assert_eq!(
+                three_stops.gradient_color(0.5),
+                Some("#FFFF00".parse().unwrap())
+            );
+            assert_eq!(
+                three_stops.gradient_color(0.25),
+                Some("#80FF00".parse().unwrap())
+            );
+        }
+
+        #[test]
+        fn discrete_theme_has_no_gradient_color() {
+            assert_eq!(theme("").gradient_color(0.5), None);
+        }
+
+        #[test]
+        fn gradient_colors_each_value() {
+            let mut configuration = configuration(&displays_toml(&[1]));
+            configuration.theme = theme(r##"gradient = ["#00FF00", "#FF0000"]"##);
+            let mut data = data(&[&[50.0]]);
+
+            configuration.apply_gradient(&mut data);
+
+            assert_eq!(
+                data.displays[0].gauges[0].color,
+                Some("#808000".parse().unwrap())
+            );
+        }
+
+        #[test]
+        fn brightness_serializes_as_type_3_with_a_level() {
+            let message = OutMessage::Brightness {
+                message: Brightness::clamped(128),
+            };
+
+            assert_eq!(
+                serde_json::to_string(&message).unwrap(),
+                r#"{"type":3,"message":{"level":128}}"#
+            );
+        }
+
+        #[test]
+        fn brightness_is_clamped_to_the_panel_range() {
+            assert_eq!(Brightness::clamped(300).level, 255);
+            assert_eq!(Brightness::clamped(-5).level, 0);
+        }
+
+        #[test]
+        fn valid_zones_pass_validation() {
+            let gauge = gauge(
+                r#"zones = [
+                    { start = 0.0, end = 60.0, color = "blue" },
+                    { start = 60.0, end = 100.0, color = "green" },
+                    { start = 110.0, end = 130.0, color = "red" },
+                ]"#,
+            );
+
+            assert!(gauge.validate(16).is_empty());
+
+            let json = serde_json::to_value(&gauge).unwrap();
+            assert_eq!(json["zones"].as_array().unwrap().len(), 3);
+            assert_eq!(json["zones"][2]["color"], OLED_COLOR_RED);
+        }
+
+        #[test]
+        fn overlapping_zones_are_rejected() {
+            let gauge = gauge(
+                r#"zones = [
+                    { start = 0.0, end = 70.0, color = "blue" },
+                    { start = 60.0, end = 100.0, color = "green" },
+                ]"#,
+            );
+
+            assert_eq!(
+                gauge.validate(16),
+                ["zone 1 starts before 70 (gauge min or previous zone end)"]
+            );
+        }
+
+        #[test]
+        fn zones_outside_the_range_are_rejected() {
+            let gauge = gauge(
+                r#"zones = [
+                    { start = -10.0, end = 0.0, color = "blue" },
+                    { start = 120.0, end = 140.0, color = "red" },
+                ]"#,
+            );
+
+            assert_eq!(
+                gauge.validate(16),
+                [
+                    "zone 0 starts before 0 (gauge min or previous zone end)",
+                    "zone 1 ends after gauge max 130",
+                ]
+            );
+        }
+
+        #[test]
+        fn gauges_without_zones_omit_them() {
+            assert!(serde_json::to_value(gauge(""))
+                .unwrap()
+                .get("zones")
+                .is_none());
+        }
+
+        #[test]
+        fn tween_is_sent_when_set() {
+            let mut data = data(&[&[1.0]]);
+            data.tween_ms = Some(250);
+
+            let json = serde_json::to_value(&data).unwrap();
+
+            assert_eq!(json[TWEEN_MS_KEY], 250);
+        }
+
+        #[test]
+        fn tween_is_omitted_when_unset() {
+            let json = serde_json::to_value(data(&[&[1.0]])).unwrap();
+
+            assert!(json.get(TWEEN_MS_KEY).is_none());
+        }
+
+        /// A snake_case field name as it is sent on the wire.
+        fn key(name: &str) -> String {
+            if !cfg!(feature = "camel-case") {
+                return name.to_string();
+            }
+
+            let mut words = name.split('_');
+            let mut key = words.next().unwrap_or_default().to_string();
+            for word in words {
+                let mut chars = word.chars();
+                key.extend(chars.next().map(|first| first.to_ascii_uppercase()));
+                key.push_str(chars.as_str());
+            }
+
+            return key;
+        }
+
+        #[test]
+        fn text_colors_serialize_to_rgb565() {
+            let gauge = gauge("label_color = \"#FFFFFF\"\nvalue_color = \"green\"");
+
+            let json = serde_json::to_value(&gauge).unwrap();
+
+            assert_eq!(json[key("label_color")], 0xFFFF);
+            assert_eq!(json[key("value_color")], OLED_COLOR_GREEN);
+        }
+
+        #[test]
+        fn absent_text_colors_are_skipped() {
+            let json = serde_json::to_value(gauge("label_color = \"red\"")).unwrap();
+
+            assert_eq!(json[key("label_color")], OLED_COLOR_RED);
+            assert!(json.get(key("value_color")).is_none());
+        }
+
+        #[test]
+        fn out_message_display_summarizes_counts() {
+            let configuration = OutMessage::Configuration {
+                message: configuration(&displays_toml(&[1, 2, 3])),
+            };
+            let data = OutMessage::Data {
+                message: data(&[&[1.0, 2.0], &[3.0], &[]]),
+            };
+
+            assert_eq!(
+                configuration.to_string(),
+                "Configuration(3 displays, 3 gauges)"
+            );
+            assert_eq!(data.to_string(), "Data(3 values)");
+        }
+
+        #[test]
+        fn value_past_the_range_expands_it_once() {
+            let mut configuration = configuration(&displays_toml(&[1]));
+            configuration.displays[0].gauges[0].auto_scale = true;
+            let view = configuration.clone();
+
+            let expansions: Vec<bool> = [50.0, 142.0, 142.0, 145.0, 120.0]
+                .iter()
+                .map(|value| configuration.auto_scale(&view, &data(&[&[*value]])))
+                .collect();
+
+            assert_eq!(expansions, [false, true, false, false, false]);
+            assert_eq!(configuration.displays[0].gauges[0].max, 150.0);
+            assert_eq!(configuration.displays[0].gauges[0].min, 0.0);
+        }
+
+        #[test]
+        fn fixed_range_never_expands() {
+            let mut configuration = configuration(&displays_toml(&[1]));
+            let view = configuration.clone();
+
+            assert!(!configuration.auto_scale(&view, &data(&[&[500.0]])));
+            assert_eq!(configuration.displays[0].gauges[0].max, 100.0);
+        }
+
+        #[test]
+        fn alert_blink_is_omitted_by_default() {
+            let json = serde_json::to_value(gauge("")).unwrap();
+
+            assert!(json.get(key("alert_blink")).is_none());
+            assert!(json.get(key("alert_blink_ms")).is_none());
+        }
+
+        #[test]
+        fn alert_blink_and_period_serialize() {
+            let json =
+                serde_json::to_value(gauge("alert_blink = true\nalert_blink_ms = 500")).unwrap();
+
+            assert_eq!(json[key("alert_blink")], true);
+            assert_eq!(json[key("alert_blink_ms")], 500);
+        }
+
+        #[test]
+        fn alert_blink_period_is_range_checked() {
+            assert!(gauge("alert_blink_ms = 100").validate(16).is_empty());
+            assert!(gauge("alert_blink_ms = 5000").validate(16).is_empty());
+            assert_eq!(
+                gauge("alert_blink_ms = 50").validate(16),
+                ["alert_blink_ms 50 is outside 100-5000"]
+            );
+        }
+
+        /// ok, low, high and alert colors as RGB565.
+        fn state_colors(theme: &GaugeTheme) -> [u16; 4] {
+            return [
+                theme.ok_color.to_rgb565(),
+                theme.low_color.to_rgb565(),
+                theme.high_color.to_rgb565(),
+                theme.alert_color.to_rgb565(),
+            ];
+        }
+
+        #[test]
+        fn presets_resolve_to_their_colors() {
+            for (name, colors) in THEME_PRESETS {
+                let theme = theme(&format!("preset = \"{}\"", name));
+                assert_eq!(state_colors(&theme), colors, "preset {}", name);
+            }
+
+            assert_eq!(
+                state_colors(&theme("")),
+                [
+                    OLED_COLOR_WARM,
+                    OLED_COLOR_BLUE,
+                    OLED_COLOR_RED,
+                    OLED_COLOR_RED
+                ]
+            );
+        }
+
+        #[test]
+        fn explicit_colors_override_the_preset() {
+            let theme =
+                theme("preset = \"night\"\nok_color = \"green\"\nalert_color = \"#FFFF00\"");
+
+            assert_eq!(
+                state_colors(&theme),
+                [
+                    OLED_COLOR_GREEN,
+                    OLED_COLOR_DIM_BLUE,
+                    OLED_COLOR_WARM,
+                    OLED_COLOR_YELLOW
+                ]
+            );
+        }
+
+        #[test]
+        fn unknown_preset_names_the_choices() {
+            let error = toml::from_str::<GaugeTheme>("preset = \"disco\"")
+                .err()
+                .unwrap();
+
+            assert!(error.to_string().contains(
+                "unknown theme preset \"disco\", expected one of: classic, racing, night"
+            ));
+        }
+
+        #[test]
+        fn data_round_trips_through_out_message() {
+            let mut sent = data(&[&[12.5, GaugeData::OFFLINE_VALUE], &[-3.0]]);
+            sent.displays[0].gauges[0].color = Some(Color::from_rgb565(OLED_COLOR_CYAN));
+            sent.displays[1].gauges[0].critical = true;
+            sent.timestamp = Some(1_700_000_000_000);
+            sent.tween_ms = Some(250);
+
+            let json = serde_json::to_string(&OutMessage::Data {
+                message: sent.clone(),
+            })
+            .unwrap();
+
+            match serde_json::from_str::<OutMessage>(&json).unwrap() {
+                OutMessage::Data { message } => assert_eq!(message, sent),
+                other => panic!("read back as {}", other),
+            }
+        }
+
+        #[test]
+        fn data_is_rounded_to_each_gauge_format() {
+            let mut configuration = configuration(&displays_toml(&[1, 2]));
+            configuration.displays[1].gauges[0].format = String::from("%.2f");
+            let mut data = data(&[&[76.9997], &[-12.3456]]);
+            data.displays[0]
+                .gauges
+                .push(GaugeData::new(GaugeData::OFFLINE_VALUE));
+            let second = configuration.displays[0].gauges[0].clone();
+            configuration.displays[0].gauges.push(second);
+
+            configuration.round_data(&mut data);
+
+            assert_eq!(
+                values(&data),
+                [vec![77.0, GaugeData::OFFLINE_VALUE], vec![-12.35]]
+            );
+        }
+
+        #[test]
+        fn inverted_scale_swaps_the_ends() {
+            let sender = gauge("inverted = true");
+
+            assert_eq!(sender.displayed_value(0.0), 130.0);
+            assert_eq!(sender.displayed_value(130.0), 0.0);
+            assert_eq!(sender.displayed_value(30.0), 100.0);
+            assert_eq!(gauge("").displayed_value(30.0), 30.0);
+        }
+
+        #[test]
+        fn inverted_gauge_stays_offline() {
+            let sender = gauge("inverted = true");
+
+            assert_eq!(
+                sender.displayed_value(GaugeData::OFFLINE_VALUE),
+                GaugeData::OFFLINE_VALUE
+            );
+        }
+
+        /// Oil pressure on display 1, critical below 1 bar above 4000 rpm,
+        /// and the tachometer on display 2.
+        fn oil_and_rpm() -> Configuration {
+            return configuration(
+                r#"
+                [[display1.gauges]]
+                id = "oil"
+                name = "OIL"
+                units = "bar"
+                format = "%.1f"
+                min = 0.0
+                max = 10.0
+                low_value = 0.5
+                high_value = 8.0
+                critical = { below = 1.0, when_gauge = "rpm", when_above = 4000.0 }
+
+                [[display2.gauges]]
+                id = "rpm"
+                name = "RPM"
+                units = "rpm"
+                format = "%.0f"
+                min = 0.0
+                max = 8000.0
+                low_value = 0.0
+                high_value = 7000.0
+                "#,
+            );
+        }
+
+        /// Whether the oil gauge is flagged critical at these readings.
+        fn oil_critical(oil: f32, rpm: f32) -> bool {
+            let configuration = oil_and_rpm();
+            let mut data = data(&[&[oil], &[rpm]]);
+
+            configuration.apply_critical(&mut data);
+
+            let gauge = &data.displays[0].gauges[0];
+            assert_eq!(
+                gauge.color.is_some(),
+                gauge.critical,
+                "critical gauges and only those are forced to a color"
+            );
+            return gauge.critical;
+        }
+
+        #[test]
+        fn low_oil_at_high_rpm_is_critical() {
+            assert!(oil_critical(0.8, 5000.0));
+            assert!(!oil_critical(0.8, 2000.0));
+            assert!(!oil_critical(3.0, 5000.0));
+            assert!(!oil_critical(3.0, 2000.0));
+        }
+
+        #[test]
+        fn critical_uses_the_alert_color() {
+            let configuration = oil_and_rpm();
+            let mut data = data(&[&[0.8], &[5000.0]]);
+
+            configuration.apply_critical(&mut data);
+
+            assert_eq!(
+                data.displays[0].gauges[0].color,
+                Some(Color::from_rgb565(OLED_COLOR_RED))
+            );
+            assert!(!data.displays[1].gauges[0].critical);
+        }
+
+        /// Every named constant with the 24-bit color it unpacks to.
+        const NAMED_RGB888: [(&str, u16, u32); 11] = [
+            ("black", OLED_COLOR_BLACK, 0x000000),
+            ("blue", OLED_COLOR_BLUE, 0x0000FF),
+            ("red", OLED_COLOR_RED, 0xFF0000),
+            ("green", OLED_COLOR_GREEN, 0x00FF00),
+            ("cyan", OLED_COLOR_CYAN, 0x00FFFF),
+            ("magenta", OLED_COLOR_MAGENTA, 0xFF00FF),
+            ("yellow", OLED_COLOR_YELLOW, 0xFFFF00),
+            ("warm", OLED_COLOR_WARM, 0xFF8200),
+            ("white", OLED_COLOR_WHITE, 0xFFFFFF),
+            ("dim_red", OLED_COLOR_DIM_RED, 0x7B0000),
+            ("dim_blue", OLED_COLOR_DIM_BLUE, 0x000084),
+        ];
+
+        #[test]
+        fn named_colors_keep_their_packing() {
+            let named: Vec<(&str, u16)> = NAMED_RGB888
+                .iter()
+                .map(|(name, value, _)| (*name, *value))
+                .collect();
+            assert_eq!(NAMED_COLORS.to_vec(), named);
+
+            for (name, value, rgb888) in NAMED_RGB888 {
+                let color = Color::from_rgb565(value);
+                assert_eq!(color.to_rgb565(), value, "{}", name);
+                assert_eq!(color.to_rgb888(), rgb888, "{}", name);
+                assert_eq!(name.parse::<Color>(), Ok(color), "{}", name);
+                assert_eq!(name.to_uppercase().parse::<Color>(), Ok(color), "{}", name);
+            }
+        }
+
+        #[test]
+        fn fuel_sender_readings_map_through_the_table() {
+            // Reads 10 ohms full and 180 ohms empty, with a tank that
+            // narrows towards the bottom.
+            let fuel = gauge(
+                "max = 100.0\nfuel = { table = [[10.0, 100.0], [90.0, 50.0], [180.0, 0.0]], low_fuel = 15.0 }",
+            );
+
+            assert_eq!(fuel.displayed_value(10.0), 100.0);
+            assert_eq!(fuel.displayed_value(50.0), 75.0);
+            assert_eq!(fuel.displayed_value(90.0), 50.0);
+            assert_eq!(fuel.displayed_value(135.0), 25.0);
+            assert_eq!(fuel.displayed_value(180.0), 0.0);
+            // Held at the ends of the table.
+            assert_eq!(fuel.displayed_value(2.0), 100.0);
+            assert_eq!(fuel.displayed_value(250.0), 0.0);
+        }
+
+        #[test]
+        fn fuel_table_must_increase() {
+            let fuel = gauge("max = 100.0\nfuel = { table = [[90.0, 50.0], [10.0, 100.0]] }");
+
+            assert_eq!(
+                fuel.validate(16),
+                ["fuel table readings must be strictly increasing"]
+            );
+        }
+
+        #[test]
+        fn disabled_display_serializes_the_flag() {
+            let toml = displays_toml(&[1, 2]).replace(
+                "[[display2.gauges]]",
+                "[display2]\nenabled = false\n\n[[display2.gauges]]",
+            );
+            let configuration = configuration(&toml);
+
+            let json = serde_json::to_value(&configuration).unwrap();
+
+            assert_eq!(json["display2"]["enabled"], false);
+            assert!(json["display1"].get("enabled").is_none());
+        }
+
+        #[test]
+        fn background_and_accent_default_to_black_and_white() {
+            let theme = theme("");
+
+            assert_eq!(theme.background_color.to_rgb565(), OLED_COLOR_BLACK);
+            assert_eq!(theme.accent_color.to_rgb565(), OLED_COLOR_WHITE);
+            // Left out for firmware that predates them.
+            let json = serde_json::to_value(&theme).unwrap();
+            assert!(json.get(key("background_color")).is_none());
+            assert!(json.get(key("accent_color")).is_none());
+        }
+
+        #[test]
+        fn background_and_accent_can_be_overridden() {
+            let theme = theme("background_color = \"#000080\"\naccent_color = \"yellow\"");
+
+            assert_eq!(theme.background_color.to_rgb565(), 0x0010);
+            assert_eq!(theme.accent_color.to_rgb565(), OLED_COLOR_YELLOW);
+            let json = serde_json::to_value(&theme).unwrap();
+            assert_eq!(json[key("background_color")], 0x0010);
+            assert_eq!(json[key("accent_color")], OLED_COLOR_YELLOW);
+        }
+
+        #[test]
+        fn background_and_accent_must_contrast() {
+            assert!(theme("background_color = \"#000080\"")
+                .validate()
+                .is_empty());
+            assert_eq!(
+                theme("accent_color = \"#080808\"").validate(),
+                ["theme background_color and accent_color are too similar to read"]
+            );
+        }
+
+        #[test]
+        fn display_units_are_sent_next_to_units() {
+            let gauge = gauge("display_units = \"°C\"");
+
+            let json = serde_json::to_value(&gauge).unwrap();
+
+            assert_eq!(json["units"], "C");
+            assert_eq!(json[key("display_units")], "°C");
+            assert_eq!(gauge.shown_units(), "°C");
+        }
+
+        #[test]
+        fn units_are_shown_without_display_units() {
+            let gauge = gauge("");
+
+            let json = serde_json::to_value(&gauge).unwrap();
+
+            // Left out, so older firmware sees the same configuration.
+            assert!(json.get(key("display_units")).is_none());
+            assert_eq!(gauge.shown_units(), "C");
+        }
+
+        #[test]
+        fn imperial_replaces_display_units_only_when_set() {
+            let mut with = gauge("display_units = \"°C\"");
+            let mut without = gauge("");
+
+            with.convert_units(UnitSystem::Imperial);
+            without.convert_units(UnitSystem::Imperial);
+
+            assert_eq!((with.units.as_str(), with.shown_units()), ("F", "°F"));
+            assert_eq!((without.units.as_str(), without.shown_units()), ("F", "F"));
+        }
+
+        /// Every serialized field with a multi-word name, on one display
+        /// with two pages.
+        const FULL_LAYOUT: &str = r##"
+            [theme]
+            background_color = "#000080"
+            accent_color = "yellow"
+
+            [display1]
+            page_interval_ms = 4000
+            pages = [{ gauges = [{ id = "rpm", name = "RPM", units = "rpm", format = "%.0f", min = 0.0, max = 8000.0, low_value = 800.0, high_value = 7000.0, redline = 6500.0 }] }]
+
+            [[display1.gauges]]
+            id = "coolant"
+            name = "COOLANT"
+            units = "C"
+            display_units = "°C"
+            format = "%.0f"
+            min = 0.0
+            max = 130.0
+            low_value = 60.0
+            high_value = 100.0
+            label_color = "white"
+            value_color = "#00FF00"
+            ok_color = "green"
+            low_color = "blue"
+            high_color = "yellow"
+            alert_color = "red"
+            alert_blink = true
+            alert_blink_ms = 400
+            zones = [{ start = 100.0, end = 130.0, color = "red" }]
+        "##;
+
+        #[test]
+        fn configuration_round_trips_through_json() {
+            let sent = serde_json::to_value(configuration(FULL_LAYOUT)).unwrap();
+
+            let read_back: Configuration = serde_json::from_value(sent.clone()).unwrap();
+
+            assert_eq!(serde_json::to_value(read_back).unwrap(), sent);
+            let coolant = &sent["display1"]["gauges"][0];
+            for field in [
+                "display_units",
+                "low_value",
+                "alert_blink_ms",
+                "value_color",
+            ] {
+                assert!(coolant.get(key(field)).is_some(), "{}", field);
+            }
+            assert!(sent["display1"].get(key("page_interval_ms")).is_some());
+            assert!(sent["theme"].get(key("background_color")).is_some());
+        }
+
+        #[test]
+        fn configuration_message_round_trips() {
+            let sent = OutMessage::Configuration {
+                message: configuration(FULL_LAYOUT),
+            };
+            let json = serde_json::to_string(&sent).unwrap();
+
+            let read_back = serde_json::from_str::<OutMessage>(&json).unwrap();
+
+            assert_eq!(serde_json::to_string(&read_back).unwrap(), json);
+        }
+
+        #[test]
+        fn tween_key_is_read_as_written() {
+            let json = format!(r#"{{"display1":{{"gauges":[]}},"{}":120}}"#, TWEEN_MS_KEY);
+
+            let data: Data = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(data.tween_ms, Some(120));
+            let error = serde_json::from_str::<Data>(r#"{"tween":1}"#)
+                .err()
+                .unwrap();
+            assert!(error.to_string().contains(TWEEN_MS_KEY));
+        }
+
+        #[cfg(feature = "camel-case")]
+        #[test]
+        fn camel_case_build_reads_both_spellings() {
+            let snake = configuration(FULL_LAYOUT);
+            let camel = configuration(
+                &FULL_LAYOUT
+                    .replace("low_value", "lowValue")
+                    .replace("page_interval_ms", "pageIntervalMs")
+                    .replace("background_color", "backgroundColor"),
+            );
+
+            assert_eq!(
+                serde_json::to_value(snake).unwrap(),
+                serde_json::to_value(camel).unwrap()
+            );
+        }
+
+        #[cfg(not(feature = "camel-case"))]
+        #[test]
+        fn snake_case_build_rejects_camel_case_keys() {
+            let camel = FULL_LAYOUT.replace("low_value", "lowValue");
+
+            assert!(toml::from_str::<Configuration>(&camel).is_err());
+        }
+
+        #[test]
+        fn invalid_theme_color_names_the_field() {
+            let error = toml::from_str::<GaugeTheme>("high_color = \"chartreuse\"")
+                .err()
+                .unwrap();
+
+            assert!(error
+                .to_string()
+                .contains("theme.high_color: invalid color \"chartreuse\""));
+        }
+
+        #[test]
+        fn imperial_converts_readings_and_thresholds_alike() {
+            let mut coolant = gauge("");
+
+            coolant.convert_units(UnitSystem::Imperial);
+
+            // 0-130 °C with bands at 60 and 100 °C.
+            assert_eq!(
+                (
+                    coolant.min,
+                    coolant.max,
+                    coolant.low_value,
+                    coolant.high_value
+                ),
+                (32.0, 266.0, 140.0, 212.0)
+            );
+            assert_eq!(coolant.displayed_value(100.0), 212.0);
+            for reading in [59.0, 80.0, 101.0] {
+                let metric = gauge("").state(reading);
+                assert_eq!(coolant.state(coolant.displayed_value(reading)), metric);
+            }
+            assert_eq!(
+                coolant.displayed_value(GaugeData::OFFLINE_VALUE),
+                GaugeData::OFFLINE_VALUE
+            );
+            // Sources still see the metric range.
+            assert_eq!(coolant.measured_range(), (0.0, 130.0));
+        }
+
+        #[test]
+        fn gauge_unit_system_overrides_the_default() {
+            let mut boost = gauge("units = \"bar\"\nmin = 0.0\nmax = 2.0\nlow_value = 0.0\nhigh_value = 1.5\nunit_system = \"metric\"");
+            let mut oil = gauge("units = \"bar\"\nmin = 0.0\nmax = 10.0\nlow_value = 1.0\nhigh_value = 8.0\nunit_system = \"imperial\"");
+            let mut rpm = gauge("units = \"rpm\"\nmax = 8000.0");
+
+            assert!(boost.convert_units(UnitSystem::Imperial).is_none());
+            assert!(oil.convert_units(UnitSystem::Metric).is_some());
+            assert!(rpm.convert_units(UnitSystem::Imperial).is_none());
+
+            assert_eq!((boost.units.as_str(), boost.max), ("bar", 2.0));
+            assert_eq!(oil.units, "psi");
+            assert!((oil.max - 145.037_74).abs() < 1e-3);
+            assert_eq!((rpm.units.as_str(), rpm.max), ("rpm", 8000.0));
+            // Converting twice would compound.
+            assert!(oil.convert_units(UnitSystem::Imperial).is_none());
+        }
+
+        /// The built-in configuration as sent to the firmware.
+        const BUILT_IN_GOLDEN: &str = concat!(
+            r#"{"type":1,"message":{"#,
+            r#""theme":{"ok_color":64512,"low_color":31,"high_color":63488,"alert_color":63488},"#,
+            r#""display1":{"gauges":[{"id":"coolant","name":"COOLANT","units":"C","format":"%.0f","min":0.0,"max":130.0,"low_value":60.0,"high_value":100.0}]},"#,
+            r#""display2":{"gauges":[{"id":"oil","name":"OIL","units":"bar","format":"%.2f","min":0.0,"max":10.0,"low_value":1.0,"high_value":8.0}]},"#,
+            r#""display3":{"gauges":[],"enabled":false}}}"#
+        );
+
+        /// `golden` with its multi-word keys as sent on the wire.
+        fn wire_keys(golden: &str) -> String {
+            let mut wire = golden.to_string();
+            for name in [
+                "ok_color",
+                "low_color",
+                "high_color",
+                "alert_color",
+                "low_value",
+                "high_value",
+            ] {
+                wire = wire.replace(&format!("\"{}\"", name), &format!("\"{}\"", key(name)));
+            }
+
+            return wire;
+        }
+
+        fn sent(configuration: Configuration) -> String {
+            let message = OutMessage::Configuration {
+                message: configuration,
+            };
+
+            return serde_json::to_string(&message).unwrap();
+        }
+
+        #[test]
+        fn built_in_configuration_matches_the_golden_json() {
+            assert_eq!(
+                sent(crate::default_configuration()),
+                wire_keys(BUILT_IN_GOLDEN)
+            );
+        }
+
+        #[test]
+        fn display_brightness_is_in_the_golden_json() {
+            let mut configuration = crate::default_configuration();
+
+            configuration.set_brightness(2, 40).unwrap();
+
+            let golden = BUILT_IN_GOLDEN.replace(
+                r#""high_value":8.0}]}"#,
+                r#""high_value":8.0}],"brightness":40}"#,
+            );
+            assert_eq!(sent(configuration), wire_keys(&golden));
+        }
+
+        #[test]
+        fn display_brightness_is_range_checked() {
+            let mut built_in = crate::default_configuration();
+
+            assert!(built_in.set_brightness(1, 256).is_err());
+            assert!(built_in.set_brightness(4, 100).is_err());
+            assert_eq!(sent(built_in), wire_keys(BUILT_IN_GOLDEN));
+
+            let dazzling = configuration(&format!(
+                "{}[display1]\nbrightness = 300\n",
+                displays_toml(&[1])
+            ));
+            let errors = dazzling.validate(16).err().unwrap();
+            assert_eq!(errors, ["display 1: brightness 300 is outside 0-255"]);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! The printf-style `format` of a gauge, which the firmware passes to its
+//! own formatter. Only a single float conversion is safe to pass along.
+
+/// A parsed `[text]%[flags][width][.precision]f[text]` format.
+pub struct FloatFormat {
+    prefix: String,
+    suffix: String,
+    left_align: bool,
+    plus_sign: bool,
+    space_sign: bool,
+    zero_pad: bool,
+    width: usize,
+    pub precision: usize,
+}
+
+/// Precision printf uses when none is given.
+const DEFAULT_PRECISION: usize = 6;
+
+/// Reads a run of ASCII digits, returning the number and the rest.
+fn take_number(text: &str) -> (Option<usize>, &str) {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    return (text[..end].parse().ok(), &text[end..]);
+}
+
+/// Literal text around the specifier, with `%%` unescaped.
+fn parse_literal(text: &str) -> Result<String, String> {
+    let mut literal = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '%' && chars.next() != Some('%') {
+            return Err(String::from("only one % conversion is allowed"));
+        }
+        literal.push(c);
+    }
+
+    return Ok(literal);
+}
+
+impl FloatFormat {
+    pub fn parse(format: &str) -> Result<FloatFormat, String> {
+        let mut start = None;
+        let mut index = 0;
+        while let Some(offset) = format[index..].find('%') {
+            let position = index + offset;
+            if format[position + 1..].starts_with('%') {
+                index = position + 2;
+                continue;
+            }
+            start = Some(position);
+            break;
+        }
+
+        let Some(start) = start else {
+            return Err(String::from("no % conversion for the value"));
+        };
+
+        let mut result = FloatFormat {
+            prefix: parse_literal(&format[..start])?,
+            suffix: String::new(),
+            left_align: false,
+            plus_sign: false,
+            space_sign: false,
+            zero_pad: false,
+            width: 0,
+            precision: DEFAULT_PRECISION,
+        };
+
+        let mut rest = &format[start + 1..];
+        loop {
+            match rest.chars().next() {
+                Some('-') => result.left_align = true,
+                Some('+') => result.plus_sign = true,
+                Some(' ') => result.space_sign = true,
+                Some('0') => result.zero_pad = true,
+                _ => break,
+            }
+            rest = &rest[1..];
+        }
+
+        let (width, after_width) = take_number(rest);
+        result.width = width.unwrap_or(0);
+        rest = after_width;
+
+        if let Some(after_dot) = rest.strip_prefix('.') {
+            let (precision, after_precision) = take_number(after_dot);
+            result.precision = precision.unwrap_or(0);
+            rest = after_precision;
+        }
+
+        match rest.chars().next() {
+            Some('f') => {}
+            Some(conversion) => {
+                return Err(format!("%{} is not a float conversion, use %f", conversion));
+            }
+            None => {
+                return Err(String::from("incomplete % conversion"));
+            }
+        }
+
+        result.suffix = parse_literal(&rest[1..])?;
+
+        return Ok(result);
+    }
+
+    /// `value` rounded to the digits this format shows, so every consumer
+    /// agrees with the display.
+    pub fn round(&self, value: f32) -> f32 {
+        // Beyond this f32 has no digits left to round away.
+        if self.precision > 9 {
+            return value;
+        }
+
+        let scale = 10f64.powi(self.precision as i32);
+        return ((value as f64 * scale).round() / scale) as f32;
+    }
+
+    /// Formats `value` the way printf would with this format.
+    pub fn render(&self, value: f32) -> String {
+        let sign = if value.is_sign_negative() {
+            "-"
+        } else if self.plus_sign {
+            "+"
+        } else if self.space_sign {
+            " "
+        } else {
+            ""
+        };
+        let digits = format!("{:.*}", self.precision, value.abs());
+
+        let padding = self.width.saturating_sub(sign.len() + digits.len());
+        let number = if self.left_align {
+            format!("{}{}{}", sign, digits, " ".repeat(padding))
+        } else if self.zero_pad {
+            format!("{}{}{}", sign, "0".repeat(padding), digits)
+        } else {
+            format!("{}{}{}", " ".repeat(padding), sign, digits)
+        };
+
+        return format!("{}{}{}", self.prefix, number, self.suffix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(format: &str) -> String {
+        return FloatFormat::parse(format).err().unwrap();
+    }
+
+    #[test]
+    fn float_conversions_are_accepted() {
+        assert_eq!(FloatFormat::parse("%.0f").unwrap().render(87.6), "88");
+        assert_eq!(FloatFormat::parse("%.2f").unwrap().render(1.005), "1.00");
+        assert_eq!(
+            FloatFormat::parse("%5.1f C").unwrap().render(-4.25),
+            " -4.2 C"
+        );
+        assert_eq!(
+            FloatFormat::parse("%+06.1f%%").unwrap().render(3.0),
+            "+003.0%"
+        );
+    }
+
+    #[test]
+    fn other_conversions_are_rejected() {
+        assert_eq!(parse_error("%s"), "%s is not a float conversion, use %f");
+        assert_eq!(parse_error("%d"), "%d is not a float conversion, use %f");
+        assert_eq!(
+            parse_error("%.1f / %.1f"),
+            "only one % conversion is allowed"
+        );
+        assert_eq!(parse_error("100%%"), "no % conversion for the value");
+        assert_eq!(parse_error("%.2"), "incomplete % conversion");
+    }
+
+    fn round(format: &str, value: f32) -> f32 {
+        return FloatFormat::parse(format).unwrap().round(value);
+    }
+
+    #[test]
+    fn no_decimals_round_to_integers() {
+        assert_eq!(round("%.0f", 76.9997), 77.0);
+        assert_eq!(round("%.0f", 76.4), 76.0);
+        assert_eq!(round("%.0f", 2.5), 3.0);
+        assert_eq!(round("%.0f", -2.5), -3.0);
+        assert_eq!(round("%.0f", -76.6), -77.0);
+    }
+
+    #[test]
+    fn two_decimals_round_to_hundredths() {
+        assert_eq!(round("%.2f", 3.14729), 3.15);
+        assert_eq!(round("%.2f", 2.678), 2.68);
+        assert_eq!(round("%.2f", -0.125), -0.13);
+        assert_eq!(round("%.2f", -12.3449), -12.34);
+    }
+
+    #[test]
+    fn missing_precision_keeps_six_decimals() {
+        assert_eq!(round("%f", 1.234_567_8), 1.234_568);
+    }
+}
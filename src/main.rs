@@ -1,36 +1,124 @@
+#![allow(clippy::needless_return)]
+
 use core::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use clap::Parser;
+use clock::{Clock, SystemClock};
+use connection::{Connection, ConnectionState, StateLog};
+use control::Control;
 use dto::dto::{InMessage, OutMessage};
-use serde_json;
+use log::{debug, error, info, log, trace, warn};
+use reload::Reloader;
+use repeated_log::RepeatedLog;
 use serialport::{self, SerialPort};
+use settings::Settings;
+use shutdown::Shutdown;
+use source::DataSource;
+use transport::{FrameReader, Resynchronizer, MESSAGE_END_BYTE};
 
+#[cfg(feature = "async")]
+mod async_backend;
+mod cli;
+mod clock;
+mod color;
+mod config;
+mod connection;
+mod control;
+#[allow(clippy::module_inception)]
 mod dto;
+mod format;
+mod pages;
+mod ports;
+mod rate_limit;
+mod reload;
+mod repeated_log;
+mod schema;
+mod self_test;
+mod settings;
+mod shared;
+mod shutdown;
+mod source;
+mod stats;
+#[cfg(test)]
+mod test_log;
+mod transport;
+mod units;
+
+/// Picks the port named in the settings, or the first one found.
+fn find_port_name(settings: &Settings) -> Option<String> {
+    return choose_port(settings, serialport::available_ports);
+}
 
-const MESSAGE_END_BYTE: u8 = '\n' as u8;
+/// `find_port_name` with the enumeration passed in.
+fn choose_port(
+    settings: &Settings,
+    available_ports: impl FnOnce() -> serialport::Result<Vec<serialport::SerialPortInfo>>,
+) -> Option<String> {
+    // A pinned port is waited for by name instead of searched for, which
+    // also covers devices the enumeration misses, such as the symlinks in
+    // /dev/serial/by-id.
+    if let Some(port_name) = &settings.port {
+        #[cfg(unix)]
+        if !std::path::Path::new(port_name).exists() {
+            debug!("Port {} not present yet", port_name);
+            return None;
+        }
 
-fn get_port() -> Option<Box<dyn serialport::SerialPort>> {
-    println!("Searching for serial ports...");
+        return Some(port_name.clone());
+    }
 
-    let ports = serialport::available_ports().expect("No ports found!");
+    debug!("Searching for serial ports...");
 
-    for port_info in ports {
-        println!("{}", port_info.port_name);
+    let ports = match available_ports() {
+        Ok(ports) => ports,
+        Err(error) => {
+            // Enumeration fails transiently on some OSes; the caller retries.
+            warn!("Failed to enumerate serial ports: {}", error);
+            return None;
+        }
+    };
 
-        // FIXME: port_name as path probably won't work on Linux
-        let port = serialport::new(port_info.port_name, 115_200)
-            .timeout(Duration::from_millis(1000))
-            .open()
-            .expect("Failed to open port");
+    for port_info in ports {
+        debug!("{}", ports::describe_port(&port_info));
 
-        println!("Port {} opened", port.name().expect("No port name!"));
+        // Never pick the OBD-II adapter or GPS module as the gauge port.
+        if settings.obd2_port.as_ref() == Some(&port_info.port_name)
+            || settings.gps_port.as_ref() == Some(&port_info.port_name)
+        {
+            continue;
+        }
 
-        return Some(port);
+        return Some(port_info.port_name);
     }
 
     return None;
 }
 
+fn get_port(settings: &Settings) -> Option<Box<dyn serialport::SerialPort>> {
+    let port_name = find_port_name(settings)?;
+
+    // FIXME: port_name as path probably won't work on Linux
+    let port = match serialport::new(&port_name, settings.baud_rate)
+        .timeout(settings.timeout())
+        .open()
+    {
+        Ok(port) => port,
+        Err(error) => {
+            warn!("Failed to open port {}: {}", port_name, error);
+            return None;
+        }
+    };
+
+    info!("Port {} opened", port.name().expect("No port name!"));
+
+    return Some(port);
+}
+
 enum Error {
     IO(std::io::Error),
     UtfConversion(std::string::FromUtf8Error),
@@ -38,6 +126,10 @@ enum Error {
         error: serde_json::Error,
         source_string: String,
     },
+    Config {
+        path: PathBuf,
+        message: String,
+    },
 }
 
 impl fmt::Display for Error {
@@ -45,229 +137,2925 @@ impl fmt::Display for Error {
         match self {
             Self::IO(error) => error.fmt(f),
             Self::UtfConversion(error) => error.fmt(f),
+            Self::JsonParsing {
+                error,
+                source_string,
+            } if source_string.is_empty() => error.fmt(f),
             Self::JsonParsing {
                 error,
                 source_string,
             } => {
-                write!(f, "{} source string: {}", error, source_string)
+                let offset = json_error_offset(error, source_string);
+                write!(
+                    f,
+                    "{} (byte {}, near {})",
+                    error,
+                    offset,
+                    excerpt(source_string, offset)
+                )
+            }
+            Self::Config { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
             }
         }
     }
 }
 
-fn read_message_string(port: &mut Box<dyn SerialPort>) -> Result<String, Error> {
-    let mut message_string_buffer: Vec<u8> = Vec::new();
+/// Bytes of context shown on each side of a JSON parsing error.
+const EXCERPT_CONTEXT: usize = 20;
 
-    let mut found_message_start = false;
-    let mut found_message_end = false;
+/// Byte offset into `source` of the position serde_json reports as a line
+/// and column, both 1-based.
+fn json_error_offset(error: &serde_json::Error, source: &str) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(error.line().saturating_sub(1))
+        .map(str::len)
+        .sum();
 
-    while !found_message_end {
-        let mut message_buffer: [u8; 1] = [0; 1];
-        let result = port.read(&mut message_buffer);
+    return (line_start + error.column().saturating_sub(1)).min(source.len());
+}
 
-        match result {
-            Ok(size) => {
-                let (message_bytes, _) = message_buffer.split_at(size);
+/// The text around `offset`, with `^` marking it and `...` where `source`
+/// was cut. Frames can be long; the whole of one is only logged with -v.
+fn excerpt(source: &str, offset: usize) -> String {
+    let mut start = offset.saturating_sub(EXCERPT_CONTEXT);
+    while !source.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut offset = offset;
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let mut end = (offset + EXCERPT_CONTEXT).min(source.len());
+    while !source.is_char_boundary(end) {
+        end += 1;
+    }
 
-                for byte_ref in message_bytes {
-                    let byte = byte_ref.to_owned();
+    return format!(
+        "\"{}{}^{}{}\"",
+        if start > 0 { "..." } else { "" },
+        &source[start..offset],
+        &source[offset..end],
+        if end < source.len() { "..." } else { "" }
+    );
+}
 
-                    if byte == MESSAGE_END_BYTE {
-                        if !found_message_start {
-                            found_message_start = true;
-                            continue;
-                        } else if !found_message_end {
-                            found_message_end = true;
-                            continue;
-                        }
-                    }
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        return Error::IO(error);
+    }
+}
 
-                    if found_message_start && !found_message_end {
-                        message_string_buffer.push(byte);
-                    }
-                }
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        return Error::UtfConversion(error);
+    }
+}
+
+/// Leaves the source string empty; use `Error::with_source` to attach the
+/// text that failed to parse.
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        return Error::JsonParsing {
+            error,
+            source_string: String::new(),
+        };
+    }
+}
+
+impl Error {
+    /// Attaches the offending text to a JSON parsing error.
+    fn with_source(self, source_string: String) -> Error {
+        match self {
+            Error::JsonParsing { error, .. } => {
+                return Error::JsonParsing {
+                    error,
+                    source_string,
+                };
             }
-            Err(error) => {
-                return Err(Error::IO(error));
+            error => {
+                return error;
             }
         }
     }
+}
 
-    match String::from_utf8(message_string_buffer) {
-        Ok(string) => {
-            return Ok(string);
+/// Parses one frame from the firmware, keeping the frame in the error.
+fn parse_message(json_string: String) -> Result<InMessage, Error> {
+    match serde_json::from_str::<InMessage>(&json_string) {
+        Ok(message) => {
+            return Ok(message);
         }
         Err(error) => {
-            return Err(Error::UtfConversion(error));
+            return Err(Error::from(error).with_source(json_string));
         }
     }
 }
 
-fn read_message(
-    port: &mut Box<dyn SerialPort>,
+fn read_message<P: Read + ?Sized>(
+    port: &mut P,
+    frame_reader: &mut FrameReader,
     is_communication_begin: &mut bool,
-) -> Result<dto::dto::InMessage, Error> {
+) -> Result<Option<dto::dto::InMessage>, Error> {
     if *is_communication_begin {
         *is_communication_begin = false;
-        return Ok(InMessage::NeedGaugeConfig {});
+        return Ok(Some(InMessage::NeedGaugeConfig {}));
     }
 
-    match read_message_string(port) {
-        Ok(json_string) => match serde_json::from_str::<dto::dto::InMessage>(&json_string) {
-            Ok(json_value) => {
-                return Ok(json_value);
-            }
+    let Some(json_string) = frame_reader.read_frame(port)? else {
+        return Ok(None);
+    };
+
+    return Ok(Some(parse_message(json_string)?));
+}
+
+/// Reads frames until one decodes, the sign that the firmware has booted
+/// and is ready for its configuration, for at most `timeout`. The frame
+/// itself is dropped; the firmware repeats its requests.
+fn wait_until_ready<P: Read + ?Sized>(
+    port: &mut P,
+    frame_reader: &mut FrameReader,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> bool {
+    let deadline = clock.now() + timeout;
+
+    while clock.now() < deadline {
+        match frame_reader.read_frame(port) {
+            Ok(Some(json_string)) => match parse_message(json_string) {
+                Ok(message) => {
+                    info!("Firmware ready ({})", message);
+                    return true;
+                }
+                Err(error) => debug!("Waiting for firmware: {}", error),
+            },
+            Ok(None) => {}
             Err(error) => {
-                return Err(Error::JsonParsing {
-                    error: error,
-                    source_string: json_string,
-                });
+                warn!("Error while waiting for firmware: {}", error);
+                return false;
             }
-        },
-        Err(error) => {
-            return Err(error);
         }
     }
+
+    return false;
 }
 
-fn handle_error(error: Error) -> Result<(), Error> {
-    // Cast the error to `&dyn Any` to use `is::<T>()` method
-    if matches!(error, Error::IO(_)) {
-        println!(
-            "IO error while working with port: {}; Abandoning port...",
-            error
+/// Consecutive IO errors tolerated on a port before it is abandoned.
+struct RetryBudget {
+    limit: u32,
+    failures: u32,
+}
+
+impl RetryBudget {
+    fn new(limit: u32) -> RetryBudget {
+        return RetryBudget { limit, failures: 0 };
+    }
+
+    /// Called after a successful exchange with the firmware.
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Records a failure, returning whether another retry is allowed.
+    fn spend(&mut self) -> bool {
+        self.failures += 1;
+        return self.failures <= self.limit;
+    }
+}
+
+/// IO errors meaning the device is gone, where retrying can't help.
+fn is_fatal_io_error(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    return matches!(
+        error.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+    );
+}
+
+fn handle_error(error: Error, retry_budget: &mut RetryBudget) -> Result<(), Error> {
+    stats::error(&error);
+
+    if let Error::IO(io_error) = &error {
+        if is_fatal_io_error(io_error) || !retry_budget.spend() {
+            error!(
+                "IO error while working with port: {}; Abandoning port...",
+                error
+            );
+            return Err(error);
+        }
+
+        warn!(
+            "IO error while working with port: {}; Retrying ({}/{})...",
+            error, retry_budget.failures, retry_budget.limit
         );
-        return Err(error);
+        return Ok(());
     }
 
-    println!("Transient error while working with port: {}", error);
+    warn!("Transient error while working with port: {}", error);
+    if let Error::JsonParsing { source_string, .. } = &error {
+        debug!("Frame that failed to parse: {}", source_string);
+    }
     return Ok(());
 }
 
-fn handle_message(message: &InMessage) -> Option<OutMessage> {
-    use rand::prelude::*;
+/// Built-in gauge layout, kept as data in the config file format.
+const DEFAULT_CONFIGURATION: &str = include_str!("default_config.toml");
+
+fn default_configuration() -> dto::dto::Configuration {
+    return toml::from_str(DEFAULT_CONFIGURATION).expect("built-in configuration is valid");
+}
+
+fn log_alerts(configuration: &dto::dto::DisplayConfiguration, data: &dto::dto::DisplayData) {
+    for (gauge, value) in configuration.gauges.iter().zip(data.gauges.iter()) {
+        if value.critical {
+            let low_fuel_message = gauge
+                .fuel
+                .as_ref()
+                .filter(|fuel| fuel.is_low(value.current_value))
+                .and_then(|fuel| fuel.low_fuel_message.as_ref());
+
+            match low_fuel_message {
+                Some(message) => warn!("{}: {}", gauge.name, message),
+                None => warn!("Critical: {} at {}", gauge.name, value.current_value),
+            }
+        } else if gauge.state(value.current_value) == dto::dto::GaugeState::Alert {
+            warn!("Alert: {} at {}", gauge.name, value.current_value);
+        }
+    }
+}
+
+/// Wall-clock milliseconds, never lower than a previously returned value
+/// even if the system clock is stepped back.
+fn timestamp_ms(clock: &dyn Clock) -> u64 {
+    static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+    let now = clock.unix_ms();
+    let previous = LAST_TIMESTAMP.fetch_max(now, Ordering::Relaxed);
 
+    return now.max(previous);
+}
+
+fn handle_message(
+    message: &InMessage,
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    connection: &mut Connection,
+    source: &mut dyn DataSource,
+) -> Vec<OutMessage> {
     match message {
         InMessage::NeedGaugeConfig {} => {
-            let result = OutMessage::Configuration {
-                message: dto::dto::Configuration {
-                    theme: dto::dto::GaugeTheme::default(),
-                    display1: dto::dto::DisplayConfiguration {
-                        gauges: vec![dto::dto::GaugeConfig {
-                            name: String::from("COOLANT"),
-                            units: String::from("C"),
-                            format: String::from("%.0f"),
-                            min: 0.0,
-                            max: 130.0,
-                            low_value: 60.0,
-                            high_value: 100.0,
-                        }],
-                    },
-                    display2: dto::dto::DisplayConfiguration {
-                        gauges: vec![dto::dto::GaugeConfig {
-                            name: String::from("OIL"),
-                            units: String::from("bar"),
-                            format: String::from("%.2f"),
-                            min: 0.0,
-                            max: 10.0,
-                            low_value: 1.0,
-                            high_value: 8.0,
-                        }],
-                    },
-                    display3: dto::dto::DisplayConfiguration { gauges: vec![] },
-                },
-            };
+            connection.restart(connection.clock.now());
+            connection.expect_ack(connection.clock.now());
 
-            return Some(result);
+            let mut result = vec![OutMessage::Configuration {
+                message: configuration.clone(),
+            }];
+
+            if let Some(level) = settings.brightness {
+                result.push(OutMessage::Brightness {
+                    message: dto::dto::Brightness::clamped(level),
+                });
+            }
+
+            return result;
         }
         InMessage::NeedGaugeData {} => {
-            let mut rng = rand::thread_rng();
-            let factor = rng.gen::<f32>();
-
-            let result = OutMessage::Data {
-                message: dto::dto::Data {
-                    display1: dto::dto::DisplayData {
-                        gauges: vec![dto::dto::GaugeData {
-                            // COOLANT C
-                            current_value: 77.0 * factor,
-                        }],
-                    },
-                    display2: dto::dto::DisplayData {
-                        gauges: vec![dto::dto::GaugeData {
-                            // OIL bar
-                            current_value: 6.5 * factor,
-                        }],
-                    },
-                    display3: dto::dto::DisplayData { gauges: vec![] },
-                },
+            // Over the rate limit, priority displays still get fresh data.
+            let throttled = !connection.data_limit.try_take(connection.clock.now());
+            if throttled
+                && !configuration
+                    .displays
+                    .iter()
+                    .any(|display| display.priority)
+            {
+                debug!("Data request over max_data_rate, dropped");
+                return vec![];
+            }
+
+            // Only the gauges on the pages being shown are sampled.
+            let mut view = configuration.with_pages(connection.pages.active());
+            if throttled {
+                view = view.priority_only();
+            }
+
+            let mut data = source::sample(source, &view);
+            data.timestamp = Some(timestamp_ms(connection.clock.as_ref()));
+            data.tween_ms = settings.tween();
+
+            if let Err(error) = view.check_data(&data) {
+                error!("Refusing to send data: {}", error);
+                return vec![];
+            }
+
+            let mut result = Vec::new();
+
+            if configuration.auto_scale(&view, &data) {
+                info!("Gauge range grown to fit new values; resending configuration");
+                view = configuration.with_pages(connection.pages.active());
+                if throttled {
+                    view = view.priority_only();
+                }
+
+                result.push(OutMessage::Configuration {
+                    message: configuration.clone(),
+                });
+                connection.expect_ack(connection.clock.now());
+                result.extend(connection.pages.restore());
+            }
+
+            for error in view.conform_data(&mut data) {
+                error!("Mismatched data: {}", error);
+            }
+
+            connection.apply_initial_values(&view, &mut data);
+            view.round_data(&mut data);
+            connection.apply_deadband(&view, &mut data);
+
+            view.apply_gradient(&mut data);
+            view.apply_critical(&mut data);
+
+            for (display, display_data) in view.displays.iter().zip(data.displays.iter()) {
+                log_alerts(display, display_data);
+            }
+
+            if throttled {
+                let full_view = configuration.with_pages(connection.pages.active());
+                connection.repeat_skipped(&full_view, &mut data);
+            }
+            connection.remember(&data);
+
+            result.push(OutMessage::Data { message: data });
+            return result;
+        }
+        InMessage::NeedDisplayConfig { display } => {
+            let index = display.checked_sub(1);
+            let Some(display_configuration) =
+                index.and_then(|index| configuration.displays.get(index))
+            else {
+                warn!(
+                    "Firmware asked for display {}, but there are displays 1-{}",
+                    display,
+                    configuration.displays.len()
+                );
+                return vec![];
             };
 
-            return Some(result);
+            let mut result = vec![OutMessage::DisplayConfiguration {
+                message: dto::dto::SingleDisplay {
+                    display: *display,
+                    configuration: display_configuration.clone(),
+                },
+            }];
+
+            // Back to the active page, as for a full configuration.
+            result.extend(connection.pages.restore().into_iter().filter(|message| {
+                matches!(message, OutMessage::ShowPage { message } if message.display == *display)
+            }));
+
+            return result;
         }
-        InMessage::Debug { message } => {
-            println!("Debug: {}", message);
-            return None;
+        InMessage::Debug { message, level } => {
+            log!(*level, "Debug: {}", message);
+            return vec![];
+        }
+        InMessage::ConfigAck { ok, error } => {
+            if !connection.config_acknowledged(*ok, error.as_deref()) {
+                return vec![];
+            }
+
+            info!("Resending configuration");
+            return handle_message(
+                &InMessage::NeedGaugeConfig {},
+                settings,
+                configuration,
+                connection,
+                source,
+            );
+        }
+        InMessage::Headlights { on } => {
+            if !configuration.set_night(*on) {
+                return vec![];
+            }
+
+            // The same gauges with the other theme, kept for later requests
+            // so a rebooted display comes back with it.
+            info!(
+                "Switching to the {} theme",
+                if *on { "night" } else { "day" }
+            );
+            return handle_message(
+                &InMessage::NeedGaugeConfig {},
+                settings,
+                configuration,
+                connection,
+                source,
+            );
         }
     }
 }
 
-fn write_message(
-    port: &mut Box<dyn SerialPort>,
+/// The configuration again if the firmware hasn't acknowledged it in time.
+fn resend_unacknowledged(
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    connection: &mut Connection,
+    source: &mut dyn DataSource,
+) -> Vec<OutMessage> {
+    if !connection.ack_overdue(connection.clock.now()) {
+        return vec![];
+    }
+
+    info!("Resending configuration");
+    return handle_message(
+        &InMessage::NeedGaugeConfig {},
+        settings,
+        configuration,
+        connection,
+        source,
+    );
+}
+
+/// Sent before closing the port on shutdown: with `offline_on_exit`, every
+/// gauge on the pages being shown goes offline.
+fn shutdown_messages(
+    settings: &Settings,
+    configuration: &dto::dto::Configuration,
+    connection: &Connection,
+) -> Vec<OutMessage> {
+    if !settings.offline_on_exit {
+        return vec![];
+    }
+
+    let view = configuration.with_pages(connection.pages.active());
+    let mut data = source::sample(&mut source::OfflineSource, &view);
+    data.timestamp = Some(timestamp_ms(connection.clock.as_ref()));
+
+    return vec![OutMessage::Data { message: data }];
+}
+
+/// JSON of an outgoing message as logged, pretty-printed when
+/// `pretty_log_json` is set. The wire always gets the compact `json`.
+fn logged_json(message: &OutMessage, json: &str, settings: &Settings) -> String {
+    if settings.pretty_log_json {
+        return serde_json::to_string_pretty(message).unwrap();
+    }
+
+    return json.to_string();
+}
+
+fn write_message<P: Write + ?Sized>(
+    port: &mut P,
     message: dto::dto::OutMessage,
+    settings: &Settings,
 ) -> Result<(), Error> {
-    println!("OutMessage: {}", serde_json::to_string(&message).unwrap());
+    debug!("OutMessage: {}", message);
+    shared::publish(&message);
+    stats::message_out();
+
+    let json = serde_json::to_string(&message).unwrap();
+    trace!(
+        "OutMessage JSON: {}",
+        logged_json(&message, &json, settings)
+    );
 
-    let mut out_message_buf = serde_json::to_vec(&message).unwrap();
+    let mut out_message_buf = json.into_bytes();
 
     out_message_buf.push(MESSAGE_END_BYTE);
 
-    match port.write_all(&out_message_buf) {
-        Ok(_) => {
-            return Ok(());
+    // Bounded by the port timeout: firmware that stopped reading fails the
+    // write with TimedOut, which the retry budget turns into a reconnect.
+    port.write_all(&out_message_buf)?;
+
+    return Ok(());
+}
+
+/// Sends the configuration and sweeps every gauge through its range.
+fn write_messages<P: Write + ?Sized>(
+    port: &mut P,
+    messages: Vec<dto::dto::OutMessage>,
+    settings: &Settings,
+) -> Result<(), Error> {
+    for message in messages {
+        write_message(port, message, settings)?;
+    }
+
+    return Ok(());
+}
+
+fn run_self_test<P: Write + ?Sized>(
+    port: &mut P,
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    connection: &mut Connection,
+    source: &mut dyn DataSource,
+) -> Result<(), Error> {
+    info!("Running self-test");
+
+    write_messages(
+        port,
+        handle_message(
+            &InMessage::NeedGaugeConfig {},
+            settings,
+            configuration,
+            connection,
+            source,
+        ),
+        settings,
+    )?;
+
+    let interval = self_test::frame_interval(settings.self_test_duration());
+    for data in self_test::sweep(configuration) {
+        write_message(port, OutMessage::Data { message: data }, settings)?;
+        std::thread::sleep(interval);
+    }
+
+    return Ok(());
+}
+
+/// Answers a synthetic config and data request, printing the replies as
+/// they would go on the wire, without opening any port.
+fn dry_run(
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    source: &mut dyn DataSource,
+) {
+    for out_message in dry_run_messages(settings, configuration, source) {
+        println!("{}", serde_json::to_string(&out_message).unwrap());
+    }
+}
+
+/// The replies `dry_run` prints.
+fn dry_run_messages(
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    source: &mut dyn DataSource,
+) -> Vec<OutMessage> {
+    let requests = [InMessage::NeedGaugeConfig {}, InMessage::NeedGaugeData {}];
+    let mut connection = Connection::new(configuration, settings, Arc::new(SystemClock));
+
+    return requests
+        .iter()
+        .flat_map(|request| {
+            handle_message(request, settings, configuration, &mut connection, source)
+        })
+        .collect();
+}
+
+/// Sends the configuration and one data sample to the port and returns the
+/// exit code, checking the whole pipeline without serving forever.
+fn run_once(
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    source: &mut dyn DataSource,
+) -> i32 {
+    let Some(mut port) = get_port(settings) else {
+        error!("No port to send to");
+        return 1;
+    };
+
+    if let Err(error) = activate_port(&mut port, settings) {
+        error!("Error activating port: {}", error);
+        return 1;
+    }
+
+    return send_once(&mut port, settings, configuration, source);
+}
+
+/// The writing half of `run_once`, on an already open port.
+fn send_once<P: Write + ?Sized>(
+    port: &mut P,
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    source: &mut dyn DataSource,
+) -> i32 {
+    // The same replies a dry run prints.
+    let out_messages = dry_run_messages(settings, configuration, source);
+
+    match write_messages(port, out_messages, settings) {
+        Ok(()) => {
+            info!("Sent configuration and one data sample");
+            return 0;
         }
         Err(error) => {
-            return handle_error(Error::IO(error));
+            error!("Failed to send: {}", error);
+            return 1;
         }
     }
 }
 
-fn main() {
-    loop {
-        match get_port() {
-            Some(mut port) => {
-                let mut is_communication_begin = true;
-                match port.write_data_terminal_ready(true) {
-                    Err(error) => {
-                        println!("Error activating port: {}", error);
-                        std::thread::sleep(Duration::from_secs(1));
-                    }
-                    Ok(_) => loop {
-                        match read_message(&mut port, &mut is_communication_begin) {
-                            Ok(message) => {
-                                println!("InMessage: {}", message);
-                                let res = handle_message(&message).and_then(|out_message| {
-                                    return Some(write_message(&mut port, out_message));
-                                });
-
-                                if res.is_some_and(|res| res.is_err()) {
-                                    // unrecoverable error - stop using port
-                                    break;
-                                }
-                            }
-                            Err(error) => {
-                                if handle_error(error).is_err() {
-                                    // unrecoverable error - stop using port
-                                    break;
-                                }
-                            }
+/// The settings and the configuration in the selected profile.
+fn load_config(cli: &cli::Cli) -> Result<(Settings, dto::dto::Configuration), Error> {
+    let Some(path) = &cli.config else {
+        let mut settings = Settings::default();
+        settings.apply_cli(cli);
+        let mut configuration = default_configuration();
+        configuration.convert_units(settings.unit_system);
+        return Ok((settings, configuration));
+    };
+
+    let config_file = config::load(path, cli.config_format)?;
+    let mut settings = config_file.settings.clone();
+    settings.apply_cli(cli);
+
+    match config_file.layout(settings.profile.as_deref()) {
+        Ok(mut configuration) => {
+            configuration.convert_units(settings.unit_system);
+            return Ok((settings, configuration));
+        }
+        Err(message) => {
+            return Err(Error::Config {
+                path: path.clone(),
+                message,
+            });
+        }
+    }
+}
+
+/// Loads and validates the configuration without touching any port.
+fn validate_config(cli: &cli::Cli) -> i32 {
+    let (settings, configuration) = match load_config(cli) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{}", error);
+            return 1;
+        }
+    };
+
+    match configuration.validate(settings.max_label_length) {
+        Ok(()) => {
+            // Preview each format so mistakes show up before the firmware
+            // renders them.
+            for display in &configuration.displays {
+                for page in 0..display.page_count() {
+                    for gauge in display.page(page) {
+                        if let Ok(format) = format::FloatFormat::parse(&gauge.format) {
+                            println!(
+                                "{}: {} -> \"{}\"",
+                                gauge.id,
+                                gauge.format,
+                                format.render(gauge.high_value)
+                            );
                         }
-                    },
+                    }
                 }
             }
-            None => {
-                println!("Waiting for port...");
-                std::thread::sleep(Duration::from_secs(1));
+            println!("Configuration is valid");
+            return 0;
+        }
+        Err(errors) => {
+            for error in errors {
+                println!("{}", error);
+            }
+            return 1;
+        }
+    }
+}
+
+/// Prints the resolved settings and the configuration exactly as the
+/// firmware will receive it.
+fn print_config(cli: &cli::Cli) -> i32 {
+    let (settings, configuration) = match load_config(cli) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{}", error);
+            return 1;
+        }
+    };
+
+    color::set_color_depth(settings.color_depth);
+
+    let resolved = resolved_config(&settings, &configuration);
+    println!("{}", serde_json::to_string_pretty(&resolved).unwrap());
+
+    return 0;
+}
+
+/// Settings and configuration as printed by `--print-config`.
+fn resolved_config(
+    settings: &Settings,
+    configuration: &dto::dto::Configuration,
+) -> serde_json::Value {
+    return serde_json::json!({
+        "settings": settings,
+        "configuration": configuration,
+    });
+}
+
+/// Writes a starting config file for `--init-config`.
+fn init_config(path: &Path, force: bool) -> i32 {
+    match config::init(path, force) {
+        Ok(()) => {
+            println!("Wrote {}", path.display());
+            return 0;
+        }
+        Err(error) => {
+            println!("{}", error);
+            return 1;
+        }
+    }
+}
+
+fn main() {
+    let mut cli = cli::Cli::parse();
+    if let Err(error) = cli.apply_env() {
+        eprintln!("{}", error);
+        std::process::exit(2);
+    }
+
+    if cli.list_ports {
+        std::process::exit(ports::list_ports());
+    }
+
+    if cli.print_schema {
+        let schema = schema::protocol_schema();
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return;
+    }
+
+    if cli.validate_config {
+        std::process::exit(validate_config(&cli));
+    }
+
+    if cli.print_config {
+        std::process::exit(print_config(&cli));
+    }
+
+    if let Some(path) = &cli.init_config {
+        std::process::exit(init_config(path, cli.force));
+    }
+
+    // A missing or broken config file doesn't leave the displays dark: the
+    // built-in configuration is served until the file is fixed and reloaded.
+    let (settings, mut configuration, load_error) = match load_config(&cli) {
+        Ok((settings, configuration)) => (settings, configuration, None),
+        Err(error) => {
+            let mut settings = Settings::default();
+            settings.apply_cli(&cli);
+            let mut configuration = default_configuration();
+            configuration.convert_units(settings.unit_system);
+            (settings, configuration, Some(error))
+        }
+    };
+
+    // -q/-v override RUST_LOG, which overrides the configured level.
+    let mut logger = env_logger::Builder::new();
+    match cli.verbosity() {
+        Some(level) => logger.filter_level(level),
+        None => logger.filter_level(settings.log_level).parse_default_env(),
+    };
+    logger.init();
+
+    if let Some(error) = load_error {
+        error!(
+            "Failed to load configuration: {}; Using the built-in configuration",
+            error
+        );
+    }
+    if cli.config.is_none() && settings.profile.is_some() {
+        warn!("Profiles come from a config file, but none was given; Using the built-in configuration");
+    }
+
+    color::set_color_depth(settings.color_depth);
+
+    if let Err(errors) = configuration.validate(settings.max_label_length) {
+        for error in errors {
+            error!("Invalid configuration: {}", error);
+        }
+        std::process::exit(1);
+    }
+
+    let mut source = match source::create(&settings) {
+        Ok(source) => source,
+        Err(error) => {
+            error!("Failed to create data source: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    if cli.dry_run {
+        dry_run(&settings, &mut configuration, source.as_mut());
+        return;
+    }
+
+    if cli.once {
+        std::process::exit(run_once(&settings, &mut configuration, source.as_mut()));
+    }
+
+    stats::start();
+    let control = Control::new(settings.control_stdin);
+    let shutdown = Shutdown::new();
+
+    // Only returns on shutdown or once out of reconnects.
+    if settings.ports.is_empty() {
+        let reloader = Reloader::new(cli.config.clone(), cli.config_format, &settings);
+        serve(
+            &cli,
+            &settings,
+            &mut configuration,
+            &reloader,
+            &control,
+            &shutdown,
+            source.as_mut(),
+        );
+    } else {
+        serve_ports(
+            &cli,
+            &settings,
+            &configuration,
+            control,
+            &shutdown,
+            source.as_mut(),
+        );
+    }
+    exit_after_serving(&shutdown, source);
+}
+
+/// Serves one port, or the first one found, with the async backend unless
+/// --sync was given.
+fn serve(
+    cli: &cli::Cli,
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+) {
+    #[cfg(feature = "async")]
+    if !cli.sync {
+        async_backend::run(settings, configuration, reloader, control, shutdown, source);
+        return;
+    }
+    #[cfg(not(feature = "async"))]
+    let _ = cli;
+
+    run_sync(settings, configuration, reloader, control, shutdown, source);
+}
+
+/// Serves every port in `ports` at once, each on a thread of its own with
+/// its own connection and copy of the configuration. The source is shared,
+/// so all ports show the same readings. Returns once every worker stopped.
+fn serve_ports(
+    cli: &cli::Cli,
+    settings: &Settings,
+    configuration: &dto::dto::Configuration,
+    control: Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+) {
+    let source = Mutex::new(source);
+
+    std::thread::scope(|scope| {
+        for port in &settings.ports {
+            let mut settings = settings.clone();
+            settings.port = Some(port.clone());
+            let mut configuration = configuration.clone();
+            let reloader = Reloader::new(cli.config.clone(), cli.config_format, &settings);
+            let control = control.subscribe();
+            let source = &source;
+
+            let worker =
+                std::thread::Builder::new()
+                    .name(port.clone())
+                    .spawn_scoped(scope, move || {
+                        info!("Serving port {}", port);
+                        let mut source = source::LockedSource::new(source);
+                        serve(
+                            cli,
+                            &settings,
+                            &mut configuration,
+                            &reloader,
+                            &control,
+                            shutdown,
+                            &mut source,
+                        );
+                        info!("Stopped serving port {}", port);
+                    });
+            if let Err(error) = worker {
+                error!("Failed to start worker for port {}: {}", port, error);
             }
         }
+
+        // The workers have their own receivers of stdin commands.
+        drop(control);
+    });
+}
+
+/// Exits successfully when asked to shut down and with an error when out
+/// of reconnects. The source is dropped first, since `exit` skips
+/// destructors and some sources clean up after themselves.
+fn exit_after_serving(shutdown: &Shutdown, source: Box<dyn DataSource>) -> ! {
+    drop(source);
+
+    if shutdown.requested() {
+        info!("Shut down");
+        std::process::exit(0);
+    }
+
+    std::process::exit(1);
+}
+
+/// Serves requests on an open port until it has to be abandoned. Any
+/// byte stream will do, which is how the tests stand in for the firmware.
+#[allow(clippy::too_many_arguments)]
+fn serve_port<P: Read + Write + ?Sized>(
+    port: &mut P,
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+    states: &mut StateLog,
+    clock: Arc<dyn Clock>,
+) {
+    let mut frame_reader = FrameReader::new();
+    let mut resynchronizer = Resynchronizer::new(settings.resync);
+    let mut is_communication_begin = true;
+    let mut last_inbound = clock.now();
+    let mut retry_budget = RetryBudget::new(settings.io_retries);
+    let mut connection = Connection::new(configuration, settings, Arc::clone(&clock));
+    states.start_session();
+
+    if let Some(timeout) = settings.ready_timeout() {
+        if !wait_until_ready(port, &mut frame_reader, timeout, clock.as_ref()) {
+            warn!("Firmware not ready after {:?}; Abandoning port...", timeout);
+            return;
+        }
+    }
+
+    loop {
+        if shutdown.requested() {
+            info!("Shutting down");
+            let out_messages = shutdown_messages(settings, configuration, &connection);
+            if let Err(error) = write_messages(port, out_messages, settings) {
+                warn!("Failed to send shutdown messages: {}", error);
+            }
+            if let Err(error) = port.flush() {
+                warn!("Failed to flush port: {}", error);
+            }
+            return;
+        }
+
+        if retry_budget.failures > 0 {
+            states.enter(ConnectionState::Degraded);
+        } else {
+            states.enter(ConnectionState::Connected);
+        }
+        stats::report_if_due(settings.stats_interval());
+
+        if settings.self_test && is_communication_begin {
+            if let Err(error) =
+                run_self_test(port, settings, configuration, &mut connection, source)
+            {
+                if handle_error(error, &mut retry_budget).is_err() {
+                    return;
+                }
+            }
+            // The self-test already sent the configuration.
+            is_communication_begin = false;
+        }
+
+        // Sent right away rather than on the next request. Writes happen
+        // one whole frame at a time on this thread, so the configuration
+        // can't land in the middle of a Data frame.
+        let mut replaced = reloader.poll();
+        let requests = control.poll(configuration);
+        if let Some(profile) = &requests.profile {
+            replaced = reloader.switch_profile(profile).or(replaced);
+        }
+        let resend = replaced.is_some() || requests.resend;
+        if let Some(mut reloaded) = replaced {
+            // Day or night carries over, as the headlights haven't changed.
+            reloaded.set_night(configuration.is_night());
+            *configuration = reloaded;
+            connection = Connection::new(configuration, settings, Arc::clone(&clock));
+        }
+        if resend {
+            let out_messages = handle_message(
+                &InMessage::NeedGaugeConfig {},
+                settings,
+                configuration,
+                &mut connection,
+                source,
+            );
+            if let Err(error) = write_messages(port, out_messages, settings) {
+                if handle_error(error, &mut retry_budget).is_err() {
+                    return;
+                }
+            }
+        }
+
+        let mut out_messages =
+            resend_unacknowledged(settings, configuration, &mut connection, source);
+        out_messages.extend(connection.pages.poll(configuration, clock.now()));
+        if let Err(error) = write_messages(port, out_messages, settings) {
+            if handle_error(error, &mut retry_budget).is_err() {
+                return;
+            }
+        }
+
+        match read_message(port, &mut frame_reader, &mut is_communication_begin) {
+            Ok(Some(message)) => {
+                stats::message_in();
+                last_inbound = clock.now();
+                debug!("InMessage: {}", message);
+                if !resynchronizer.accept(&message) {
+                    continue;
+                }
+
+                let out_messages =
+                    handle_message(&message, settings, configuration, &mut connection, source);
+
+                match write_messages(port, out_messages, settings) {
+                    Ok(()) => retry_budget.reset(),
+                    Err(error) => {
+                        if handle_error(error, &mut retry_budget).is_err() {
+                            // unrecoverable error - stop using port
+                            return;
+                        }
+                    }
+                }
+            }
+            Ok(None) => {
+                if let Some(keepalive) = settings.keepalive() {
+                    if clock.now().duration_since(last_inbound) > keepalive {
+                        warn!(
+                            "No message from firmware for {:?}; Abandoning port...",
+                            keepalive
+                        );
+                        return;
+                    }
+                }
+            }
+            Err(error) => {
+                resynchronizer.failed(&error);
+                if handle_error(error, &mut retry_budget).is_err() {
+                    // unrecoverable error - stop using port
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Runs the DTR sequence, waiting after each step.
+fn activate_port(
+    port: &mut Box<dyn SerialPort>,
+    settings: &Settings,
+) -> Result<(), serialport::Error> {
+    return run_dtr_sequence(
+        settings,
+        |level| port.write_data_terminal_ready(level),
+        std::thread::sleep,
+    );
+}
+
+/// `activate_port` with the DTR line and the pauses passed in.
+fn run_dtr_sequence(
+    settings: &Settings,
+    mut set_dtr: impl FnMut(bool) -> Result<(), serialport::Error>,
+    mut sleep: impl FnMut(Duration),
+) -> Result<(), serialport::Error> {
+    for (level, pause) in settings.dtr_sequence() {
+        set_dtr(level)?;
+        sleep(pause);
+    }
+
+    return Ok(());
+}
+
+fn run_sync(
+    settings: &Settings,
+    configuration: &mut dto::dto::Configuration,
+    reloader: &Reloader,
+    control: &Control,
+    shutdown: &Shutdown,
+    source: &mut dyn DataSource,
+) {
+    let mut reconnect_log = RepeatedLog::new();
+    let mut states = StateLog::new();
+
+    while !shutdown.requested() {
+        match get_port(settings) {
+            Some(mut port) => {
+                states.enter(ConnectionState::Opening);
+
+                match activate_port(&mut port, settings) {
+                    Err(error) => {
+                        states.enter(ConnectionState::Searching);
+                        reconnect_log.log(&format!("Error activating port: {}", error));
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                    Ok(_) => {
+                        reconnect_log.flush();
+                        serve_port(
+                            &mut port,
+                            settings,
+                            configuration,
+                            reloader,
+                            control,
+                            shutdown,
+                            source,
+                            &mut states,
+                            Arc::new(SystemClock),
+                        );
+                        states.enter(ConnectionState::Disconnected);
+
+                        // Best effort, the device may already be gone.
+                        let _ = port.write_data_terminal_ready(false);
+
+                        if shutdown.requested() || states.out_of_reconnects(settings) {
+                            return;
+                        }
+                    }
+                }
+            }
+            None => {
+                states.enter(ConnectionState::Searching);
+                reconnect_log.log("Waiting for port...");
+                stats::report_if_due(settings.stats_interval());
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dto::dto::{GaugeConfig, GaugeData};
+
+    /// Frames the backend sent, until `count` have arrived or the port
+    /// stops answering.
+    fn read_frames<R: Read>(firmware: &mut R, count: usize) -> Vec<serde_json::Value> {
+        let mut buffer = Vec::new();
+        let mut idle_reads = 0;
+        while buffer
+            .iter()
+            .filter(|byte| **byte == MESSAGE_END_BYTE)
+            .count()
+            < count
+            && idle_reads < 50
+        {
+            let mut chunk = [0; 256];
+            match firmware.read(&mut chunk) {
+                Ok(size) if size > 0 => buffer.extend_from_slice(&chunk[..size]),
+                _ => idle_reads += 1,
+            }
+        }
+
+        return buffer
+            .split(|byte| *byte == MESSAGE_END_BYTE)
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| serde_json::from_slice(frame).unwrap())
+            .collect();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_config_and_data_over_a_pseudo_terminal() {
+        let (mut firmware, mut port) = serialport::TTYPort::pair().expect("pty pair");
+        let settings = Settings::default();
+        let mut configuration = default_configuration();
+        let display_count = configuration.displays.len();
+        let reloader = Reloader::new(None, None, &settings);
+        let shutdown = Shutdown::new();
+        let mut source = source::RandomSource::new(1.0, Some(7));
+
+        let frames = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                serve_port(
+                    &mut port,
+                    &settings,
+                    &mut configuration,
+                    &reloader,
+                    &Control::new(false),
+                    &shutdown,
+                    &mut source,
+                    &mut StateLog::new(),
+                    Arc::new(SystemClock),
+                );
+            });
+
+            // The configuration comes unasked, then one reply per request.
+            firmware.write_all(b"\n{\"type\":1}\n").unwrap();
+            firmware.write_all(b"{\"type\":2}\n").unwrap();
+            let frames = read_frames(&mut firmware, 3);
+            shutdown.request();
+
+            frames
+        });
+
+        let types: Vec<_> = frames.iter().map(|frame| frame["type"].clone()).collect();
+        assert_eq!(types, [1, 1, 2]);
+        assert_eq!(
+            frames[0]["message"]["display1"]["gauges"][0]["id"],
+            "coolant"
+        );
+        for display in 1..=display_count {
+            assert!(frames[2]["message"][format!("display{}", display)]["gauges"].is_array());
+        }
+    }
+
+    /// A file in the temp directory, unique to this process and `name`.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gauges-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+
+        return path;
+    }
+
+    fn cli(args: &[&str]) -> cli::Cli {
+        return cli::Cli::try_parse_from(std::iter::once("car_pc").chain(args.iter().copied()))
+            .unwrap();
+    }
+
+    #[test]
+    fn valid_config_file_validates() {
+        let path = temp_file("valid.toml", DEFAULT_CONFIGURATION);
+
+        let code = validate_config(&cli(&[
+            "--validate-config",
+            "--config",
+            path.to_str().unwrap(),
+        ]));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn invalid_config_file_fails_validation() {
+        let invalid = DEFAULT_CONFIGURATION.replace("max = 130.0", "max = -1.0");
+        let path = temp_file("invalid.toml", &invalid);
+
+        let code = validate_config(&cli(&[
+            "--validate-config",
+            "--config",
+            path.to_str().unwrap(),
+        ]));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(code, 0);
+    }
+
+    #[test]
+    fn unreadable_config_file_fails_validation() {
+        let path = temp_file("unreadable.toml", "[[display1.gauges]\n");
+
+        let code = validate_config(&cli(&[
+            "--validate-config",
+            "--config",
+            path.to_str().unwrap(),
+        ]));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(code, 0);
+    }
+
+    /// Stands in for the firmware end of a port. Each read returns the
+    /// next scripted chunk, or once they run out, what `after` returns,
+    /// and moves the clock on by `tick`.
+    struct MockPort {
+        chunks: std::collections::VecDeque<std::io::Result<Vec<u8>>>,
+        after: fn() -> std::io::Result<usize>,
+        clock: Arc<clock::MockClock>,
+        tick: Duration,
+        reads: usize,
+        written: Vec<u8>,
+        /// Bytes the firmware takes before it stops reading and every
+        /// write times out; unlimited if unset.
+        write_room: Option<usize>,
+        flushes: usize,
+    }
+
+    impl MockPort {
+        fn new(chunks: &[&str], after: fn() -> std::io::Result<usize>, tick: Duration) -> MockPort {
+            return MockPort {
+                chunks: chunks
+                    .iter()
+                    .map(|chunk| Ok(chunk.as_bytes().to_vec()))
+                    .collect(),
+                after,
+                clock: Arc::new(clock::MockClock::new(0)),
+                tick,
+                reads: 0,
+                written: Vec::new(),
+                write_room: None,
+                flushes: 0,
+            };
+        }
+
+        /// Scripts a failed read after the chunks so far.
+        fn push_error(&mut self, kind: std::io::ErrorKind) {
+            self.chunks.push_back(Err(kind.into()));
+        }
+
+        fn push_chunk(&mut self, chunk: &str) {
+            self.chunks.push_back(Ok(chunk.as_bytes().to_vec()));
+        }
+
+        /// Types of the messages written so far.
+        fn written_types(&self) -> Vec<u64> {
+            return self
+                .written
+                .split(|byte| *byte == MESSAGE_END_BYTE)
+                .filter(|frame| !frame.is_empty())
+                .map(|frame| {
+                    let message: serde_json::Value = serde_json::from_slice(frame).unwrap();
+                    message["type"].as_u64().unwrap()
+                })
+                .collect();
+        }
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            assert!(self.reads < 1000, "the loop never gave up on the port");
+            self.clock.advance(self.tick);
+
+            let Some(chunk) = self.chunks.pop_front() else {
+                return (self.after)();
+            };
+            let chunk = chunk?;
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            return Ok(chunk.len());
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let accepted = match &mut self.write_room {
+                Some(0) => return Err(std::io::ErrorKind::TimedOut.into()),
+                Some(room) => {
+                    let accepted = buf.len().min(*room);
+                    *room -= accepted;
+                    accepted
+                }
+                None => buf.len(),
+            };
+            self.written.extend_from_slice(&buf[..accepted]);
+            return Ok(accepted);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            return Ok(());
+        }
+    }
+
+    fn timed_out() -> std::io::Result<usize> {
+        return Err(std::io::ErrorKind::TimedOut.into());
+    }
+
+    /// Runs the blocking loop on `port` until it gives up on it.
+    fn serve_mock(port: &mut MockPort, settings: &Settings) -> StateLog {
+        let mut configuration = default_configuration();
+        let mut source = source::RandomSource::new(1.0, Some(7));
+        let mut states = StateLog::new();
+
+        serve_port(
+            port,
+            settings,
+            &mut configuration,
+            &Reloader::new(None, None, settings),
+            &Control::new(false),
+            &Shutdown::new(),
+            &mut source,
+            &mut states,
+            port.clock.clone(),
+        );
+
+        return states;
+    }
+
+    #[test]
+    fn silent_firmware_trips_the_keepalive() {
+        let settings = Settings {
+            keepalive_ms: 5000,
+            ..Settings::default()
+        };
+        let mut port = MockPort::new(&[], timed_out, Duration::from_secs(1));
+
+        serve_mock(&mut port, &settings);
+
+        // Given up on after the first read more than 5 s past the start.
+        assert_eq!(port.reads, 6);
+        assert_eq!(port.written_types(), [1]);
+    }
+
+    #[test]
+    fn requests_keep_the_keepalive_from_tripping() {
+        let settings = Settings {
+            keepalive_ms: 5000,
+            ..Settings::default()
+        };
+        let requests = ["\n{\"type\":2}\n", "{\"type\":2}\n", "{\"type\":2}\n"];
+        let mut port = MockPort::new(&requests, timed_out, Duration::from_secs(4));
+
+        serve_mock(&mut port, &settings);
+
+        // 12 s of requests 4 s apart, then silence until 5 s after the last.
+        assert_eq!(port.reads, 5);
+        assert_eq!(port.written_types(), [1, 2, 2, 2]);
+    }
+
+    fn io_error(kind: std::io::ErrorKind) -> Error {
+        return Error::IO(kind.into());
+    }
+
+    #[test]
+    fn recoverable_error_burst_stays_within_the_budget() {
+        let mut retry_budget = RetryBudget::new(3);
+
+        for _ in 0..3 {
+            assert!(
+                handle_error(io_error(std::io::ErrorKind::TimedOut), &mut retry_budget).is_ok()
+            );
+        }
+        // A successful exchange refills the budget.
+        retry_budget.reset();
+        for _ in 0..3 {
+            assert!(
+                handle_error(io_error(std::io::ErrorKind::TimedOut), &mut retry_budget).is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn errors_past_the_budget_abandon_the_port() {
+        let mut retry_budget = RetryBudget::new(3);
+
+        for _ in 0..3 {
+            assert!(
+                handle_error(io_error(std::io::ErrorKind::TimedOut), &mut retry_budget).is_ok()
+            );
+        }
+        assert!(handle_error(io_error(std::io::ErrorKind::TimedOut), &mut retry_budget).is_err());
+    }
+
+    #[test]
+    fn permanent_failure_abandons_the_port_at_once() {
+        let mut retry_budget = RetryBudget::new(3);
+
+        assert!(handle_error(io_error(std::io::ErrorKind::BrokenPipe), &mut retry_budget).is_err());
+    }
+
+    #[test]
+    fn parse_errors_never_spend_the_budget() {
+        let mut retry_budget = RetryBudget::new(0);
+        let error = parse_message(String::from("{\"type\":")).err().unwrap();
+
+        assert!(handle_error(error, &mut retry_budget).is_ok());
+        assert_eq!(retry_budget.failures, 0);
+    }
+
+    /// What handle_message works on, for calling it directly: the
+    /// built-in layout, a seeded random source and a mock clock.
+    struct Session {
+        settings: Settings,
+        configuration: dto::dto::Configuration,
+        connection: Connection,
+        source: Box<dyn DataSource>,
+        clock: Arc<clock::MockClock>,
+    }
+
+    /// Returns `values` one read after the other, then offline.
+    struct Readings {
+        values: std::collections::VecDeque<f32>,
+    }
+
+    impl Readings {
+        fn new(values: &[f32]) -> Readings {
+            return Readings {
+                values: values.iter().copied().collect(),
+            };
+        }
+    }
+
+    impl DataSource for Readings {
+        fn read(&mut self, _: usize, _: usize, _: &GaugeConfig) -> Result<f32, String> {
+            return Ok(self.values.pop_front().unwrap_or(GaugeData::OFFLINE_VALUE));
+        }
+    }
+
+    impl Session {
+        fn new(settings: Settings) -> Session {
+            return Session::with_clock(settings, clock::MockClock::new(0));
+        }
+
+        fn with_clock(settings: Settings, clock: clock::MockClock) -> Session {
+            let configuration = default_configuration();
+            let clock = Arc::new(clock);
+            let connection = Connection::new(&configuration, &settings, clock.clone());
+
+            return Session {
+                settings,
+                configuration,
+                connection,
+                source: Box::new(source::RandomSource::new(1.0, Some(7))),
+                clock,
+            };
+        }
+
+        /// The same session with the `toml` layout read by `source`.
+        fn with_layout(mut self, toml: &str, source: impl DataSource + 'static) -> Session {
+            self.configuration = toml::from_str(toml).unwrap();
+            self.connection =
+                Connection::new(&self.configuration, &self.settings, self.clock.clone());
+            self.source = Box::new(source);
+            return self;
+        }
+
+        fn handle(&mut self, message: InMessage) -> Vec<OutMessage> {
+            return handle_message(
+                &message,
+                &self.settings,
+                &mut self.configuration,
+                &mut self.connection,
+                self.source.as_mut(),
+            );
+        }
+
+        /// The Data message answering one NeedGaugeData.
+        fn data(&mut self) -> dto::dto::Data {
+            match self.handle(InMessage::NeedGaugeData {}).pop() {
+                Some(OutMessage::Data { message }) => {
+                    return message;
+                }
+                _ => {
+                    panic!("no Data for NeedGaugeData");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn data_timestamps_increase() {
+        // Later than any real clock or other test, so none can have sent
+        // a newer timestamp.
+        let start = 4_200_000_000_000;
+        let mut session = Session::with_clock(Settings::default(), clock::MockClock::new(start));
+
+        let first = session.data().timestamp.unwrap();
+        session.clock.advance(Duration::from_millis(100));
+        let second = session.data().timestamp.unwrap();
+        session.clock.advance(Duration::from_millis(100));
+        let third = session.data().timestamp.unwrap();
+
+        assert!(first >= start);
+        assert_eq!(second, first + 100);
+        assert_eq!(third, second + 100);
+    }
+
+    #[test]
+    fn timestamps_never_go_back() {
+        let clock = clock::MockClock::new(4_100_000_000_000);
+        let first = timestamp_ms(&clock);
+
+        // As if the system clock were stepped back by NTP.
+        let earlier = clock::MockClock::new(4_099_000_000_000);
+
+        assert!(timestamp_ms(&earlier) >= first);
+    }
+
+    fn port_info(port_name: &str) -> serialport::SerialPortInfo {
+        return serialport::SerialPortInfo {
+            port_name: port_name.to_string(),
+            port_type: serialport::SerialPortType::Unknown,
+        };
+    }
+
+    #[test]
+    fn enumeration_error_means_try_again() {
+        let failed = || {
+            Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "enumeration failed",
+            ))
+        };
+
+        assert_eq!(choose_port(&Settings::default(), failed), None);
+    }
+
+    #[test]
+    fn first_port_that_isnt_a_sensor_is_chosen() {
+        let settings = Settings {
+            obd2_port: Some(String::from("/dev/ttyUSB0")),
+            ..Settings::default()
+        };
+        let ports = || Ok(vec![port_info("/dev/ttyUSB0"), port_info("/dev/ttyACM0")]);
+
+        assert_eq!(
+            choose_port(&settings, ports),
+            Some(String::from("/dev/ttyACM0"))
+        );
+    }
+
+    #[test]
+    fn pinned_port_skips_enumeration() {
+        let path = temp_file("pinned-port", "");
+        let settings = Settings {
+            port: Some(path.to_str().unwrap().to_string()),
+            ..Settings::default()
+        };
+        let unused = || -> serialport::Result<Vec<serialport::SerialPortInfo>> {
+            panic!("enumerated despite a pinned port");
+        };
+
+        let chosen = choose_port(&settings, unused);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(chosen, settings.port);
+    }
+
+    #[test]
+    fn dry_run_sends_config_then_data() {
+        let mut configuration = default_configuration();
+        let mut source = source::RandomSource::new(1.0, Some(7));
+
+        let messages = dry_run_messages(&Settings::default(), &mut configuration, &mut source);
+
+        assert!(matches!(
+            messages.as_slice(),
+            [OutMessage::Configuration { .. }, OutMessage::Data { .. }]
+        ));
+    }
+
+    #[test]
+    fn data_carries_the_configured_tween() {
+        let settings = Settings {
+            tween_ms: 250,
+            ..Settings::default()
+        };
+
+        assert_eq!(Session::new(settings).data().tween_ms, Some(250));
+        assert_eq!(Session::new(Settings::default()).data().tween_ms, None);
+    }
+
+    /// Reads one message from `bytes` past the start of communication.
+    fn read_bytes(bytes: &[u8]) -> Result<Option<InMessage>, Error> {
+        let mut is_communication_begin = false;
+        return read_message(
+            &mut std::io::Cursor::new(bytes),
+            &mut FrameReader::new(),
+            &mut is_communication_begin,
+        );
+    }
+
+    #[test]
+    fn bad_json_becomes_json_parsing_with_the_frame() {
+        let error = read_bytes(b"\n{\"type\":\n").err().unwrap();
+
+        assert!(matches!(
+            error,
+            Error::JsonParsing { ref source_string, .. } if source_string == "{\"type\":"
+        ));
+    }
+
+    #[test]
+    fn invalid_utf8_becomes_utf_conversion() {
+        let error = read_bytes(b"\n{\"type\":\xFF}\n").err().unwrap();
+
+        assert!(matches!(error, Error::UtfConversion(_)));
+    }
+
+    #[test]
+    fn port_failure_becomes_io() {
+        fn broken_pipe() -> std::io::Result<usize> {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        let mut port = MockPort::new(&[], broken_pipe, Duration::ZERO);
+        let mut is_communication_begin = false;
+
+        let error = read_message(
+            &mut port,
+            &mut FrameReader::new(),
+            &mut is_communication_begin,
+        )
+        .err()
+        .unwrap();
+
+        assert!(
+            matches!(error, Error::IO(ref error) if error.kind() == std::io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn plain_json_conversion_has_no_source() {
+        let error: Error = serde_json::from_str::<serde_json::Value>("nope")
+            .unwrap_err()
+            .into();
+
+        assert!(matches!(
+            error,
+            Error::JsonParsing { ref source_string, .. } if source_string.is_empty()
+        ));
+        assert!(matches!(
+            error.with_source(String::from("nope")),
+            Error::JsonParsing { ref source_string, .. } if source_string == "nope"
+        ));
+    }
+
+    fn end_of_file() -> std::io::Result<usize> {
+        return Ok(0);
+    }
+
+    #[test]
+    fn endless_end_of_file_abandons_the_port() {
+        let mut port = MockPort::new(&[], end_of_file, Duration::ZERO);
+
+        serve_mock(&mut port, &Settings::default());
+
+        // The unasked configuration, then MAX_EMPTY_READS reads of nothing.
+        assert_eq!(port.reads, 10);
+        assert_eq!(port.written_types(), [1]);
+    }
+
+    #[test]
+    fn data_between_empty_reads_keeps_the_port() {
+        let chunks = ["", "", "", "", "", "", "", "", "\n{\"type\":2}\n"];
+        let mut port = MockPort::new(&chunks, end_of_file, Duration::ZERO);
+
+        serve_mock(&mut port, &Settings::default());
+
+        assert_eq!(port.reads, 19);
+        assert_eq!(port.written_types(), [1, 2]);
+    }
+
+    /// DTR levels and pauses in the order the connect sequence ran them.
+    fn connect_sequence(settings: &Settings) -> Vec<String> {
+        let steps = std::cell::RefCell::new(Vec::new());
+
+        run_dtr_sequence(
+            settings,
+            |level| {
+                steps.borrow_mut().push(format!("dtr {}", level));
+                Ok(())
+            },
+            |pause| steps.borrow_mut().push(format!("sleep {:?}", pause)),
+        )
+        .unwrap();
+
+        return steps.into_inner();
+    }
+
+    #[test]
+    fn startup_delay_follows_dtr() {
+        let settings = Settings {
+            startup_delay_ms: 2000,
+            ..Settings::default()
+        };
+
+        assert_eq!(connect_sequence(&settings), ["dtr true", "sleep 2s"]);
+    }
+
+    #[test]
+    fn dtr_toggle_resets_the_board_first() {
+        let settings = Settings {
+            startup_delay_ms: 1500,
+            dtr_toggle: true,
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            connect_sequence(&settings),
+            ["dtr false", "sleep 100ms", "dtr true", "sleep 1.5s"]
+        );
+    }
+
+    fn other_error() -> std::io::Result<usize> {
+        return Err(std::io::ErrorKind::Other.into());
+    }
+
+    #[test]
+    fn session_walks_through_the_connection_states() {
+        let mut port = MockPort::new(&[], other_error, Duration::ZERO);
+        port.push_error(std::io::ErrorKind::Other);
+        port.push_chunk("\n{\"type\":2}\n");
+
+        let logged = test_log::capture(|| {
+            // As run_sync does around serving a port.
+            let mut states = StateLog::new();
+            states.enter(ConnectionState::Searching);
+            states.enter(ConnectionState::Opening);
+            let settings = Settings::default();
+            let clock = port.clock.clone();
+            serve_port(
+                &mut port,
+                &settings,
+                &mut default_configuration(),
+                &Reloader::new(None, None, &settings),
+                &Control::new(false),
+                &Shutdown::new(),
+                &mut source::RandomSource::new(1.0, Some(7)),
+                &mut states,
+                clock,
+            );
+            states.enter(ConnectionState::Disconnected);
+        });
+
+        // Without the session id, which is random.
+        let transitions: Vec<&str> = logged
+            .iter()
+            .filter(|(level, line)| *level == log::Level::Info && line.starts_with("Connection"))
+            .map(|(_, line)| line.split_once(": ").unwrap().1)
+            .collect();
+        assert_eq!(
+            transitions,
+            [
+                "Searching",
+                "Searching -> Opening",
+                "Opening -> Connected",
+                "Connected -> Degraded",
+                "Degraded -> Connected",
+                "Connected -> Degraded",
+                "Degraded -> Disconnected",
+            ]
+        );
+    }
+
+    #[test]
+    fn data_is_throttled_but_configurations_are_not() {
+        let settings = Settings {
+            max_data_rate: 2.0,
+            ..Settings::default()
+        };
+        let mut session = Session::new(settings);
+
+        let mut data_sent = 0;
+        for _ in 0..10 {
+            let configuration = session.handle(InMessage::NeedGaugeConfig {});
+            assert!(matches!(
+                configuration.as_slice(),
+                [OutMessage::Configuration { .. }]
+            ));
+
+            data_sent += session.handle(InMessage::NeedGaugeData {}).len();
+            session.clock.advance(Duration::from_millis(100));
+        }
+
+        // 0.9 s of requests at 2 per second: at the start and 0.5 s in.
+        assert_eq!(data_sent, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn once_sends_one_configuration_and_one_data() {
+        let (mut firmware, mut port) = serialport::TTYPort::pair().expect("pty pair");
+        let mut configuration = default_configuration();
+        let mut source = source::RandomSource::new(1.0, Some(7));
+
+        let status = send_once(
+            &mut port,
+            &Settings::default(),
+            &mut configuration,
+            &mut source,
+        );
+        // Asks for one frame too many, so waits out the idle reads.
+        firmware.set_timeout(Duration::from_millis(10)).unwrap();
+        let frames = read_frames(&mut firmware, 3);
+
+        assert_eq!(status, 0);
+        let types: Vec<u64> = frames
+            .iter()
+            .map(|frame| frame["type"].as_u64().unwrap())
+            .collect();
+        assert_eq!(types, [1, 2]);
+    }
+
+    /// One 0-100 oil gauge holding changes up to 5.
+    const DEADBAND_LAYOUT: &str = r#"
+        [[display1.gauges]]
+        id = "oil"
+        name = "OIL"
+        units = "%"
+        format = "%.0f"
+        min = 0.0
+        max = 100.0
+        low_value = 10.0
+        high_value = 90.0
+        deadband = 5.0
+    "#;
+
+    /// Value of the first gauge on the first display.
+    fn first_value(data: &dto::dto::Data) -> f32 {
+        return data.displays[0].gauges[0].current_value;
+    }
+
+    #[test]
+    fn second_config_request_resends_and_clears_the_deadband() {
+        let mut session = Session::new(Settings::default())
+            .with_layout(DEADBAND_LAYOUT, Readings::new(&[50.0, 52.0, 52.0]));
+
+        assert!(matches!(
+            session.handle(InMessage::NeedGaugeConfig {}).as_slice(),
+            [OutMessage::Configuration { .. }]
+        ));
+        assert_eq!(first_value(&session.data()), 50.0);
+        assert_eq!(first_value(&session.data()), 50.0);
+
+        // The firmware reset and starts over.
+        assert!(matches!(
+            session.handle(InMessage::NeedGaugeConfig {}).as_slice(),
+            [OutMessage::Configuration { .. }]
+        ));
+        assert_eq!(first_value(&session.data()), 52.0);
+    }
+
+    #[test]
+    fn low_fuel_forces_the_alert() {
+        let layout = r#"
+            [theme]
+            alert_color = "magenta"
+
+            [[display1.gauges]]
+            id = "fuel"
+            name = "FUEL"
+            units = "%"
+            format = "%.0f"
+            min = 0.0
+            max = 100.0
+            low_value = 0.0
+            high_value = 100.0
+            fuel = { table = [[10.0, 100.0], [180.0, 0.0]], low_fuel = 15.0, low_fuel_message = "Fill up soon" }
+        "#;
+        let mut session =
+            Session::new(Settings::default()).with_layout(layout, Readings::new(&[95.0, 163.0]));
+
+        let half = session.data();
+        let mut almost_empty = None;
+        let logged = test_log::capture(|| almost_empty = Some(session.data()));
+        let almost_empty = almost_empty.unwrap();
+
+        assert_eq!(first_value(&half), 50.0);
+        assert!(!half.displays[0].gauges[0].critical);
+        assert_eq!(first_value(&almost_empty), 10.0);
+        assert!(almost_empty.displays[0].gauges[0].critical);
+        assert_eq!(
+            almost_empty.displays[0].gauges[0].color,
+            Some(color::Color::from_rgb565(dto::dto::OLED_COLOR_MAGENTA))
+        );
+        assert!(logged.contains(&(log::Level::Warn, String::from("FUEL: Fill up soon"))));
+    }
+
+    #[test]
+    fn requests_in_one_chunk_are_each_answered_in_order() {
+        let chunks = [
+            "\n{\"type\":2}\n{\"type\":2}\n",
+            "{\"type\":2}\n{\"type\":1}\n{\"type\":2}\n",
+        ];
+        let mut port = MockPort::new(&chunks, end_of_file, Duration::ZERO);
+
+        serve_mock(&mut port, &Settings::default());
+
+        assert_eq!(port.written_types(), [1, 2, 2, 2, 1, 2]);
+    }
+
+    #[test]
+    fn disabled_display_gets_no_data() {
+        let layout = r#"
+            [[display1.gauges]]
+            id = "oil"
+            name = "OIL"
+            units = "%"
+            format = "%.0f"
+            min = 0.0
+            max = 100.0
+            low_value = 10.0
+            high_value = 90.0
+
+            [display2]
+            enabled = false
+
+            [[display2.gauges]]
+            id = "boost"
+            name = "BOOST"
+            units = "%"
+            format = "%.0f"
+            min = 0.0
+            max = 100.0
+            low_value = 10.0
+            high_value = 90.0
+        "#;
+        let mut session =
+            Session::new(Settings::default()).with_layout(layout, Readings::new(&[40.0, 60.0]));
+
+        let data = session.data();
+
+        assert_eq!(data.displays.len(), 2);
+        assert_eq!(first_value(&data), 40.0);
+        assert!(data.displays[1].gauges.is_empty());
+    }
+
+    /// Replies to each of `messages` in turn, from one connection reading
+    /// a seeded random source.
+    fn replies(
+        settings: &Settings,
+        mut configuration: dto::dto::Configuration,
+        messages: &[InMessage],
+    ) -> Vec<Vec<OutMessage>> {
+        let mut connection =
+            Connection::new(&configuration, settings, Arc::new(clock::MockClock::new(0)));
+        let mut source = source::RandomSource::new(1.0, Some(7));
+
+        return messages
+            .iter()
+            .map(|message| {
+                handle_message(
+                    message,
+                    settings,
+                    &mut configuration,
+                    &mut connection,
+                    &mut source,
+                )
+            })
+            .collect();
+    }
+
+    /// Replies to one message with the built-in layout.
+    fn reply(message: InMessage) -> Vec<OutMessage> {
+        return replies(&Settings::default(), default_configuration(), &[message])
+            .pop()
+            .unwrap();
+    }
+
+    #[test]
+    fn config_request_gets_the_built_in_gauges() {
+        let replies = reply(InMessage::NeedGaugeConfig {});
+        let [OutMessage::Configuration { message }] = replies.as_slice() else {
+            panic!("no Configuration");
+        };
+
+        let names: Vec<Vec<&str>> = message
+            .displays
+            .iter()
+            .map(|display| {
+                display
+                    .gauges
+                    .iter()
+                    .map(|gauge| gauge.name.as_str())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(names, [vec!["COOLANT"], vec!["OIL"], vec![]]);
+    }
+
+    #[test]
+    fn data_request_gets_a_value_per_gauge() {
+        let replies = reply(InMessage::NeedGaugeData {});
+        let [OutMessage::Data { message }] = replies.as_slice() else {
+            panic!("no Data");
+        };
+
+        let counts: Vec<usize> = message
+            .displays
+            .iter()
+            .map(|display| display.gauges.len())
+            .collect();
+        assert_eq!(counts, [1, 1, 0]);
+    }
+
+    #[test]
+    fn debug_gets_no_reply() {
+        let debug = InMessage::Debug {
+            message: String::from("\"booted\""),
+            level: log::Level::Info,
+        };
+
+        assert!(reply(debug).is_empty());
+    }
+
+    #[test]
+    fn display_config_request_in_and_out_of_range() {
+        let in_range = reply(InMessage::NeedDisplayConfig { display: 2 });
+        let [OutMessage::DisplayConfiguration { message }] = in_range.as_slice() else {
+            panic!("no DisplayConfiguration");
+        };
+        assert_eq!(message.display, 2);
+        assert_eq!(message.configuration.gauges[0].id, "oil");
+
+        assert!(reply(InMessage::NeedDisplayConfig { display: 0 }).is_empty());
+        assert!(reply(InMessage::NeedDisplayConfig { display: 4 }).is_empty());
+    }
+
+    #[test]
+    fn config_acks_settle_or_resend() {
+        let settings = Settings {
+            config_ack_timeout_ms: 1000,
+            ..Settings::default()
+        };
+        let ok = || InMessage::ConfigAck {
+            ok: true,
+            error: None,
+        };
+        let failed = || InMessage::ConfigAck {
+            ok: false,
+            error: Some(String::from("too many gauges")),
+        };
+
+        let replies = replies(
+            &settings,
+            default_configuration(),
+            &[
+                InMessage::NeedGaugeConfig {},
+                ok(),
+                InMessage::NeedGaugeConfig {},
+                failed(),
+                // Nothing pending once applied.
+                ok(),
+                failed(),
+            ],
+        );
+
+        assert!(replies[1].is_empty());
+        assert!(matches!(
+            replies[3].as_slice(),
+            [OutMessage::Configuration { .. }]
+        ));
+        assert!(replies[4].is_empty());
+        assert!(replies[5].is_empty());
+    }
+
+    #[test]
+    fn headlights_switch_the_theme() {
+        let mut layout = String::from(DEFAULT_CONFIGURATION);
+        layout.push_str("\n[night_theme]\npreset = \"night\"\n");
+        let configuration: dto::dto::Configuration = toml::from_str(&layout).unwrap();
+        let on = || InMessage::Headlights { on: true };
+
+        let replies = replies(&Settings::default(), configuration, &[on(), on()]);
+
+        let [OutMessage::Configuration { message }] = replies[0].as_slice() else {
+            panic!("no Configuration for the night theme");
+        };
+        assert!(message.is_night());
+        // Already on the night theme.
+        assert!(replies[1].is_empty());
+
+        // Without a night theme there is nothing to switch to.
+        assert!(reply(on()).is_empty());
+    }
+
+    #[test]
+    fn echoed_backend_frame_is_an_error() {
+        let echoed = serde_json::to_string(&reply(InMessage::NeedGaugeData {})[0]).unwrap();
+
+        let error = parse_message(echoed).err().unwrap();
+
+        assert!(error
+            .to_string()
+            .contains("received a backend Data message; check for a loopback or swapped TX/RX"));
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_data() {
+        let requests: Vec<InMessage> = (0..5).map(|_| InMessage::NeedGaugeData {}).collect();
+        let run = || {
+            return replies(&Settings::default(), default_configuration(), &requests)
+                .into_iter()
+                .map(|replies| match replies.as_slice() {
+                    [OutMessage::Data { message }] => message.displays.clone(),
+                    _ => panic!("no Data"),
+                })
+                .collect::<Vec<_>>();
+        };
+
+        let first = run();
+
+        assert_eq!(first, run());
+        // The seeded source still moves the needles between requests.
+        assert_ne!(first[0], first[1]);
+    }
+
+    #[test]
+    fn initial_value_is_shown_until_the_first_reading() {
+        let layout = DEADBAND_LAYOUT.replace("deadband = 5.0", "initial_value = 20.0");
+        let offline = GaugeData::OFFLINE_VALUE;
+        let mut session = Session::new(Settings::default())
+            .with_layout(&layout, Readings::new(&[offline, offline, 40.0, offline]));
+
+        assert_eq!(first_value(&session.data()), 20.0);
+        assert_eq!(first_value(&session.data()), 20.0);
+        assert_eq!(first_value(&session.data()), 40.0);
+        // Offline after a real reading is not a cold start.
+        assert_eq!(first_value(&session.data()), offline);
+    }
+
+    #[test]
+    fn without_initial_value_the_gauge_starts_offline() {
+        let mut session =
+            Session::new(Settings::default()).with_layout(DEADBAND_LAYOUT, Readings::new(&[]));
+
+        assert_eq!(first_value(&session.data()), GaugeData::OFFLINE_VALUE);
+    }
+
+    #[test]
+    fn printed_config_reflects_overrides() {
+        let path = temp_file(
+            "print.toml",
+            &format!("[settings]\nbaud_rate = 57600\n\n{}", DEFAULT_CONFIGURATION),
+        );
+        let cli = cli(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--unit-system",
+            "imperial",
+            "--brightness",
+            "40",
+        ]);
+
+        let Ok((settings, configuration)) = load_config(&cli) else {
+            panic!("config did not load");
+        };
+        let resolved = resolved_config(&settings, &configuration);
+        std::fs::remove_file(&path).unwrap();
+
+        // From the file, on top of the defaults.
+        assert_eq!(resolved["settings"]["baud_rate"], 57600);
+        // From the command line, on top of the file.
+        assert_eq!(resolved["settings"]["brightness"], 40);
+        assert_eq!(resolved["settings"]["unit_system"], "imperial");
+        // As the firmware will receive it, converted to imperial.
+        let coolant = &resolved["configuration"]["display1"]["gauges"][0];
+        assert_eq!(coolant["units"], "F");
+    }
+
+    #[test]
+    fn stalled_write_times_out_and_abandons_the_port() {
+        let requests = ["{\"type\":2}\n"; 10];
+        let mut port = MockPort::new(&requests, timed_out, Duration::from_millis(100));
+        port.write_room = Some(10);
+
+        // Only part of the frame fits before the write times out.
+        let error = write_message(
+            &mut port,
+            OutMessage::Brightness {
+                message: dto::dto::Brightness::clamped(40),
+            },
+            &Settings::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(&error, Error::IO(error) if error.kind() == std::io::ErrorKind::TimedOut));
+        assert_eq!(port.written.len(), 10);
+
+        let captured = test_log::capture(|| {
+            serve_mock(&mut port, &Settings::default());
+        });
+
+        // Each stalled write spends the retry budget until the port is
+        // given up on, instead of hanging.
+        assert_eq!(port.written.len(), 10);
+        assert!(port.reads < requests.len());
+        let retries = captured
+            .iter()
+            .filter(|(_, message)| message.contains("timed out") && message.contains("Retrying"))
+            .count();
+        assert_eq!(retries, 3);
+        assert!(captured
+            .iter()
+            .any(|(level, message)| *level == log::Level::Error
+                && message.contains("Abandoning port")));
+    }
+
+    #[test]
+    fn wire_bytes_are_compact_even_with_pretty_logs() {
+        let message = || OutMessage::Configuration {
+            message: default_configuration(),
+        };
+        let compact = serde_json::to_string(&message()).unwrap();
+
+        for pretty_log_json in [false, true] {
+            let settings = Settings {
+                pretty_log_json,
+                ..Settings::default()
+            };
+            let mut written = Vec::new();
+
+            let captured = test_log::capture(|| {
+                assert!(write_message(&mut written, message(), &settings).is_ok());
+            });
+
+            assert_eq!(written, [compact.as_bytes(), &[MESSAGE_END_BYTE]].concat());
+            let logged = captured
+                .iter()
+                .find_map(|(_, line)| line.strip_prefix("OutMessage JSON: "))
+                .unwrap();
+            assert_eq!(logged.contains('\n'), pretty_log_json);
+        }
+    }
+
+    /// A gauge's id, name, units, format and min, max, low and high values.
+    type GaugeSummary<'a> = (&'a str, &'a str, &'a str, &'a str, [f32; 4]);
+
+    #[test]
+    fn embedded_default_is_the_documented_layout() {
+        let configuration = default_configuration();
+
+        // Coolant on the first display, oil pressure on the second, the
+        // third off, as the built-in layout has always been.
+        let layout: Vec<(bool, Vec<GaugeSummary>)> = configuration
+            .displays
+            .iter()
+            .map(|display| {
+                let gauges = display
+                    .gauges
+                    .iter()
+                    .map(|gauge| {
+                        (
+                            gauge.id.as_str(),
+                            gauge.name.as_str(),
+                            gauge.units.as_str(),
+                            gauge.format.as_str(),
+                            [gauge.min, gauge.max, gauge.low_value, gauge.high_value],
+                        )
+                    })
+                    .collect();
+                (display.enabled, gauges)
+            })
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (
+                    true,
+                    vec![("coolant", "COOLANT", "C", "%.0f", [0.0, 130.0, 60.0, 100.0])]
+                ),
+                (
+                    true,
+                    vec![("oil", "OIL", "bar", "%.2f", [0.0, 10.0, 1.0, 8.0])]
+                ),
+                (false, vec![]),
+            ]
+        );
+        let classic: dto::dto::GaugeTheme = toml::from_str("preset = \"classic\"").unwrap();
+        assert_eq!(
+            serde_json::to_value(&configuration.theme).unwrap(),
+            serde_json::to_value(&classic).unwrap()
+        );
+        assert!(configuration.night_theme.is_none());
+        assert!(configuration
+            .validate(Settings::default().max_label_length)
+            .is_ok());
+    }
+
+    /// Connection cycles run_sync goes through before it gives up, each
+    /// with firmware that drops the link at once; at most 10.
+    fn cycles_until_giving_up(settings: &Settings) -> usize {
+        let mut configuration = default_configuration();
+        let mut source = source::RandomSource::new(1.0, Some(7));
+        let mut states = StateLog::new();
+
+        for cycle in 1..=10 {
+            let mut port = MockPort::new(&[], broken_pipe, Duration::from_millis(100));
+            let clock = port.clock.clone();
+            states.enter(ConnectionState::Opening);
+            serve_port(
+                &mut port,
+                settings,
+                &mut configuration,
+                &Reloader::new(None, None, settings),
+                &Control::new(false),
+                &Shutdown::new(),
+                &mut source,
+                &mut states,
+                clock,
+            );
+            states.enter(ConnectionState::Disconnected);
+
+            if states.out_of_reconnects(settings) {
+                return cycle;
+            }
+        }
+
+        return 10;
+    }
+
+    fn broken_pipe() -> std::io::Result<usize> {
+        return Err(std::io::ErrorKind::BrokenPipe.into());
+    }
+
+    #[test]
+    fn max_reconnects_gives_up_after_that_many_cycles() {
+        let mut settings = Settings::default();
+        settings.apply_cli(&cli(&["--max-reconnects", "2"]));
+        assert_eq!(settings.max_reconnects, Some(2));
+
+        let mut cycles = 0;
+        let captured = test_log::capture(|| cycles = cycles_until_giving_up(&settings));
+
+        assert_eq!(cycles, 2);
+        assert!(captured.contains(&(
+            log::Level::Error,
+            String::from("Lost the connection 2 times; Giving up")
+        )));
+    }
+
+    #[test]
+    fn reconnects_are_unlimited_by_default() {
+        assert_eq!(cycles_until_giving_up(&Settings::default()), 10);
+    }
+
+    /// Levels the firmware log line in `json` is logged at.
+    fn firmware_log_levels(json: &str) -> Vec<log::Level> {
+        let Ok(message) = parse_message(String::from(json)) else {
+            panic!("{} did not parse", json);
+        };
+
+        return test_log::capture(|| {
+            reply(message);
+        })
+        .into_iter()
+        .filter(|(_, line)| line.starts_with("Debug: "))
+        .map(|(level, _)| level)
+        .collect();
+    }
+
+    #[test]
+    fn firmware_log_lines_keep_their_level() {
+        assert_eq!(
+            firmware_log_levels(r#"{"type":3,"message":"low voltage","level":"warn"}"#),
+            [log::Level::Warn]
+        );
+        assert_eq!(
+            firmware_log_levels(r#"{"type":3,"message":"panel fault","level":"ERROR"}"#),
+            [log::Level::Error]
+        );
+        // Older firmware sends no level, or one this backend doesn't know.
+        assert_eq!(
+            firmware_log_levels(r#"{"type":3,"message":"booted"}"#),
+            [log::Level::Debug]
+        );
+        assert_eq!(
+            firmware_log_levels(r#"{"type":3,"message":"booted","level":"loud"}"#),
+            [log::Level::Debug]
+        );
+    }
+
+    #[test]
+    fn connecting_mid_frame_recovers_within_one_message() {
+        let chunks = ["pe\":2}\n{\"type\":2}\n", "{\"type\":2}\n"];
+        let mut port = MockPort::new(&chunks, broken_pipe, Duration::from_millis(100));
+
+        serve_mock(&mut port, &Settings::default());
+
+        // The tail of the frame sent before connecting is dropped silently
+        // and the next request is answered.
+        assert_eq!(port.written_types(), [1, 2, 2]);
+    }
+
+    #[test]
+    fn handshake_resync_ignores_requests_until_a_config_request() {
+        let settings = Settings {
+            resync: transport::Resync::Handshake,
+            ..Settings::default()
+        };
+        let chunks = ["\n{\"type\":\n{\"type\":2}\n{\"type\":1}\n{\"type\":2}\n"];
+        let mut port = MockPort::new(&chunks, broken_pipe, Duration::from_millis(100));
+
+        serve_mock(&mut port, &settings);
+
+        assert_eq!(port.written_types(), [1, 1, 2]);
+    }
+
+    #[test]
+    fn echoed_data_frame_is_diagnosed_as_a_loopback() {
+        let echoed = r#"{"type":2,"message":{"display1":{"gauges":[{"current_value":1.0}]}}}"#;
+        // Reads take at most 64 bytes.
+        let (head, tail) = echoed.split_at(40);
+        let mut port = MockPort::new(
+            &["\n", head, tail, "\n{\"type\":99}\n"],
+            broken_pipe,
+            Duration::from_millis(100),
+        );
+
+        let captured = test_log::capture(|| {
+            serve_mock(&mut port, &Settings::default());
+        });
+
+        let warnings: Vec<&str> = captured
+            .iter()
+            .filter(|(level, _)| *level == log::Level::Warn)
+            .map(|(_, message)| message.as_str())
+            .filter(|message| message.starts_with("Transient error"))
+            .collect();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0]
+            .contains("received a backend Data message; check for a loopback or swapped TX/RX"));
+        // Genuinely malformed input reads differently.
+        assert!(!warnings[1].contains("loopback"));
+        assert!(warnings[1].contains("unsupported type"));
+        // Neither is answered.
+        assert_eq!(port.written_types(), [1]);
+    }
+
+    fn ready_probe() -> Settings {
+        return Settings {
+            ready_timeout_ms: 2000,
+            ..Settings::default()
+        };
+    }
+
+    #[test]
+    fn config_waits_for_the_firmware_to_be_ready() {
+        let mut port = MockPort::new(&[], broken_pipe, Duration::from_millis(100));
+        for _ in 0..5 {
+            port.push_error(std::io::ErrorKind::TimedOut);
+        }
+        // Boot noise, then the first frame that decodes.
+        port.push_chunk("\n{\"type\":\n{\"type\":2}\n");
+        port.push_chunk("{\"type\":2}\n");
+
+        serve_mock(&mut port, &ready_probe());
+
+        // The ready frame itself isn't answered, the next request is.
+        assert_eq!(port.written_types(), [1, 2]);
+    }
+
+    #[test]
+    fn firmware_never_ready_abandons_the_port() {
+        let mut port = MockPort::new(&[], timed_out, Duration::from_millis(100));
+
+        let captured = test_log::capture(|| {
+            serve_mock(&mut port, &ready_probe());
+        });
+
+        // 2 s of 100 ms reads, with nothing sent.
+        assert_eq!(port.reads, 20);
+        assert!(port.written.is_empty());
+        assert!(captured.contains(&(
+            log::Level::Warn,
+            String::from("Firmware not ready after 2s; Abandoning port...")
+        )));
+    }
+
+    /// Reads 1, 2, 3, ... one per gauge read.
+    struct Counter(f32);
+
+    impl DataSource for Counter {
+        fn read(&mut self, _: usize, _: usize, _: &GaugeConfig) -> Result<f32, String> {
+            self.0 += 1.0;
+            return Ok(self.0);
+        }
+    }
+
+    #[test]
+    fn priority_display_stays_fresh_under_throttling() {
+        let layout = r#"
+            [display1]
+            priority = true
+            [[display1.gauges]]
+            id = "rpm"
+            name = "RPM"
+            units = "rpm"
+            format = "%.0f"
+            min = 0.0
+            max = 8000.0
+            low_value = 800.0
+            high_value = 7000.0
+
+            [[display2.gauges]]
+            id = "oil"
+            name = "OIL"
+            units = "%"
+            format = "%.0f"
+            min = 0.0
+            max = 8000.0
+            low_value = 10.0
+            high_value = 7000.0
+        "#;
+        let settings = Settings {
+            max_data_rate: 2.0,
+            ..Settings::default()
+        };
+        let mut session = Session::new(settings).with_layout(layout, Counter(0.0));
+
+        let mut values = Vec::new();
+        for _ in 0..10 {
+            let data = session.data();
+            values.push([
+                data.displays[0].gauges[0].current_value,
+                data.displays[1].gauges[0].current_value,
+            ]);
+            session.clock.advance(Duration::from_millis(100));
+        }
+
+        let updates = |display: usize| {
+            return values
+                .windows(2)
+                .filter(|pair| pair[0][display] != pair[1][display])
+                .count();
+        };
+        // Every request refreshes the tach; the oil gauge only when
+        // max_data_rate allows, repeating its last value in between.
+        assert_eq!(updates(0), 9);
+        assert_eq!(updates(1), 1);
+    }
+
+    #[test]
+    fn display_config_request_gets_only_that_display() {
+        let Ok(request) = parse_message(String::from(r#"{"type":4,"display":1}"#)) else {
+            panic!("display request did not parse");
+        };
+
+        let replies = reply(request);
+
+        let [reply] = replies.as_slice() else {
+            panic!("expected a single reply");
+        };
+        let json = serde_json::to_value(reply).unwrap();
+        assert_eq!(json["type"], 5);
+        assert_eq!(json["message"]["display"], 1);
+        let gauges = json["message"]["configuration"]["gauges"]
+            .as_array()
+            .unwrap();
+        let ids: Vec<&str> = gauges
+            .iter()
+            .map(|gauge| gauge["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["coolant"]);
+    }
+
+    #[test]
+    fn display_config_request_out_of_range_is_refused() {
+        let Ok(request) = parse_message(String::from(r#"{"type":4,"display":9}"#)) else {
+            panic!("display request did not parse");
+        };
+
+        let mut replies = None;
+        let captured = test_log::capture(|| replies = Some(reply(request)));
+
+        assert!(replies.unwrap().is_empty());
+        assert!(captured.contains(&(
+            log::Level::Warn,
+            String::from("Firmware asked for display 9, but there are displays 1-3")
+        )));
+        // A request without a display is malformed.
+        assert!(parse_message(String::from(r#"{"type":4}"#))
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("missing field `display`"));
+    }
+
+    /// Serves `port` with a shutdown already requested.
+    fn shut_down(port: &mut MockPort, settings: &Settings) {
+        let shutdown = Shutdown::new();
+        shutdown.request();
+        let clock = port.clock.clone();
+
+        serve_port(
+            port,
+            settings,
+            &mut default_configuration(),
+            &Reloader::new(None, None, settings),
+            &Control::new(false),
+            &shutdown,
+            &mut source::RandomSource::new(1.0, Some(7)),
+            &mut StateLog::new(),
+            clock,
+        );
+    }
+
+    #[test]
+    fn shutdown_sends_offline_data_and_flushes() {
+        let settings = Settings {
+            offline_on_exit: true,
+            ..Settings::default()
+        };
+        let mut port = MockPort::new(&[], timed_out, Duration::from_millis(100));
+
+        shut_down(&mut port, &settings);
+
+        assert_eq!(port.written_types(), [2]);
+        let frame = &port.written[..port.written.len() - 1];
+        let data: serde_json::Value = serde_json::from_slice(frame).unwrap();
+        let current_value = if cfg!(feature = "camel-case") {
+            "currentValue"
+        } else {
+            "current_value"
+        };
+        for display in ["display1", "display2"] {
+            assert_eq!(
+                data["message"][display]["gauges"][0][current_value],
+                GaugeData::OFFLINE_VALUE
+            );
+        }
+        assert_eq!(port.flushes, 1);
+        assert_eq!(port.reads, 0);
+    }
+
+    #[test]
+    fn shutdown_flushes_without_offline_on_exit() {
+        let mut port = MockPort::new(&[], timed_out, Duration::from_millis(100));
+
+        shut_down(&mut port, &Settings::default());
+
+        assert!(port.written.is_empty());
+        assert_eq!(port.flushes, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn two_ports_are_served_at_once_from_one_source() {
+        let (mut front, mut front_port) = serialport::TTYPort::pair().expect("pty pair");
+        let (mut rear, mut rear_port) = serialport::TTYPort::pair().expect("pty pair");
+        let settings = Settings::default();
+        let shutdown = Shutdown::new();
+        let mut random = source::RandomSource::new(0.0, Some(7));
+        let source: Mutex<&mut dyn DataSource> = Mutex::new(&mut random);
+
+        let (front_frames, rear_frames) = std::thread::scope(|scope| {
+            for port in [&mut front_port, &mut rear_port] {
+                let (settings, shutdown, source) = (&settings, &shutdown, &source);
+                scope.spawn(move || {
+                    // Each worker with its own configuration and session.
+                    serve_port(
+                        port,
+                        settings,
+                        &mut default_configuration(),
+                        &Reloader::new(None, None, settings),
+                        &Control::new(false),
+                        shutdown,
+                        &mut source::LockedSource::new(source),
+                        &mut StateLog::new(),
+                        Arc::new(SystemClock),
+                    );
+                });
+            }
+
+            // Each firmware asks for something else.
+            front.write_all(b"\n{\"type\":2}\n").unwrap();
+            rear.write_all(b"\n{\"type\":4,\"display\":2}\n").unwrap();
+            let frames = (read_frames(&mut front, 2), read_frames(&mut rear, 2));
+            shutdown.request();
+
+            frames
+        });
+
+        let types = |frames: &[serde_json::Value]| {
+            return frames
+                .iter()
+                .map(|frame| frame["type"].as_u64().unwrap())
+                .collect::<Vec<_>>();
+        };
+        assert_eq!(types(&front_frames), [1, 2]);
+        assert_eq!(types(&rear_frames), [1, 5]);
+        assert_eq!(rear_frames[1]["message"]["display"], 2);
+    }
+
+    fn parse_error(json: &str) -> String {
+        return parse_message(String::from(json)).err().unwrap().to_string();
+    }
+
+    #[test]
+    fn parse_error_points_at_the_offending_byte() {
+        assert_eq!(
+            parse_error(r#"{"type":2,"x":tru}"#),
+            "expected ident at line 1 column 18 (byte 17, near \"{\"type\":2,\"x\":tru^}\")"
+        );
+    }
+
+    #[test]
+    fn parse_error_in_a_long_frame_shows_an_excerpt() {
+        let padding = "a".repeat(100);
+        let json = format!("{{\"type\":3,\"message\":\"{}\",\n\"level\":]}}", padding);
+
+        let error = parse_error(&json);
+
+        let offset = json.find(']').unwrap();
+        assert!(
+            error.ends_with(&format!(
+                "(byte {}, near \"...{}^{}\")",
+                offset,
+                &json[offset - 20..offset],
+                &json[offset..]
+            )),
+            "{}",
+            error
+        );
+        assert!(!error.contains(&padding));
+
+        // The whole frame is still logged, at debug level.
+        let Err(error) = parse_message(json.clone()) else {
+            panic!("{} parsed", json);
+        };
+        let captured = test_log::capture(|| {
+            assert!(handle_error(error, &mut RetryBudget::new(3)).is_ok());
+        });
+        assert!(captured.contains(&(
+            log::Level::Debug,
+            format!("Frame that failed to parse: {}", json)
+        )));
+    }
+
+    #[test]
+    fn excerpt_never_splits_a_character() {
+        let source = "°°°°°°°°°°°°°°°°°°°°°°°°°°°°°°";
+
+        for offset in 0..source.len() {
+            // Slicing off a char boundary would panic.
+            let excerpt = excerpt(source, offset);
+            assert!(excerpt.contains('^'));
+        }
+    }
+
+    #[test]
+    fn overdue_ack_resends_until_the_retries_run_out() {
+        let settings = Settings {
+            config_ack_timeout_ms: 1000,
+            config_retries: 2,
+            ..Settings::default()
+        };
+        let mut configuration = default_configuration();
+        let clock = Arc::new(clock::MockClock::new(0));
+        let mut connection = Connection::new(&configuration, &settings, clock.clone());
+        let mut source = source::RandomSource::new(1.0, Some(7));
+        let sent = handle_message(
+            &InMessage::NeedGaugeConfig {},
+            &settings,
+            &mut configuration,
+            &mut connection,
+            &mut source,
+        );
+        assert_eq!(sent.len(), 1);
+
+        let mut resends = vec![];
+        let logs = test_log::capture(|| {
+            for _ in 0..4 {
+                clock.advance(Duration::from_millis(1000));
+                let on_time = resend_unacknowledged(
+                    &settings,
+                    &mut configuration,
+                    &mut connection,
+                    &mut source,
+                );
+                clock.advance(Duration::from_millis(1));
+                let late = resend_unacknowledged(
+                    &settings,
+                    &mut configuration,
+                    &mut connection,
+                    &mut source,
+                );
+                resends.push((on_time.len(), late.len()));
+            }
+        });
+
+        // Two retries after the first send, then no more.
+        assert_eq!(resends, [(0, 1), (0, 1), (0, 0), (0, 0)]);
+        assert!(logs
+            .iter()
+            .any(|(_, line)| line == "Configuration not applied after 3 attempts; Giving up"));
+    }
+
+    #[test]
+    fn ack_after_a_resend_stops_the_retries() {
+        let settings = Settings {
+            config_ack_timeout_ms: 1000,
+            ..Settings::default()
+        };
+        let mut configuration = default_configuration();
+        let clock = Arc::new(clock::MockClock::new(0));
+        let mut connection = Connection::new(&configuration, &settings, clock.clone());
+        let mut source = source::RandomSource::new(1.0, Some(7));
+        let ack = InMessage::ConfigAck {
+            ok: true,
+            error: None,
+        };
+
+        handle_message(
+            &InMessage::NeedGaugeConfig {},
+            &settings,
+            &mut configuration,
+            &mut connection,
+            &mut source,
+        );
+        clock.advance(Duration::from_millis(1001));
+        let resent =
+            resend_unacknowledged(&settings, &mut configuration, &mut connection, &mut source);
+        assert_eq!(resent.len(), 1);
+        handle_message(
+            &ack,
+            &settings,
+            &mut configuration,
+            &mut connection,
+            &mut source,
+        );
+
+        clock.advance(Duration::from_millis(5000));
+        assert!(
+            resend_unacknowledged(&settings, &mut configuration, &mut connection, &mut source)
+                .is_empty()
+        );
     }
 }
@@ -0,0 +1,162 @@
+//! Timer that rotates displays with several gauge pages, telling the
+//! firmware which page to show and the data path which gauges to sample.
+
+use std::time::{Duration, Instant};
+
+use crate::dto::dto::{Configuration, OutMessage, ShowPage};
+
+pub struct PageRotator {
+    active: Vec<usize>,
+    switched_at: Instant,
+}
+
+impl PageRotator {
+    pub fn new(configuration: &Configuration, now: Instant) -> PageRotator {
+        return PageRotator {
+            active: vec![0; configuration.displays.len()],
+            switched_at: now,
+        };
+    }
+
+    /// Active page of each display, in display order.
+    pub fn active(&self) -> &[usize] {
+        return &self.active;
+    }
+
+    /// Back to the first page everywhere, as the firmware does whenever it
+    /// receives a configuration.
+    pub fn reset(&mut self, now: Instant) {
+        self.active.fill(0);
+        self.switched_at = now;
+    }
+
+    /// Switches the firmware back to the active pages after it was sent a
+    /// configuration mid-rotation.
+    pub fn restore(&self) -> Vec<OutMessage> {
+        return self
+            .active
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| **page != 0)
+            .map(|(index, page)| OutMessage::ShowPage {
+                message: ShowPage {
+                    display: index + 1,
+                    page: *page,
+                },
+            })
+            .collect();
+    }
+
+    /// Advances every display whose interval has elapsed, returning the
+    /// messages that switch the firmware along with it.
+    pub fn poll(&mut self, configuration: &Configuration, now: Instant) -> Vec<OutMessage> {
+        let elapsed = now.duration_since(self.switched_at);
+        let mut result = Vec::new();
+
+        for (index, display) in configuration.displays.iter().enumerate() {
+            let Some(interval_ms) = display.page_interval_ms else {
+                continue;
+            };
+
+            if display.pages.is_empty() || interval_ms == 0 || !display.enabled {
+                continue;
+            }
+
+            let interval = Duration::from_millis(interval_ms);
+            let page = (elapsed.as_millis() / interval.as_millis()) as usize % display.page_count();
+
+            if page != self.active[index] {
+                self.active[index] = page;
+                result.push(OutMessage::ShowPage {
+                    message: ShowPage {
+                        display: index + 1,
+                        page,
+                    },
+                });
+            }
+        }
+
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAUGE: &str = r#"
+        id = "g"
+        name = "G"
+        units = "%"
+        format = "%.0f"
+        min = 0.0
+        max = 100.0
+        low_value = 10.0
+        high_value = 90.0
+    "#;
+
+    /// Display 1 rotates through three pages a second apart; display 2
+    /// has a single page.
+    fn configuration() -> Configuration {
+        let gauge = |id: &str| GAUGE.replace("\"g\"", &format!("\"{}\"", id));
+
+        return toml::from_str(&format!(
+            "[display1]\npage_interval_ms = 1000\n[[display1.gauges]]\n{}\n[[display1.pages]]\n[[display1.pages.gauges]]\n{}\n[[display1.pages]]\n[[display1.pages.gauges]]\n{}\n[[display2.gauges]]\n{}",
+            gauge("a"),
+            gauge("b"),
+            gauge("c"),
+            gauge("d")
+        ))
+        .unwrap();
+    }
+
+    fn shown(messages: Vec<OutMessage>) -> Vec<(usize, usize)> {
+        return messages
+            .into_iter()
+            .map(|message| match message {
+                OutMessage::ShowPage { message } => (message.display, message.page),
+                other => panic!("expected ShowPage, got {}", other),
+            })
+            .collect();
+    }
+
+    #[test]
+    fn pages_switch_in_order_and_wrap_around() {
+        let configuration = configuration();
+        let start = Instant::now();
+        let mut pages = PageRotator::new(&configuration, start);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let sequence: Vec<Vec<(usize, usize)>> = [0, 999, 1000, 1500, 2000, 3000]
+            .into_iter()
+            .map(|ms| shown(pages.poll(&configuration, at(ms))))
+            .collect();
+
+        assert_eq!(
+            sequence,
+            [
+                vec![],
+                vec![],
+                vec![(1, 1)],
+                vec![],
+                vec![(1, 2)],
+                vec![(1, 0)]
+            ]
+        );
+    }
+
+    #[test]
+    fn restore_reshows_the_active_pages() {
+        let configuration = configuration();
+        let start = Instant::now();
+        let mut pages = PageRotator::new(&configuration, start);
+
+        pages.poll(&configuration, start + Duration::from_millis(2000));
+        assert_eq!(pages.active(), [2, 0]);
+        assert_eq!(shown(pages.restore()), [(1, 2)]);
+
+        pages.reset(start + Duration::from_millis(2500));
+        assert_eq!(pages.active(), [0, 0]);
+        assert_eq!(shown(pages.restore()), []);
+    }
+}
@@ -0,0 +1,92 @@
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// One-line human readable description of a serial port.
+pub fn describe_port(port_info: &SerialPortInfo) -> String {
+    match &port_info.port_type {
+        SerialPortType::UsbPort(usb) => {
+            let mut description = format!(
+                "{} USB VID:PID {:04x}:{:04x}",
+                port_info.port_name, usb.vid, usb.pid
+            );
+            if let Some(serial_number) = &usb.serial_number {
+                description.push_str(&format!(" serial {}", serial_number));
+            }
+            if let Some(manufacturer) = &usb.manufacturer {
+                description.push_str(&format!(" manufacturer \"{}\"", manufacturer));
+            }
+            if let Some(product) = &usb.product {
+                description.push_str(&format!(" product \"{}\"", product));
+            }
+            return description;
+        }
+        SerialPortType::PciPort => {
+            return format!("{} PCI", port_info.port_name);
+        }
+        SerialPortType::BluetoothPort => {
+            return format!("{} Bluetooth", port_info.port_name);
+        }
+        SerialPortType::Unknown => {
+            return format!("{} unknown type", port_info.port_name);
+        }
+    }
+}
+
+/// Prints every available port, returning the process exit code.
+pub fn list_ports() -> i32 {
+    match serialport::available_ports() {
+        Ok(ports) => {
+            if ports.is_empty() {
+                println!("No serial ports found");
+            }
+            for port_info in &ports {
+                println!("{}", describe_port(port_info));
+            }
+            return 0;
+        }
+        Err(error) => {
+            println!("Failed to enumerate serial ports: {}", error);
+            return 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::UsbPortInfo;
+
+    use super::*;
+
+    fn port(port_name: &str, port_type: SerialPortType) -> SerialPortInfo {
+        return SerialPortInfo {
+            port_name: port_name.to_string(),
+            port_type,
+        };
+    }
+
+    #[test]
+    fn usb_ports_show_vid_and_pid() {
+        let ports = [
+            port(
+                "/dev/ttyACM0",
+                SerialPortType::UsbPort(UsbPortInfo {
+                    vid: 0x2341,
+                    pid: 0x0043,
+                    serial_number: Some(String::from("85736323838351F0F1A1")),
+                    manufacturer: Some(String::from("Arduino (www.arduino.cc)")),
+                    product: None,
+                }),
+            ),
+            port("/dev/ttyS0", SerialPortType::PciPort),
+        ];
+
+        let lines: Vec<String> = ports.iter().map(describe_port).collect();
+
+        assert_eq!(
+            lines,
+            [
+                "/dev/ttyACM0 USB VID:PID 2341:0043 serial 85736323838351F0F1A1 manufacturer \"Arduino (www.arduino.cc)\"",
+                "/dev/ttyS0 PCI",
+            ]
+        );
+    }
+}
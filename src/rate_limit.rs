@@ -0,0 +1,106 @@
+//! Token bucket capping how often data is sent, so firmware on slow
+//! displays isn't flooded faster than it can redraw.
+
+use std::time::Instant;
+
+pub struct TokenBucket {
+    /// Tokens added per second; `None` means unlimited.
+    rate: Option<f64>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Sends allowed back to back after a quiet spell.
+const BURST: f64 = 1.0;
+
+impl TokenBucket {
+    pub fn new(per_second: f64, now: Instant) -> TokenBucket {
+        let rate = if per_second > 0.0 {
+            Some(per_second)
+        } else {
+            None
+        };
+
+        return TokenBucket {
+            rate,
+            tokens: BURST,
+            refilled_at: now,
+        };
+    }
+
+    /// Takes a token if one is available.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(BURST);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Which of `count` attempts `interval` apart get a token.
+    fn allowed(
+        bucket: &mut TokenBucket,
+        start: Instant,
+        count: u32,
+        interval: Duration,
+    ) -> Vec<bool> {
+        return (0..count)
+            .map(|attempt| bucket.try_take(start + interval * attempt))
+            .collect();
+    }
+
+    #[test]
+    fn bucket_allows_its_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4.0, start);
+
+        // Requests at 10 Hz against a limit of 4 per second.
+        let allowed = allowed(&mut bucket, start, 10, Duration::from_millis(100));
+
+        assert_eq!(
+            allowed,
+            [true, false, false, true, false, false, true, false, false, true]
+        );
+    }
+
+    #[test]
+    fn quiet_spell_allows_only_a_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4.0, start);
+
+        let allowed = allowed(
+            &mut bucket,
+            start + Duration::from_secs(10),
+            3,
+            Duration::ZERO,
+        );
+
+        assert_eq!(allowed, [true, false, false]);
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(0.0, start);
+
+        assert!(allowed(&mut bucket, start, 100, Duration::ZERO)
+            .iter()
+            .all(|allowed| *allowed));
+    }
+}
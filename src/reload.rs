@@ -0,0 +1,261 @@
+//! Re-reads the config file on SIGHUP, when it changes on disk or when
+//! another profile is picked, so gauges can be changed without restarting
+//! the process.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{error, info, warn};
+
+use crate::config::{self, ConfigFormat};
+use crate::dto::dto::Configuration;
+use crate::settings::Settings;
+use crate::units::UnitSystem;
+use crate::Error;
+
+/// How often the file's modification time is checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Watch {
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+pub struct Reloader {
+    path: Option<PathBuf>,
+    format: Option<ConfigFormat>,
+    requested: Arc<AtomicBool>,
+    /// Set when changes to the file should trigger a reload.
+    watch: Option<Mutex<Watch>>,
+    max_label_length: usize,
+    unit_system: UnitSystem,
+    /// Profile to load; the top-level layout when unset.
+    profile: Mutex<Option<String>>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    return std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+}
+
+impl Reloader {
+    pub fn new(
+        path: Option<PathBuf>,
+        format: Option<ConfigFormat>,
+        settings: &Settings,
+    ) -> Reloader {
+        let requested = Arc::new(AtomicBool::new(false));
+
+        #[cfg(unix)]
+        if let Err(error) =
+            signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&requested))
+        {
+            warn!("Failed to install SIGHUP handler: {}", error);
+        }
+
+        let watch = match &path {
+            Some(path) if settings.watch_config => Some(Mutex::new(Watch {
+                modified: modified(path),
+                checked_at: Instant::now(),
+            })),
+            _ => None,
+        };
+
+        return Reloader {
+            path,
+            format,
+            requested,
+            watch,
+            max_label_length: settings.max_label_length,
+            unit_system: settings.unit_system,
+            profile: Mutex::new(settings.profile.clone()),
+        };
+    }
+
+    /// Whether the file was modified since it was last loaded, checked at
+    /// most every `WATCH_INTERVAL`.
+    fn file_changed(&self) -> bool {
+        let (Some(path), Some(watch)) = (&self.path, &self.watch) else {
+            return false;
+        };
+
+        let mut watch = watch
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if watch.checked_at.elapsed() < WATCH_INTERVAL {
+            return false;
+        }
+        watch.checked_at = Instant::now();
+
+        let modified = modified(path);
+        if modified == watch.modified {
+            return false;
+        }
+
+        // Recorded even if the new contents fail to load, so a broken file
+        // is reported once rather than on every check.
+        watch.modified = modified;
+        info!("{} changed", path.display());
+        return true;
+    }
+
+    /// The freshly loaded configuration if a reload was requested since the
+    /// last call, in the active profile. Failures are logged and keep the
+    /// current configuration.
+    pub fn poll(&self) -> Option<Configuration> {
+        let signalled = self.requested.swap(false, Ordering::Relaxed);
+        if !signalled && !self.file_changed() {
+            return None;
+        }
+
+        let profile = self.active_profile();
+        let configuration = self.load(profile.as_deref())?;
+
+        // Settings such as the port or baud rate only apply on restart.
+        info!("Configuration reloaded");
+        return Some(configuration);
+    }
+
+    /// The layout of profile `name`, read afresh from the config file. It
+    /// stays active across reloads. Failures are logged and keep the
+    /// current profile.
+    pub fn switch_profile(&self, name: &str) -> Option<Configuration> {
+        let configuration = self.load(Some(name))?;
+
+        *self
+            .profile
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(name.to_string());
+        info!("Switched to profile {}", name);
+        return Some(configuration);
+    }
+
+    fn active_profile(&self) -> Option<String> {
+        return self
+            .profile
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+    }
+
+    fn load(&self, profile: Option<&str>) -> Option<Configuration> {
+        let Some(path) = &self.path else {
+            warn!("No config file was given to load from");
+            return None;
+        };
+
+        let layout = config::load(path, self.format).and_then(|config_file| {
+            config_file
+                .layout(profile)
+                .map_err(|message| Error::Config {
+                    path: path.clone(),
+                    message,
+                })
+        });
+        let mut configuration = match layout {
+            Ok(configuration) => configuration,
+            Err(error) => {
+                error!("Failed to load configuration: {}", error);
+                return None;
+            }
+        };
+
+        configuration.convert_units(self.unit_system);
+
+        if let Err(errors) = configuration.validate(self.max_label_length) {
+            for error in errors {
+                error!("Invalid configuration, keeping the old one: {}", error);
+            }
+            return None;
+        }
+
+        return Some(configuration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = include_str!("default_config.toml");
+
+    const TRACK_GAUGE: &str = r#"id = "rpm"
+name = "RPM"
+units = "rpm"
+format = "%.0f"
+min = 0.0
+max = 8000.0
+low_value = 800.0
+high_value = 7000.0
+"#;
+
+    fn first_gauge_name(configuration: &Configuration) -> &str {
+        return &configuration.displays[0].gauges[0].name;
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sighup_reloads_the_changed_file() {
+        let path = std::env::temp_dir().join(format!("gauges-{}-sighup.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let settings = Settings {
+            watch_config: false,
+            ..Settings::default()
+        };
+        let reloader = Reloader::new(Some(path.clone()), None, &settings);
+
+        std::fs::write(&path, CONFIG.replace("\"COOLANT\"", "\"WATER\"")).unwrap();
+        // Without the signal or a file watch, the change goes unnoticed.
+        assert!(reloader.poll().is_none());
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+        let reloaded = reloader.poll();
+        let reloaded_again = reloader.poll();
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(first_gauge_name(&reloaded.unwrap()), "WATER");
+        assert!(reloaded_again.is_none());
+    }
+
+    #[test]
+    fn broken_file_keeps_the_current_configuration() {
+        let with_track = format!(
+            "{}\n[[profile.track.display1.gauges]]\n{}",
+            CONFIG, TRACK_GAUGE
+        );
+        let path =
+            std::env::temp_dir().join(format!("gauges-{}-reload-broken.toml", std::process::id()));
+        std::fs::write(&path, &with_track).unwrap();
+        let settings = Settings {
+            watch_config: false,
+            ..Settings::default()
+        };
+        let reloader = Reloader::new(Some(path.clone()), None, &settings);
+        let switched = reloader.switch_profile("track");
+
+        let mut reloads = Vec::new();
+        for broken in [
+            with_track.replace("max = 8000.0", "max = -1.0"),
+            format!("{}\n[[display1.gauges]\n", with_track),
+        ] {
+            std::fs::write(&path, broken).unwrap();
+            // What the SIGHUP handler does, without signalling the other
+            // tests' reloaders.
+            reloader.requested.store(true, Ordering::Relaxed);
+            reloads.push(reloader.poll());
+        }
+
+        std::fs::write(&path, &with_track).unwrap();
+        reloader.requested.store(true, Ordering::Relaxed);
+        let repaired = reloader.poll();
+
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(first_gauge_name(&switched.unwrap()), "RPM");
+        assert!(reloads.iter().all(Option::is_none));
+        assert_eq!(reloader.active_profile().as_deref(), Some("track"));
+        assert_eq!(first_gauge_name(&repaired.unwrap()), "RPM");
+    }
+}
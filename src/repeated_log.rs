@@ -0,0 +1,96 @@
+use log::{debug, info};
+
+/// Collapses runs of an identical log line, such as a reconnect loop
+/// failing every second, into the first line plus a repeat count.
+pub struct RepeatedLog {
+    last: Option<String>,
+    repeats: u32,
+}
+
+impl RepeatedLog {
+    pub fn new() -> RepeatedLog {
+        return RepeatedLog {
+            last: None,
+            repeats: 0,
+        };
+    }
+
+    pub fn log(&mut self, message: &str) {
+        if self.last.as_deref() == Some(message) {
+            self.repeats += 1;
+            // Still show progress, but ever more sparsely.
+            if self.repeats.is_power_of_two() {
+                debug!("{} (x{})", message, self.repeats + 1);
+            }
+            return;
+        }
+
+        self.flush();
+        info!("{}", message);
+        self.last = Some(message.to_string());
+    }
+
+    /// Ends the current run, reporting how often its line repeated.
+    pub fn flush(&mut self) {
+        if let Some(last) = self.last.take() {
+            if self.repeats > 0 {
+                info!("{} (x{})", last, self.repeats + 1);
+            }
+        }
+        self.repeats = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+    use crate::test_log;
+
+    #[test]
+    fn repeated_failures_are_coalesced() {
+        let lines = test_log::capture(|| {
+            let mut reconnect_log = RepeatedLog::new();
+            for _ in 0..100 {
+                reconnect_log.log("Waiting for port...");
+            }
+            reconnect_log.flush();
+        });
+
+        let info: Vec<&str> = lines
+            .iter()
+            .filter(|(level, _)| *level == Level::Info)
+            .map(|(_, line)| line.as_str())
+            .collect();
+        assert_eq!(info, ["Waiting for port...", "Waiting for port... (x100)"]);
+
+        // Progress at 2, 3, 5, 9, ... attempts, not one line per attempt.
+        let progress = lines.iter().filter(|(level, _)| *level == Level::Debug);
+        assert_eq!(progress.count(), 7);
+    }
+
+    #[test]
+    fn a_new_message_ends_the_run() {
+        let lines = test_log::capture(|| {
+            let mut reconnect_log = RepeatedLog::new();
+            reconnect_log.log("Waiting for port...");
+            reconnect_log.log("Waiting for port...");
+            reconnect_log.log("Error activating port: busy");
+        });
+
+        let info: Vec<&str> = lines
+            .iter()
+            .filter(|(level, _)| *level == Level::Info)
+            .map(|(_, line)| line.as_str())
+            .collect();
+        assert_eq!(
+            info,
+            [
+                "Waiting for port...",
+                "Waiting for port... (x2)",
+                "Error activating port: busy",
+            ]
+        );
+    }
+}
@@ -0,0 +1,494 @@
+//! JSON Schema of the wire protocol, for firmware and tool authors. Types
+//! with hand-written serde impls get hand-written schemas here; the rest
+//! derive theirs.
+
+use std::borrow::Cow;
+
+use schemars::{generate::SchemaSettings, json_schema, JsonSchema, Schema, SchemaGenerator};
+
+use crate::color::{Color, ColorSpec};
+use crate::dto::dto::{
+    Brightness, Configuration, Data, DisplayConfiguration, DisplayData, GaugeTheme, InMessage,
+    OutMessage, ShowPage, SingleDisplay, TWEEN_MS_KEY,
+};
+
+/// Keys of the per-display entries, `display1`, `display2`, ...
+const DISPLAY_KEY_PATTERN: &str = "^display[1-9][0-9]*$";
+
+impl JsonSchema for Color {
+    fn schema_name() -> Cow<'static, str> {
+        return Cow::Borrowed("Color");
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        return json_schema!({
+            "description": "RGB565, or RGB888 when the backend runs with --color-depth rgb888",
+            "type": "integer",
+            "minimum": 0,
+            "maximum": 0xFF_FFFF
+        });
+    }
+}
+
+impl JsonSchema for ColorSpec {
+    fn schema_name() -> Cow<'static, str> {
+        return Cow::Borrowed("ColorSpec");
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        return json_schema!({
+            "oneOf": [
+                {
+                    "description": "RGB565",
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": 0xFFFF
+                },
+                {
+                    "description": "\"#RRGGBB\" or a color name such as \"warm\"",
+                    "type": "string"
+                }
+            ]
+        });
+    }
+}
+
+impl JsonSchema for Configuration {
+    fn schema_name() -> Cow<'static, str> {
+        return Cow::Borrowed("Configuration");
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        return json_schema!({
+            "type": "object",
+            "properties": {
+                "theme": generator.subschema_for::<GaugeTheme>()
+            },
+            "patternProperties": {
+                DISPLAY_KEY_PATTERN: generator.subschema_for::<DisplayConfiguration>()
+            },
+            "required": ["theme"],
+            "additionalProperties": false
+        });
+    }
+}
+
+impl JsonSchema for Data {
+    fn schema_name() -> Cow<'static, str> {
+        return Cow::Borrowed("Data");
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        return json_schema!({
+            "type": "object",
+            "properties": {
+                "timestamp": {
+                    "description": "Milliseconds since the Unix epoch, never decreasing",
+                    "type": "integer",
+                    "minimum": 0
+                },
+                TWEEN_MS_KEY: {
+                    "description": "Time to animate the needles to the new values over",
+                    "type": "integer",
+                    "minimum": 0
+                }
+            },
+            "patternProperties": {
+                DISPLAY_KEY_PATTERN: generator.subschema_for::<DisplayData>()
+            },
+            "additionalProperties": false
+        });
+    }
+}
+
+/// Schema of one `{"type": N, "message": ...}` envelope.
+fn envelope(type_: u8, title: &str, message: Option<Schema>) -> Schema {
+    let mut schema = json_schema!({
+        "title": title,
+        "type": "object",
+        "properties": {
+            "type": { "const": type_ }
+        },
+        "required": ["type"]
+    });
+
+    if let Some(message) = message {
+        schema
+            .ensure_object()
+            .get_mut("properties")
+            .and_then(|properties| properties.as_object_mut())
+            .unwrap()
+            .insert(String::from("message"), message.to_value());
+        schema.insert(
+            String::from("required"),
+            serde_json::json!(["type", "message"]),
+        );
+    }
+
+    return schema;
+}
+
+impl JsonSchema for OutMessage {
+    fn schema_name() -> Cow<'static, str> {
+        return Cow::Borrowed("OutMessage");
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        return json_schema!({
+            "oneOf": [
+                envelope(1, "Configuration", Some(generator.subschema_for::<Configuration>())),
+                envelope(2, "Data", Some(generator.subschema_for::<Data>())),
+                envelope(3, "Brightness", Some(generator.subschema_for::<Brightness>())),
+                envelope(4, "ShowPage", Some(generator.subschema_for::<ShowPage>())),
+                envelope(
+                    5,
+                    "DisplayConfiguration",
+                    Some(generator.subschema_for::<SingleDisplay>())
+                )
+            ]
+        });
+    }
+}
+
+/// The Debug envelope, which may also carry the level to log it at.
+fn debug_envelope() -> Schema {
+    let mut schema = envelope(
+        3,
+        "Debug",
+        Some(json_schema!({
+            "description": "Anything; logged as JSON text"
+        })),
+    );
+
+    schema
+        .ensure_object()
+        .get_mut("properties")
+        .and_then(|properties| properties.as_object_mut())
+        .unwrap()
+        .insert(
+            String::from("level"),
+            serde_json::json!({
+                "description": "Log level, debug when absent",
+                "enum": ["trace", "debug", "info", "warn", "error"]
+            }),
+        );
+
+    return schema;
+}
+
+/// The NeedDisplayConfig envelope, naming its display next to the type.
+fn need_display_config_envelope() -> Schema {
+    let mut schema = envelope(4, "NeedDisplayConfig", None);
+
+    schema
+        .ensure_object()
+        .get_mut("properties")
+        .and_then(|properties| properties.as_object_mut())
+        .unwrap()
+        .insert(
+            String::from("display"),
+            serde_json::json!({
+                "description": "1-based, like the displayN keys",
+                "type": "integer",
+                "minimum": 1
+            }),
+        );
+    schema.insert(
+        String::from("required"),
+        serde_json::json!(["type", "display"]),
+    );
+
+    return schema;
+}
+
+/// The ConfigAck envelope, with the outcome next to the type.
+fn config_ack_envelope() -> Schema {
+    let mut schema = envelope(6, "ConfigAck", None);
+
+    let properties = schema
+        .ensure_object()
+        .get_mut("properties")
+        .and_then(|properties| properties.as_object_mut())
+        .unwrap();
+    properties.insert(
+        String::from("ok"),
+        serde_json::json!({
+            "description": "Whether the configuration was applied",
+            "type": "boolean"
+        }),
+    );
+    properties.insert(
+        String::from("error"),
+        serde_json::json!({
+            "description": "Why it wasn't, when ok is false",
+            "type": "string"
+        }),
+    );
+    schema.insert(String::from("required"), serde_json::json!(["type", "ok"]));
+
+    return schema;
+}
+
+/// The Headlights envelope, with the input state next to the type.
+fn headlights_envelope() -> Schema {
+    let mut schema = envelope(7, "Headlights", None);
+
+    schema
+        .ensure_object()
+        .get_mut("properties")
+        .and_then(|properties| properties.as_object_mut())
+        .unwrap()
+        .insert(
+            String::from("on"),
+            serde_json::json!({
+                "description": "Headlight input; the night theme is used while on",
+                "type": "boolean"
+            }),
+        );
+    schema.insert(String::from("required"), serde_json::json!(["type", "on"]));
+
+    return schema;
+}
+
+impl JsonSchema for InMessage {
+    fn schema_name() -> Cow<'static, str> {
+        return Cow::Borrowed("InMessage");
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        return json_schema!({
+            "oneOf": [
+                envelope(1, "NeedGaugeConfig", None),
+                envelope(2, "NeedGaugeData", None),
+                debug_envelope(),
+                need_display_config_envelope(),
+                config_ack_envelope(),
+                headlights_envelope()
+            ]
+        });
+    }
+}
+
+/// Schemas of both message directions, as one JSON document.
+pub fn protocol_schema() -> serde_json::Value {
+    let settings = SchemaSettings::draft2020_12().for_serialize();
+
+    return serde_json::json!({
+        "InMessage": settings.clone().into_generator().into_root_schema_for::<InMessage>(),
+        "OutMessage": settings.into_generator().into_root_schema_for::<OutMessage>(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::source::{self, RandomSource};
+
+    /// Whether `value` matches `schema`, for the keywords the protocol
+    /// schema uses. `root` resolves `$ref`s.
+    fn is_valid(root: &Value, schema: &Value, value: &Value) -> bool {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.strip_prefix("#/$defs/").unwrap();
+            return is_valid(root, &root["$defs"][name], value);
+        }
+
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&Value> = match types {
+                Value::Array(types) => types.iter().collect(),
+                type_ => vec![type_],
+            };
+            if !types
+                .iter()
+                .any(|type_| has_type(value, type_.as_str().unwrap()))
+            {
+                return false;
+            }
+        }
+
+        if schema
+            .get("const")
+            .is_some_and(|expected| expected != value)
+        {
+            return false;
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                return false;
+            }
+        }
+
+        if let Some(number) = value.as_f64() {
+            if schema["minimum"]
+                .as_f64()
+                .is_some_and(|minimum| number < minimum)
+            {
+                return false;
+            }
+            if schema["maximum"]
+                .as_f64()
+                .is_some_and(|maximum| number > maximum)
+            {
+                return false;
+            }
+        }
+
+        if let Some(Value::Array(choices)) = schema.get("oneOf") {
+            let matching = choices
+                .iter()
+                .filter(|choice| is_valid(root, choice, value))
+                .count();
+            if matching != 1 {
+                return false;
+            }
+        }
+        if let Some(Value::Array(choices)) = schema.get("anyOf") {
+            if !choices.iter().any(|choice| is_valid(root, choice, value)) {
+                return false;
+            }
+        }
+
+        if let (Some(items), Value::Array(elements)) = (schema.get("items"), value) {
+            if !elements
+                .iter()
+                .all(|element| is_valid(root, items, element))
+            {
+                return false;
+            }
+        }
+
+        if let Value::Object(fields) = value {
+            return is_valid_object(root, schema, fields);
+        }
+
+        return true;
+    }
+
+    fn has_type(value: &Value, type_: &str) -> bool {
+        match type_ {
+            "null" => return value.is_null(),
+            "boolean" => return value.is_boolean(),
+            "integer" => return value.is_i64() || value.is_u64(),
+            "number" => return value.is_number(),
+            "string" => return value.is_string(),
+            "array" => return value.is_array(),
+            "object" => return value.is_object(),
+            _ => panic!("unknown type {}", type_),
+        }
+    }
+
+    /// `displayN` keys; the only pattern the schema uses.
+    fn matches_pattern(pattern: &str, key: &str) -> bool {
+        assert_eq!(pattern, DISPLAY_KEY_PATTERN);
+
+        return key
+            .strip_prefix("display")
+            .is_some_and(|number| number.parse::<usize>().is_ok_and(|number| number > 0));
+    }
+
+    fn is_valid_object(
+        root: &Value,
+        schema: &Value,
+        fields: &serde_json::Map<String, Value>,
+    ) -> bool {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            if !required
+                .iter()
+                .all(|key| fields.contains_key(key.as_str().unwrap()))
+            {
+                return false;
+            }
+        }
+
+        for (key, field) in fields {
+            let mut known = false;
+
+            if let Some(property) = schema
+                .get("properties")
+                .and_then(|properties| properties.get(key))
+            {
+                known = true;
+                if !is_valid(root, property, field) {
+                    return false;
+                }
+            }
+
+            if let Some(Value::Object(patterns)) = schema.get("patternProperties") {
+                for (pattern, property) in patterns {
+                    if matches_pattern(pattern, key) {
+                        known = true;
+                        if !is_valid(root, property, field) {
+                            return false;
+                        }
+                    }
+                }
+            }
+
+            match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) if !known => return false,
+                Some(additional @ Value::Object(_))
+                    if !known && !is_valid(root, additional, field) =>
+                {
+                    return false;
+                }
+                _ => {}
+            }
+        }
+
+        return true;
+    }
+
+    fn out_message_is_valid(message: &OutMessage) -> bool {
+        let schema = &protocol_schema()["OutMessage"];
+        return is_valid(schema, schema, &serde_json::to_value(message).unwrap());
+    }
+
+    fn in_message_is_valid(json: &str) -> bool {
+        let schema = &protocol_schema()["InMessage"];
+        return is_valid(schema, schema, &serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    fn schema_validates_a_configuration_message() {
+        let message = OutMessage::Configuration {
+            message: crate::default_configuration(),
+        };
+
+        assert!(out_message_is_valid(&message));
+    }
+
+    #[test]
+    fn schema_validates_a_data_message() {
+        let configuration = crate::default_configuration();
+        let mut data = source::sample(&mut RandomSource::new(1.0, Some(7)), &configuration);
+        data.timestamp = Some(1_700_000_000_000);
+        data.tween_ms = Some(250);
+
+        assert!(out_message_is_valid(&OutMessage::Data { message: data }));
+    }
+
+    #[test]
+    fn schema_validates_firmware_messages() {
+        assert!(in_message_is_valid(r#"{"type":1}"#));
+        assert!(in_message_is_valid(r#"{"type":2}"#));
+        assert!(in_message_is_valid(
+            r#"{"type":3,"message":"hi","level":"warn"}"#
+        ));
+        assert!(in_message_is_valid(r#"{"type":4,"display":2}"#));
+        assert!(in_message_is_valid(
+            r#"{"type":6,"ok":false,"error":"bad"}"#
+        ));
+        assert!(in_message_is_valid(r#"{"type":7,"on":true}"#));
+    }
+
+    #[test]
+    fn schema_rejects_wrong_type_numbers() {
+        assert!(!in_message_is_valid(r#"{"type":5}"#));
+        assert!(!in_message_is_valid(r#"{"type":4}"#));
+
+        let data = serde_json::json!({"type": 1, "message": {"display1": {"gauges": []}}});
+        let schema = &protocol_schema()["OutMessage"];
+        assert!(!is_valid(schema, schema, &data));
+    }
+}
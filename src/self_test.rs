@@ -0,0 +1,95 @@
+//! Startup sweep that drives every gauge from `min` to `max` and back, so
+//! all displays and color zones can be checked by eye after connecting.
+
+use std::time::Duration;
+
+use crate::dto::dto::{Configuration, Data, DisplayData, GaugeData};
+
+/// Frames per direction of the sweep.
+const SWEEP_STEPS: usize = 20;
+
+/// Data frames for a full up-and-down sweep of every configured gauge.
+pub fn sweep(configuration: &Configuration) -> Vec<Data> {
+    let positions = (0..=SWEEP_STEPS).chain((0..SWEEP_STEPS).rev());
+    // First pages only, and nothing on disabled displays.
+    let view = configuration.with_pages(&vec![0; configuration.displays.len()]);
+
+    return positions
+        .map(|step| {
+            let fraction = step as f32 / SWEEP_STEPS as f32;
+
+            let mut data = Data {
+                displays: view
+                    .displays
+                    .iter()
+                    .map(|display| DisplayData {
+                        gauges: display
+                            .gauges
+                            .iter()
+                            .map(|gauge| {
+                                GaugeData::new(gauge.min + (gauge.max - gauge.min) * fraction)
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+                timestamp: None,
+                tween_ms: None,
+            };
+            view.apply_gradient(&mut data);
+
+            return data;
+        })
+        .collect();
+}
+
+/// Delay between sweep frames so the whole sweep takes `duration`.
+pub fn frame_interval(duration: Duration) -> Duration {
+    return duration / (2 * SWEEP_STEPS as u32 + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_rises_to_max_and_falls_back_within_range() {
+        let configuration = crate::default_configuration();
+        let frames = sweep(&configuration);
+
+        for (display, display_config) in configuration.displays.iter().enumerate() {
+            if !display_config.enabled {
+                assert!(frames
+                    .iter()
+                    .all(|data| data.displays[display].gauges.is_empty()));
+                continue;
+            }
+
+            for (index, gauge) in display_config.gauges.iter().enumerate() {
+                let values: Vec<f32> = frames
+                    .iter()
+                    .map(|data| data.displays[display].gauges[index].current_value)
+                    .collect();
+                let peak = values.len() / 2;
+
+                assert_eq!(values[0], gauge.min);
+                assert_eq!(values[peak], gauge.max);
+                assert_eq!(values[values.len() - 1], gauge.min);
+                assert!(values[..=peak].windows(2).all(|pair| pair[0] < pair[1]));
+                assert!(values[peak..].windows(2).all(|pair| pair[0] > pair[1]));
+                assert!(values
+                    .iter()
+                    .all(|value| (gauge.min..=gauge.max).contains(value)));
+            }
+        }
+    }
+
+    #[test]
+    fn sweep_takes_the_configured_duration() {
+        let frames = 2 * SWEEP_STEPS as u32 + 1;
+
+        assert_eq!(
+            frame_interval(Duration::from_millis(4100)) * frames,
+            Duration::from_millis(4100)
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Cli;
+use crate::color::ColorDepth;
+use crate::source::{CanSignal, SourceKind};
+use crate::transport::Resync;
+use crate::units::UnitSystem;
+
+/// How long DTR is held low by `dtr_toggle`.
+const DTR_TOGGLE_LOW: Duration = Duration::from_millis(100);
+
+/// Runtime settings of the backend itself, as opposed to the
+/// `Configuration` sent to the firmware. Defaults are overridden by the
+/// `[settings]` table of the config file, which is overridden by the CLI.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Port name to use; the first available port when unset.
+    pub port: Option<String>,
+    /// Serve each of these ports at once, e.g. separate front and rear
+    /// gauge controllers, instead of the single `port`.
+    pub ports: Vec<String>,
+    pub baud_rate: u32,
+    pub timeout_ms: u64,
+    pub log_level: log::LevelFilter,
+    /// Pretty-print message JSON in trace logs; the wire stays compact.
+    pub pretty_log_json: bool,
+    /// Sweep every gauge after connecting, before serving requests.
+    pub self_test: bool,
+    pub self_test_ms: u64,
+    /// Color encoding the firmware expects.
+    pub color_depth: ColorDepth,
+    /// Brightness sent after every configuration, clamped to 0-255.
+    pub brightness: Option<i64>,
+    /// Reconnect after this long without a message from the firmware;
+    /// 0 waits forever.
+    pub keepalive_ms: u64,
+    /// Consecutive transient IO errors tolerated before abandoning a port.
+    pub io_retries: u32,
+    /// Exit with an error once the connection was lost this many times,
+    /// for a supervisor to restart or alert; reconnect forever when unset.
+    pub max_reconnects: Option<u32>,
+    /// Resend the configuration when the firmware hasn't acknowledged it
+    /// after this long; 0 for firmware that sends no acknowledgements.
+    pub config_ack_timeout_ms: u64,
+    /// Resends of an unacknowledged or rejected configuration.
+    pub config_retries: u32,
+    /// On Ctrl-C or SIGTERM, send every gauge offline before closing the
+    /// port, so the firmware doesn't keep showing the last values.
+    pub offline_on_exit: bool,
+    /// Read `hide <gauge id>`, `show <gauge id>`, `snapshot`, `readings`
+    /// and other commands from stdin.
+    pub control_stdin: bool,
+    /// Reload the config file when it changes, as on SIGHUP.
+    pub watch_config: bool,
+    /// Log uptime, reconnects and message counts this often; 0 never.
+    pub stats_interval_ms: u64,
+    /// How to recover from a frame that can't be decoded.
+    pub resync: Resync,
+    /// Pause after asserting DTR before the first message, for boards
+    /// that reset on DTR and need time to boot.
+    pub startup_delay_ms: u64,
+    /// Hold the configuration back until the firmware sends a valid
+    /// frame, giving up on the port after this long; 0 sends right away.
+    pub ready_timeout_ms: u64,
+    /// Pulse DTR low before asserting it, to force such a reset.
+    pub dtr_toggle: bool,
+    /// `[profile.<name>]` of the config file to start with; the top-level
+    /// layout when unset.
+    pub profile: Option<String>,
+    /// Longest gauge name or units, in characters, the displays can fit.
+    pub max_label_length: usize,
+    /// Units gauges are shown in, unless they set their own.
+    pub unit_system: UnitSystem,
+    /// Where gauge values come from.
+    pub source: SourceKind,
+    /// Show every gauge offline when the source can't be opened, instead
+    /// of exiting.
+    pub safe_mode: bool,
+    /// Most `Data` messages sent per second; requests beyond it are
+    /// dropped, or only refresh the priority displays if there are any.
+    /// 0 sends every one.
+    pub max_data_rate: f64,
+    /// Needle animation time sent with each Data message, normally the
+    /// firmware's data request interval; 0 leaves animation off.
+    pub tween_ms: u64,
+    /// Between reads of a gauge with `update_ms`, extrapolate from its last
+    /// two reads instead of repeating the last one.
+    pub predict: bool,
+    /// How far the `random` source swings, from 0 (steady) to 1 (the
+    /// whole range).
+    pub random_noise: f32,
+    /// Seed of the `random` source, for repeatable values.
+    pub random_seed: Option<u64>,
+    /// Serial port of the OBD-II adapter used by the `obd2` source.
+    pub obd2_port: Option<String>,
+    pub obd2_baud_rate: u32,
+    /// SocketCAN interface used by the `can` source.
+    pub can_interface: String,
+    /// A CAN value older than this reads as offline.
+    pub can_stale_ms: u64,
+    /// How each gauge is decoded from the CAN frames.
+    pub can_signals: Vec<CanSignal>,
+    /// Serial port of the NMEA GPS module used by the `gps` source.
+    pub gps_port: Option<String>,
+    pub gps_baud_rate: u32,
+    /// Unix domain socket the `socket` source listens on.
+    pub socket_path: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            port: None,
+            ports: Vec::new(),
+            baud_rate: 115_200,
+            timeout_ms: 1000,
+            log_level: log::LevelFilter::Info,
+            pretty_log_json: false,
+            self_test: false,
+            self_test_ms: 3000,
+            color_depth: ColorDepth::Rgb565,
+            brightness: None,
+            keepalive_ms: 10_000,
+            io_retries: 3,
+            max_reconnects: None,
+            config_ack_timeout_ms: 0,
+            config_retries: 3,
+            offline_on_exit: false,
+            control_stdin: false,
+            watch_config: true,
+            stats_interval_ms: 0,
+            resync: Resync::Delimiter,
+            startup_delay_ms: 0,
+            ready_timeout_ms: 0,
+            dtr_toggle: false,
+            profile: None,
+            max_label_length: 16,
+            unit_system: UnitSystem::Metric,
+            source: SourceKind::Random,
+            safe_mode: false,
+            max_data_rate: 0.0,
+            tween_ms: 0,
+            predict: false,
+            random_noise: 1.0,
+            random_seed: None,
+            obd2_port: None,
+            obd2_baud_rate: 38_400,
+            can_interface: String::from("can0"),
+            can_stale_ms: 1000,
+            can_signals: Vec::new(),
+            gps_port: None,
+            gps_baud_rate: 9600,
+            socket_path: PathBuf::from("/tmp/gauges.sock"),
+        }
+    }
+}
+
+impl Settings {
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        match cli.port.as_slice() {
+            [] => {}
+            [port] => self.port = Some(port.clone()),
+            ports => self.ports = ports.to_vec(),
+        }
+        if let Some(baud) = cli.baud {
+            self.baud_rate = baud;
+        }
+        if let Some(timeout_ms) = cli.timeout_ms {
+            self.timeout_ms = timeout_ms;
+        }
+        if let Some(log_level) = cli.log_level {
+            self.log_level = log_level;
+        }
+        if cli.pretty_log_json {
+            self.pretty_log_json = true;
+        }
+        if cli.self_test {
+            self.self_test = true;
+        }
+        if let Some(self_test_ms) = cli.self_test_ms {
+            self.self_test_ms = self_test_ms;
+        }
+        if let Some(color_depth) = cli.color_depth {
+            self.color_depth = color_depth;
+        }
+
+        if let Some(unit_system) = cli.unit_system {
+            self.unit_system = unit_system;
+        }
+        if let Some(brightness) = cli.brightness {
+            self.brightness = Some(brightness);
+        }
+        if let Some(keepalive_ms) = cli.keepalive_ms {
+            self.keepalive_ms = keepalive_ms;
+        }
+        if let Some(io_retries) = cli.io_retries {
+            self.io_retries = io_retries;
+        }
+        if let Some(max_reconnects) = cli.max_reconnects {
+            self.max_reconnects = Some(max_reconnects);
+        }
+        if let Some(config_ack_timeout_ms) = cli.config_ack_timeout_ms {
+            self.config_ack_timeout_ms = config_ack_timeout_ms;
+        }
+        if let Some(config_retries) = cli.config_retries {
+            self.config_retries = config_retries;
+        }
+        if cli.offline_on_exit {
+            self.offline_on_exit = true;
+        }
+        if cli.control_stdin {
+            self.control_stdin = true;
+        }
+        if cli.no_watch_config {
+            self.watch_config = false;
+        }
+        if let Some(stats_interval_ms) = cli.stats_interval_ms {
+            self.stats_interval_ms = stats_interval_ms;
+        }
+        if let Some(resync) = cli.resync {
+            self.resync = resync;
+        }
+        if let Some(startup_delay_ms) = cli.startup_delay_ms {
+            self.startup_delay_ms = startup_delay_ms;
+        }
+        if let Some(ready_timeout_ms) = cli.ready_timeout_ms {
+            self.ready_timeout_ms = ready_timeout_ms;
+        }
+        if cli.dtr_toggle {
+            self.dtr_toggle = true;
+        }
+        if let Some(profile) = &cli.profile {
+            self.profile = Some(profile.clone());
+        }
+        if let Some(source) = cli.source {
+            self.source = source;
+        }
+        if cli.safe_mode {
+            self.safe_mode = true;
+        }
+        if let Some(max_data_rate) = cli.max_data_rate {
+            self.max_data_rate = max_data_rate;
+        }
+        if let Some(tween_ms) = cli.tween_ms {
+            self.tween_ms = tween_ms;
+        }
+        if cli.predict {
+            self.predict = true;
+        }
+        if let Some(random_noise) = cli.random_noise {
+            self.random_noise = random_noise;
+        }
+        if let Some(random_seed) = cli.random_seed {
+            self.random_seed = Some(random_seed);
+        }
+        if let Some(obd2_port) = &cli.obd2_port {
+            self.obd2_port = Some(obd2_port.clone());
+        }
+        if let Some(obd2_baud) = cli.obd2_baud {
+            self.obd2_baud_rate = obd2_baud;
+        }
+        if let Some(can_interface) = &cli.can_interface {
+            self.can_interface = can_interface.clone();
+        }
+        if let Some(gps_port) = &cli.gps_port {
+            self.gps_port = Some(gps_port.clone());
+        }
+        if let Some(gps_baud) = cli.gps_baud {
+            self.gps_baud_rate = gps_baud;
+        }
+        if let Some(socket_path) = &cli.socket_path {
+            self.socket_path = socket_path.clone();
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        return Duration::from_millis(self.timeout_ms);
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        if self.keepalive_ms == 0 {
+            return None;
+        }
+
+        return Some(Duration::from_millis(self.keepalive_ms));
+    }
+
+    pub fn config_ack_timeout(&self) -> Option<Duration> {
+        if self.config_ack_timeout_ms == 0 {
+            return None;
+        }
+
+        return Some(Duration::from_millis(self.config_ack_timeout_ms));
+    }
+
+    pub fn ready_timeout(&self) -> Option<Duration> {
+        if self.ready_timeout_ms == 0 {
+            return None;
+        }
+
+        return Some(Duration::from_millis(self.ready_timeout_ms));
+    }
+
+    pub fn stats_interval(&self) -> Option<Duration> {
+        if self.stats_interval_ms == 0 {
+            return None;
+        }
+
+        return Some(Duration::from_millis(self.stats_interval_ms));
+    }
+
+    pub fn tween(&self) -> Option<u64> {
+        if self.tween_ms == 0 {
+            return None;
+        }
+
+        return Some(self.tween_ms);
+    }
+
+    /// DTR levels to set when opening a port, each followed by a pause.
+    pub fn dtr_sequence(&self) -> Vec<(bool, Duration)> {
+        let mut sequence = Vec::new();
+
+        if self.dtr_toggle {
+            sequence.push((false, DTR_TOGGLE_LOW));
+        }
+        sequence.push((true, Duration::from_millis(self.startup_delay_ms)));
+
+        return sequence;
+    }
+
+    pub fn self_test_duration(&self) -> Duration {
+        return Duration::from_millis(self.self_test_ms);
+    }
+
+    #[cfg_attr(not(all(feature = "can", target_os = "linux")), allow(dead_code))]
+    pub fn can_stale(&self) -> Duration {
+        return Duration::from_millis(self.can_stale_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn cli_overrides_file_values() {
+        let mut settings: Settings = toml::from_str("baud_rate = 57600\ntimeout_ms = 500").unwrap();
+        let cli = Cli::try_parse_from(["car_pc", "--baud", "9600"]).unwrap();
+
+        settings.apply_cli(&cli);
+
+        assert_eq!(settings.baud_rate, 9600);
+        assert_eq!(settings.timeout_ms, 500);
+    }
+
+    #[test]
+    fn defaults_apply_when_unset() {
+        let mut settings: Settings = toml::from_str("baud_rate = 57600").unwrap();
+        let cli = Cli::try_parse_from(["car_pc"]).unwrap();
+
+        settings.apply_cli(&cli);
+
+        let defaults = Settings::default();
+        assert_eq!(settings.baud_rate, 57600);
+        assert_eq!(settings.timeout_ms, defaults.timeout_ms);
+        assert_eq!(settings.keepalive_ms, defaults.keepalive_ms);
+        assert_eq!(settings.port, None);
+        assert_eq!(settings.source, SourceKind::Random);
+    }
+}
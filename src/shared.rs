@@ -0,0 +1,226 @@
+//! Latest configuration and data sent to the firmware, readable from any
+//! thread for consumers such as network servers, without holding up the
+//! serial loop.
+
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::dto::dto::{Configuration, Data, OutMessage};
+
+#[derive(Clone, Default, Serialize)]
+pub struct Snapshot {
+    pub configuration: Option<Configuration>,
+    pub data: Option<Data>,
+    /// Page each display shows, as last told to the firmware.
+    pub pages: Vec<usize>,
+}
+
+/// One gauge value with where it sits, for dashboards laying gauges out
+/// like the physical displays.
+#[derive(Serialize)]
+pub struct GaugeReading {
+    pub id: String,
+    /// 0-based, unlike the `displayN` keys of the wire format.
+    pub display: usize,
+    pub page: usize,
+    pub position: usize,
+    pub value: f32,
+    pub units: String,
+}
+
+impl Snapshot {
+    /// The latest data joined with the gauges it belongs to.
+    pub fn readings(&self) -> Vec<GaugeReading> {
+        let (Some(configuration), Some(data)) = (&self.configuration, &self.data) else {
+            return Vec::new();
+        };
+
+        let mut readings = Vec::new();
+        for (display, (display_config, display_data)) in configuration
+            .displays
+            .iter()
+            .zip(data.displays.iter())
+            .enumerate()
+        {
+            let page = self.pages.get(display).copied().unwrap_or(0);
+            if !display_config.enabled || page >= display_config.page_count() {
+                continue;
+            }
+
+            for (position, (gauge, value)) in display_config
+                .page(page)
+                .iter()
+                .zip(display_data.gauges.iter())
+                .enumerate()
+            {
+                readings.push(GaugeReading {
+                    id: gauge.id.clone(),
+                    display,
+                    page,
+                    position,
+                    value: value.current_value,
+                    units: gauge.shown_units().to_string(),
+                });
+            }
+        }
+
+        return readings;
+    }
+}
+
+/// Replaced wholesale on every update, so the lock is only held to swap
+/// or clone an `Arc`, never while serializing or reading a snapshot.
+static LATEST: Mutex<Option<Arc<Snapshot>>> = Mutex::new(None);
+
+/// Records a message on its way to the firmware.
+pub fn publish(message: &OutMessage) {
+    let mut latest = LATEST
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut snapshot = latest.as_deref().cloned().unwrap_or_default();
+
+    match message {
+        OutMessage::Configuration { message } => {
+            // The firmware starts over on the first page of every display.
+            snapshot.pages = vec![0; message.displays.len()];
+            snapshot.configuration = Some(message.clone());
+        }
+        OutMessage::Data { message } => {
+            snapshot.data = Some(message.clone());
+        }
+        OutMessage::ShowPage { message } => {
+            let index = message.display.checked_sub(1);
+            if let Some(page) = index.and_then(|index| snapshot.pages.get_mut(index)) {
+                *page = message.page;
+            }
+        }
+        OutMessage::Brightness { .. } | OutMessage::DisplayConfiguration { .. } => {
+            return;
+        }
+    }
+
+    *latest = Some(Arc::new(snapshot));
+}
+
+/// What was last sent, empty until the first message.
+pub fn snapshot() -> Arc<Snapshot> {
+    let latest = LATEST
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    return latest.clone().unwrap_or_default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `displays` displays of one gauge each.
+    fn configuration(displays: usize) -> Configuration {
+        let toml: String = (1..=displays)
+            .map(|display| {
+                format!(
+                    "[[display{}.gauges]]\nid = \"g{}\"\nname = \"G\"\nunits = \"%\"\nformat = \"%.0f\"\nmin = 0.0\nmax = 100.0\nlow_value = 10.0\nhigh_value = 90.0\n",
+                    display, display
+                )
+            })
+            .collect();
+        return toml::from_str(&toml).unwrap();
+    }
+
+    #[test]
+    fn readers_see_whole_snapshots_while_publishing() {
+        let layouts = [configuration(1), configuration(3)];
+        let published = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for round in 0..200 {
+                    publish(&OutMessage::Configuration {
+                        message: layouts[round % 2].clone(),
+                    });
+                }
+                published.store(true, std::sync::atomic::Ordering::Relaxed);
+            });
+
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut reads = 0;
+                        while !published.load(std::sync::atomic::Ordering::Relaxed) || reads == 0 {
+                            let snapshot = snapshot();
+                            // Never a configuration from one publish with
+                            // the pages of another.
+                            if let Some(configuration) = &snapshot.configuration {
+                                assert_eq!(snapshot.pages.len(), configuration.displays.len());
+                            }
+                            reads += 1;
+                        }
+                    })
+                })
+                .collect();
+
+            for reader in readers {
+                reader.join().unwrap();
+            }
+        });
+
+        assert!(snapshot().configuration.is_some());
+    }
+
+    #[test]
+    fn snapshot_prints_as_json() {
+        let snapshot = Snapshot {
+            configuration: Some(configuration(2)),
+            data: None,
+            pages: vec![0, 1],
+        };
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+
+        assert_eq!(json["configuration"]["display2"]["gauges"][0]["id"], "g2");
+        assert_eq!(json["data"], serde_json::Value::Null);
+        assert_eq!(json["pages"], serde_json::json!([0, 1]));
+    }
+
+    #[test]
+    fn readings_place_each_gauge() {
+        let mut configuration = configuration(3);
+        configuration.displays[1].enabled = false;
+        let mut second = configuration.displays[2].gauges[0].clone();
+        second.id = String::from("g3b");
+        configuration.displays[2].gauges.push(second);
+        let data: Data =
+            serde_json::from_str(r#"{"display1":{"gauges":[{"current_value":12.0}]},"display2":{"gauges":[{"current_value":34.0}]},"display3":{"gauges":[{"current_value":56.0},{"current_value":78.0}]}}"#)
+                .unwrap();
+        let snapshot = Snapshot {
+            configuration: Some(configuration),
+            data: Some(data),
+            pages: vec![0, 0, 0],
+        };
+
+        let json = serde_json::to_value(snapshot.readings()).unwrap();
+
+        // The disabled second display shows nothing.
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"id": "g1", "display": 0, "page": 0, "position": 0, "value": 12.0, "units": "%"},
+                {"id": "g3", "display": 2, "page": 0, "position": 0, "value": 56.0, "units": "%"},
+                {"id": "g3b", "display": 2, "page": 0, "position": 1, "value": 78.0, "units": "%"},
+            ])
+        );
+    }
+
+    #[test]
+    fn no_readings_before_the_first_data() {
+        let snapshot = Snapshot {
+            configuration: Some(configuration(1)),
+            data: None,
+            pages: vec![0],
+        };
+
+        assert!(snapshot.readings().is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+//! Stops serving on SIGINT or SIGTERM, so the port is flushed and released
+//! and the data source cleaned up instead of the process dying mid-frame.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::warn;
+
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let requested = Arc::new(AtomicBool::new(false));
+
+        #[cfg(unix)]
+        for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            // A second signal while shutting down exits right away, in case
+            // the port hangs.
+            if let Err(error) =
+                signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&requested))
+            {
+                warn!("Failed to install signal handler: {}", error);
+            }
+            if let Err(error) = signal_hook::flag::register(signal, Arc::clone(&requested)) {
+                warn!("Failed to install signal handler: {}", error);
+            }
+        }
+
+        return Shutdown { requested };
+    }
+
+    pub fn requested(&self) -> bool {
+        return self.requested.load(Ordering::Relaxed);
+    }
+
+    /// Asks for a shutdown as a signal would.
+    #[cfg(test)]
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Resolves once a shutdown was requested.
+    #[cfg(feature = "async")]
+    pub async fn wait(&self) {
+        while !self.requested() {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::dto::dto::{GaugeConfig, GaugeData};
+
+use super::DataSource;
+
+struct Reading {
+    value: f32,
+    read_at: Instant,
+    /// Change per second since the read before, when predicting.
+    slope: Option<f32>,
+}
+
+impl Reading {
+    /// The value at `now`: the last read, or with a slope, extrapolated
+    /// along it without leaving the gauge's range.
+    fn value_at(&self, now: Instant, gauge: &GaugeConfig) -> f32 {
+        let Some(slope) = self.slope else {
+            return self.value;
+        };
+
+        let elapsed = now.duration_since(self.read_at).as_secs_f32();
+        let (min, max) = gauge.measured_range();
+        return (self.value + slope * elapsed).clamp(min, max);
+    }
+}
+
+/// Wraps a source so each gauge is only read again once its `update_ms`
+/// has passed, repeating the last value in between. Slow sensors such as
+/// coolant temperature then don't cost a bus round trip on every request.
+/// With `predict`, values in between are extrapolated from the last two
+/// reads instead, so slowly read gauges move smoothly rather than in steps.
+pub struct CachedSource {
+    inner: Box<dyn DataSource>,
+    predict: bool,
+    clock: Arc<dyn Clock>,
+    values: HashMap<String, Reading>,
+}
+
+impl CachedSource {
+    pub fn new(inner: Box<dyn DataSource>, predict: bool, clock: Arc<dyn Clock>) -> CachedSource {
+        return CachedSource {
+            inner,
+            predict,
+            clock,
+            values: HashMap::new(),
+        };
+    }
+
+    fn slope(&self, gauge: &GaugeConfig, value: f32, now: Instant) -> Option<f32> {
+        // Fuel senders read in ohms, not in the gauge's range.
+        if !self.predict || gauge.fuel.is_some() || value == GaugeData::OFFLINE_VALUE {
+            return None;
+        }
+
+        let previous = self.values.get(&gauge.id)?;
+        let elapsed = now.duration_since(previous.read_at).as_secs_f32();
+        if previous.value == GaugeData::OFFLINE_VALUE || elapsed <= 0.0 {
+            return None;
+        }
+
+        return Some((value - previous.value) / elapsed);
+    }
+}
+
+impl DataSource for CachedSource {
+    fn refresh(&mut self) {
+        self.inner.refresh();
+    }
+
+    fn read(&mut self, display: usize, index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        let Some(update_ms) = gauge.update_ms else {
+            return self.inner.read(display, index, gauge);
+        };
+
+        let now = self.clock.now();
+        if let Some(reading) = self.values.get(&gauge.id) {
+            if now.duration_since(reading.read_at) < Duration::from_millis(update_ms) {
+                return Ok(reading.value_at(now, gauge));
+            }
+        }
+
+        let value = self.inner.read(display, index, gauge)?;
+        let slope = self.slope(gauge, value, now);
+        self.values.insert(
+            gauge.id.clone(),
+            Reading {
+                value,
+                read_at: now,
+                slope,
+            },
+        );
+
+        return Ok(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    /// Reads 1, 2, 3, ... on every read that gets through.
+    struct Counter {
+        reads: u32,
+    }
+
+    impl DataSource for Counter {
+        fn read(&mut self, _: usize, _: usize, _: &GaugeConfig) -> Result<f32, String> {
+            self.reads += 1;
+            return Ok(self.reads as f32);
+        }
+    }
+
+    fn gauge(update_ms: u64) -> GaugeConfig {
+        return toml::from_str(&format!(
+            r#"
+            id = "coolant"
+            name = "COOLANT"
+            units = "C"
+            format = "%.0f"
+            min = 0.0
+            max = 130.0
+            low_value = 60.0
+            high_value = 100.0
+            update_ms = {}
+            "#,
+            update_ms
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn cached_value_goes_stale_after_update_ms() {
+        let clock = Arc::new(MockClock::new(0));
+        let mut source = CachedSource::new(Box::new(Counter { reads: 0 }), false, clock.clone());
+        let gauge = gauge(500);
+
+        assert_eq!(source.read(0, 0, &gauge), Ok(1.0));
+
+        clock.advance(Duration::from_millis(499));
+        assert_eq!(source.read(0, 0, &gauge), Ok(1.0));
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(source.read(0, 0, &gauge), Ok(2.0));
+    }
+
+    /// Counts the reads that got through for each gauge id.
+    struct ReadCounts {
+        counts: Arc<std::sync::Mutex<HashMap<String, u32>>>,
+    }
+
+    impl DataSource for ReadCounts {
+        fn read(&mut self, _: usize, _: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+            let mut counts = self.counts.lock().unwrap();
+            *counts.entry(gauge.id.clone()).or_default() += 1;
+            return Ok(50.0);
+        }
+    }
+
+    #[test]
+    fn slow_gauge_is_read_less_often_than_a_fast_one() {
+        let clock = Arc::new(MockClock::new(0));
+        let counts = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let inner = ReadCounts {
+            counts: counts.clone(),
+        };
+        let mut source = CachedSource::new(Box::new(inner), false, clock.clone());
+        let mut rpm = gauge(100);
+        rpm.id = String::from("rpm");
+        let coolant = gauge(1000);
+
+        // Two seconds of requests every 100 ms.
+        for _ in 0..20 {
+            assert_eq!(source.read(0, 0, &rpm), Ok(50.0));
+            assert_eq!(source.read(0, 1, &coolant), Ok(50.0));
+            clock.advance(Duration::from_millis(100));
+        }
+
+        let counts = counts.lock().unwrap();
+        assert_eq!(counts["rpm"], 20);
+        assert_eq!(counts["coolant"], 2);
+    }
+
+    /// Reads `values` in turn, then offline.
+    struct Scripted(Vec<f32>);
+
+    impl DataSource for Scripted {
+        fn read(&mut self, _: usize, _: usize, _: &GaugeConfig) -> Result<f32, String> {
+            if self.0.is_empty() {
+                return Ok(GaugeData::OFFLINE_VALUE);
+            }
+            return Ok(self.0.remove(0));
+        }
+    }
+
+    /// The value read half a second after each of `values`, read 1 s apart.
+    fn halfway(values: &[f32], predict: bool) -> Vec<f32> {
+        let clock = Arc::new(MockClock::new(0));
+        let inner = Scripted(values.to_vec());
+        let mut source = CachedSource::new(Box::new(inner), predict, clock.clone());
+        let gauge = gauge(1000);
+
+        let mut halfway = Vec::new();
+        for _ in values {
+            source.read(0, 0, &gauge).unwrap();
+            clock.advance(Duration::from_millis(500));
+            halfway.push(source.read(0, 0, &gauge).unwrap());
+            clock.advance(Duration::from_millis(500));
+        }
+        return halfway;
+    }
+
+    #[test]
+    fn prediction_extrapolates_between_reads() {
+        // Nothing to extrapolate from until the second read.
+        assert_eq!(halfway(&[60.0, 70.0, 74.0], true), [60.0, 75.0, 76.0]);
+        assert_eq!(halfway(&[60.0, 70.0, 74.0], false), [60.0, 70.0, 74.0]);
+    }
+
+    #[test]
+    fn prediction_stays_within_the_range() {
+        assert_eq!(halfway(&[100.0, 125.0], true), [100.0, 130.0]);
+        assert_eq!(halfway(&[20.0, 5.0], true), [20.0, 0.0]);
+    }
+
+    #[test]
+    fn offline_readings_are_not_extrapolated() {
+        let offline = GaugeData::OFFLINE_VALUE;
+
+        assert_eq!(halfway(&[60.0, offline, 70.0], true), [60.0, offline, 70.0]);
+    }
+}
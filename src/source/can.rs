@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use socketcan::{CanFilter, CanSocket, EmbeddedFrame, Frame, Socket, SocketOptions};
+
+use crate::dto::dto::{GaugeConfig, GaugeData};
+
+use super::CanSignal;
+
+/// Frame identifier bits compared by the receive filters.
+const CAN_ID_MASK: u32 = 0x1FFF_FFFF;
+
+/// Engine data broadcast on a SocketCAN interface. Each configured signal
+/// feeds the gauge with the matching `id`; a gauge whose frames stop
+/// arriving reads as offline once its last value is older than the
+/// staleness limit.
+pub struct CanSource {
+    socket: CanSocket,
+    signals: Vec<CanSignal>,
+    values: HashMap<String, (f32, Instant)>,
+    stale_after: Duration,
+}
+
+impl CanSource {
+    pub fn new(
+        interface: &str,
+        signals: Vec<CanSignal>,
+        stale_after: Duration,
+    ) -> Result<CanSource, String> {
+        let socket = match CanSocket::open(interface) {
+            Ok(socket) => socket,
+            Err(error) => {
+                return Err(format!(
+                    "failed to open CAN interface {}: {}",
+                    interface, error
+                ));
+            }
+        };
+
+        let filters: Vec<CanFilter> = signals
+            .iter()
+            .map(|signal| CanFilter::new(signal.can_id, CAN_ID_MASK))
+            .collect();
+
+        if let Err(error) = socket
+            .set_filters(&filters)
+            .and_then(|_| socket.set_nonblocking(true))
+        {
+            return Err(format!(
+                "failed to set up CAN interface {}: {}",
+                interface, error
+            ));
+        }
+
+        info!("Listening for {} signals on {}", signals.len(), interface);
+        return Ok(CanSource {
+            socket,
+            signals,
+            values: HashMap::new(),
+            stale_after,
+        });
+    }
+}
+
+impl super::DataSource for CanSource {
+    /// Drains every frame received since the last refresh.
+    fn refresh(&mut self) {
+        loop {
+            let frame = match self.socket.read_frame() {
+                Ok(frame) => frame,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    return;
+                }
+                Err(error) => {
+                    warn!("Failed to read CAN frame: {}", error);
+                    return;
+                }
+            };
+
+            let now = Instant::now();
+            for signal in &self.signals {
+                if signal.can_id != frame.raw_id() {
+                    continue;
+                }
+
+                if let Some(value) = signal.decode(frame.data()) {
+                    self.values.insert(signal.gauge.clone(), (value, now));
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, _display: usize, _index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        match self.values.get(&gauge.id) {
+            Some((value, received)) if received.elapsed() <= self.stale_after => {
+                return Ok(*value);
+            }
+            _ => {
+                return Ok(GaugeData::OFFLINE_VALUE);
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Where a gauge value sits inside a CAN frame. Parsed from the settings
+//! even when the `can` source itself isn't built.
+#![cfg_attr(not(all(feature = "can", target_os = "linux")), allow(dead_code))]
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanSignal {
+    /// `id` of the gauge this signal drives.
+    pub gauge: String,
+    pub can_id: u32,
+    /// First bit of the value in little-endian (Intel) bit numbering.
+    pub start_bit: u32,
+    /// Width of the value in bits, at most 64.
+    pub length: u32,
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    #[serde(default)]
+    pub offset: f32,
+}
+
+fn default_scale() -> f32 {
+    return 1.0;
+}
+
+impl CanSignal {
+    /// `raw * scale + offset`, or None when the frame is too short to
+    /// hold the signal.
+    pub fn decode(&self, data: &[u8]) -> Option<f32> {
+        let available = data.len().min(8) as u32 * 8;
+        if self.length == 0 || self.length > 64 || self.start_bit + self.length > available {
+            return None;
+        }
+
+        let mut bytes = [0u8; 8];
+        let count = data.len().min(8);
+        bytes[..count].copy_from_slice(&data[..count]);
+
+        let mask = match self.length {
+            64 => u64::MAX,
+            length => (1u64 << length) - 1,
+        };
+        let raw = (u64::from_le_bytes(bytes) >> self.start_bit) & mask;
+
+        let value = if self.signed && raw >> (self.length - 1) & 1 == 1 {
+            (raw | !mask) as i64 as f64
+        } else {
+            raw as f64
+        };
+
+        return Some((value * self.scale as f64 + self.offset as f64) as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(start_bit: u32, length: u32, signed: bool, scale: f32, offset: f32) -> CanSignal {
+        return CanSignal {
+            gauge: "test".to_string(),
+            can_id: 0x7E8,
+            start_bit,
+            length,
+            signed,
+            scale,
+            offset,
+        };
+    }
+
+    #[test]
+    fn decodes_scaled_rpm_from_a_sample_frame() {
+        // 16-bit little-endian engine speed at byte 2, 0.25 rpm per bit.
+        let rpm = signal(16, 16, false, 0.25, 0.0);
+        let frame = [0x00, 0x00, 0x40, 0x1F, 0x00, 0x00, 0x00, 0x00];
+
+        assert_eq!(rpm.decode(&frame), Some(2000.0));
+    }
+
+    #[test]
+    fn decodes_offset_coolant_temperature() {
+        let coolant = signal(0, 8, false, 1.0, -40.0);
+
+        assert_eq!(coolant.decode(&[130]), Some(90.0));
+    }
+
+    #[test]
+    fn sign_extends_signed_signals() {
+        let torque = signal(4, 8, true, 1.0, 0.0);
+
+        // 0xF6 shifted up by 4 bits is -10 as an 8-bit value.
+        assert_eq!(torque.decode(&[0x60, 0x0F]), Some(-10.0));
+    }
+
+    #[test]
+    fn frame_too_short_for_the_signal_is_none() {
+        let rpm = signal(16, 16, false, 0.25, 0.0);
+
+        assert_eq!(rpm.decode(&[0x00, 0x00, 0x40]), None);
+    }
+}
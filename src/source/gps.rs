@@ -0,0 +1,252 @@
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::dto::dto::{GaugeConfig, GaugeData};
+
+use super::DataSource;
+
+/// A fix older than this reads as offline, e.g. after the module loses
+/// the sky or stops talking.
+const FIX_TIMEOUT: Duration = Duration::from_secs(2);
+
+const KMH_PER_KNOT: f32 = 1.852;
+const MPH_PER_KMH: f32 = 0.621_371;
+
+/// Speed in km/h from an NMEA sentence such as
+/// `$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A`.
+/// `Some(None)` is a sentence saying there is no fix; `None` is any other
+/// or a garbled sentence.
+fn parse_sentence(line: &str) -> Option<Option<f32>> {
+    let line = line.trim();
+    let body = line.strip_prefix('$')?;
+
+    let body = match body.split_once('*') {
+        Some((body, checksum)) => {
+            let expected = u8::from_str_radix(checksum, 16).ok()?;
+            if body.bytes().fold(0, |sum, byte| sum ^ byte) != expected {
+                return None;
+            }
+            body
+        }
+        None => body,
+    };
+
+    let fields: Vec<&str> = body.split(',').collect();
+    // Any talker, GP, GN, GL, ...
+    let sentence = fields.first()?.get(2..)?;
+
+    match sentence {
+        "RMC" => {
+            if fields.get(2) != Some(&"A") {
+                return Some(None);
+            }
+            let knots: f32 = fields.get(7)?.parse().ok()?;
+            return Some(Some(knots * KMH_PER_KNOT));
+        }
+        "VTG" => {
+            if fields.get(9) == Some(&"N") {
+                return Some(None);
+            }
+            match fields.get(7)?.parse::<f32>() {
+                Ok(kmh) => {
+                    return Some(Some(kmh));
+                }
+                Err(_) => {
+                    return Some(None);
+                }
+            }
+        }
+        _ => {
+            return None;
+        }
+    }
+}
+
+/// Ground speed from a serial GPS module speaking NMEA. Feeds gauges with
+/// id `speed` (km/h) or `speed_mph`; every other gauge reads as offline,
+/// as do both speeds while there is no fix.
+pub struct GpsSource {
+    port: Box<dyn serialport::SerialPort>,
+    buffer: Vec<u8>,
+    /// Latest speed in km/h and when it was received.
+    fix: Option<(f32, Instant)>,
+}
+
+impl GpsSource {
+    pub fn new(port_name: &str, baud_rate: u32, timeout: Duration) -> Result<GpsSource, String> {
+        let port = match serialport::new(port_name, baud_rate)
+            .timeout(timeout)
+            .open()
+        {
+            Ok(port) => port,
+            Err(error) => {
+                return Err(format!(
+                    "failed to open GPS module {}: {}",
+                    port_name, error
+                ));
+            }
+        };
+
+        info!("GPS module {} opened", port_name);
+        return Ok(GpsSource {
+            port,
+            buffer: Vec::new(),
+            fix: None,
+        });
+    }
+
+    /// Reads what the module sent since the last call, without waiting.
+    fn receive(&mut self) -> Result<(), String> {
+        let available = match self.port.bytes_to_read() {
+            Ok(available) => available as usize,
+            Err(error) => {
+                return Err(error.to_string());
+            }
+        };
+
+        if available == 0 {
+            return Ok(());
+        }
+
+        let start = self.buffer.len();
+        self.buffer.resize(start + available, 0);
+        match self.port.read(&mut self.buffer[start..]) {
+            Ok(read) => self.buffer.truncate(start + read),
+            Err(error) => {
+                self.buffer.truncate(start);
+                return Err(error.to_string());
+            }
+        }
+
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+
+            match parse_sentence(&line) {
+                Some(Some(speed)) => self.fix = Some((speed, Instant::now())),
+                Some(None) => self.fix = None,
+                None => {}
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl DataSource for GpsSource {
+    fn refresh(&mut self) {
+        if let Err(error) = self.receive() {
+            debug!("Failed to read GPS module: {}", error);
+        }
+    }
+
+    fn read(&mut self, _display: usize, _index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        let speed = match self.fix {
+            Some((speed, received_at)) if received_at.elapsed() <= FIX_TIMEOUT => speed,
+            _ => {
+                return Ok(GaugeData::OFFLINE_VALUE);
+            }
+        };
+
+        match gauge.id.as_str() {
+            "speed" => {
+                return Ok(speed);
+            }
+            "speed_mph" => {
+                return Ok(speed * MPH_PER_KMH);
+            }
+            _ => {
+                return Ok(GaugeData::OFFLINE_VALUE);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n";
+    const RMC_NO_FIX: &str = "$GPRMC,123519,V,,,,,,,230394,,*33\r\n";
+
+    fn speed(line: &str) -> Option<Option<f32>> {
+        return parse_sentence(line);
+    }
+
+    #[test]
+    fn speed_from_rmc_and_vtg() {
+        assert_eq!(speed(RMC), Some(Some(22.4 * KMH_PER_KNOT)));
+        assert_eq!(
+            speed("$GNRMC,083559.00,A,4717.11437,N,00833.91522,E,0.004,77.52,091202,,,A*49"),
+            Some(Some(0.004 * KMH_PER_KNOT))
+        );
+        assert_eq!(
+            speed("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K,A*25"),
+            Some(Some(10.2))
+        );
+    }
+
+    #[test]
+    fn no_fix_sentences() {
+        assert_eq!(speed(RMC_NO_FIX), Some(None));
+        assert_eq!(speed("$GPVTG,,,,,,,,,N*30"), Some(None));
+    }
+
+    #[test]
+    fn other_or_garbled_sentences_are_ignored() {
+        assert_eq!(
+            speed("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"),
+            None
+        );
+        assert_eq!(
+            speed("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6B"),
+            None
+        );
+        assert_eq!(speed("GPRMC,123519,A"), None);
+    }
+
+    fn gauge(id: &str) -> GaugeConfig {
+        return toml::from_str(&format!(
+            "id = \"{}\"\nname = \"SPEED\"\nunits = \"km/h\"\nformat = \"%.0f\"\nmin = 0.0\nmax = 200.0\nlow_value = 0.0\nhigh_value = 200.0",
+            id
+        ))
+        .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn speed_gauge_goes_offline_without_a_fix() {
+        let (mut module, port) = serialport::TTYPort::pair().expect("pty pair");
+        let mut source = GpsSource {
+            port: Box::new(port),
+            buffer: Vec::new(),
+            fix: None,
+        };
+        let speed = gauge("speed");
+
+        source.refresh();
+        assert_eq!(source.read(0, 0, &speed), Ok(GaugeData::OFFLINE_VALUE));
+
+        module.write_all(RMC.as_bytes()).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        source.refresh();
+        assert_eq!(source.read(0, 0, &speed), Ok(22.4 * KMH_PER_KNOT));
+        assert_eq!(
+            source.read(0, 0, &gauge("speed_mph")),
+            Ok(22.4 * KMH_PER_KNOT * MPH_PER_KMH)
+        );
+        assert_eq!(
+            source.read(0, 0, &gauge("rpm")),
+            Ok(GaugeData::OFFLINE_VALUE)
+        );
+
+        module.write_all(RMC_NO_FIX.as_bytes()).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        source.refresh();
+        assert_eq!(source.read(0, 0, &speed), Ok(GaugeData::OFFLINE_VALUE));
+    }
+}
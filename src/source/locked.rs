@@ -0,0 +1,35 @@
+use std::sync::Mutex;
+
+use crate::dto::dto::GaugeConfig;
+
+use super::DataSource;
+
+/// One source shared by the workers serving several ports. Every call
+/// locks it, so each worker reads whole values, however the calls of the
+/// workers interleave.
+pub struct LockedSource<'a, 'b> {
+    inner: &'a Mutex<&'b mut dyn DataSource>,
+}
+
+impl<'a, 'b> LockedSource<'a, 'b> {
+    pub fn new(inner: &'a Mutex<&'b mut dyn DataSource>) -> LockedSource<'a, 'b> {
+        return LockedSource { inner };
+    }
+}
+
+impl DataSource for LockedSource<'_, '_> {
+    fn refresh(&mut self) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .refresh();
+    }
+
+    fn read(&mut self, display: usize, index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        return self
+            .inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .read(display, index, gauge);
+    }
+}
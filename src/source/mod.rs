@@ -0,0 +1,328 @@
+//! Where gauge values come from.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use log::warn;
+
+use crate::clock::SystemClock;
+use crate::dto::dto::{Configuration, Data, DisplayData, GaugeConfig, GaugeData};
+use crate::settings::Settings;
+
+mod cached;
+#[cfg(all(feature = "can", target_os = "linux"))]
+mod can;
+mod can_signal;
+#[cfg(feature = "gps")]
+mod gps;
+mod locked;
+#[cfg(feature = "obd2")]
+mod obd2;
+mod offline;
+mod random;
+#[cfg(unix)]
+mod socket;
+#[cfg(feature = "system-sensors")]
+mod system;
+
+pub use cached::CachedSource;
+#[cfg(all(feature = "can", target_os = "linux"))]
+pub use can::CanSource;
+pub use can_signal::CanSignal;
+#[cfg(feature = "gps")]
+pub use gps::GpsSource;
+pub use locked::LockedSource;
+#[cfg(feature = "obd2")]
+pub use obd2::Obd2Source;
+pub use offline::OfflineSource;
+pub use random::RandomSource;
+#[cfg(unix)]
+pub use socket::SocketSource;
+#[cfg(feature = "system-sensors")]
+pub use system::SystemSensorSource;
+
+/// `Send` so a source can be shared by the workers of several ports.
+pub trait DataSource: Send {
+    /// Called once per `Data` message, before any gauge is read.
+    fn refresh(&mut self) {}
+
+    /// Current value of the `index`-th gauge on the `display`-th display.
+    /// A failed read only takes that gauge offline.
+    fn read(&mut self, display: usize, index: usize, gauge: &GaugeConfig) -> Result<f32, String>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// Random values within each gauge's range.
+    Random,
+    /// Every gauge offline, for running without sensors.
+    Offline,
+    /// Host CPU temperature, CPU load and memory use.
+    System,
+    /// Engine data from an ELM327-style OBD-II adapter.
+    Obd2,
+    /// Engine data broadcast on a SocketCAN interface.
+    Can,
+    /// Ground speed from a serial NMEA GPS module.
+    Gps,
+    /// `Data` messages written to a Unix domain socket by another process.
+    Socket,
+}
+
+/// The configured source, reading each gauge at its own `update_ms`, with
+/// `predict` extrapolating in between. With
+/// `safe_mode`, a source that can't be opened leaves every gauge offline
+/// rather than stopping the backend.
+pub fn create(settings: &Settings) -> Result<Box<dyn DataSource>, String> {
+    let source = match open(settings) {
+        Ok(source) => source,
+        Err(error) if settings.safe_mode => {
+            warn!(
+                "Failed to create data source: {}; Showing gauges offline",
+                error
+            );
+            Box::new(OfflineSource)
+        }
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    return Ok(Box::new(CachedSource::new(
+        source,
+        settings.predict,
+        Arc::new(SystemClock),
+    )));
+}
+
+fn open(settings: &Settings) -> Result<Box<dyn DataSource>, String> {
+    match settings.source {
+        SourceKind::Offline => {
+            return Ok(Box::new(OfflineSource));
+        }
+        SourceKind::Random => {
+            return Ok(Box::new(RandomSource::new(
+                settings.random_noise,
+                settings.random_seed,
+            )));
+        }
+        #[cfg(feature = "system-sensors")]
+        SourceKind::System => {
+            return Ok(Box::new(SystemSensorSource::new()));
+        }
+        #[cfg(not(feature = "system-sensors"))]
+        SourceKind::System => {
+            return Err(String::from(
+                "the system source requires building with the system-sensors feature",
+            ));
+        }
+        #[cfg(feature = "obd2")]
+        SourceKind::Obd2 => {
+            let Some(port_name) = &settings.obd2_port else {
+                return Err(String::from("the obd2 source requires obd2_port to be set"));
+            };
+
+            let source = Obd2Source::new(port_name, settings.obd2_baud_rate, settings.timeout())?;
+            return Ok(Box::new(source));
+        }
+        #[cfg(not(feature = "obd2"))]
+        SourceKind::Obd2 => {
+            return Err(String::from(
+                "the obd2 source requires building with the obd2 feature",
+            ));
+        }
+        #[cfg(all(feature = "can", target_os = "linux"))]
+        SourceKind::Can => {
+            let source = CanSource::new(
+                &settings.can_interface,
+                settings.can_signals.clone(),
+                settings.can_stale(),
+            )?;
+            return Ok(Box::new(source));
+        }
+        #[cfg(not(all(feature = "can", target_os = "linux")))]
+        SourceKind::Can => {
+            return Err(String::from(
+                "the can source requires Linux and building with the can feature",
+            ));
+        }
+        #[cfg(feature = "gps")]
+        SourceKind::Gps => {
+            let Some(port_name) = &settings.gps_port else {
+                return Err(String::from("the gps source requires gps_port to be set"));
+            };
+
+            let source = GpsSource::new(port_name, settings.gps_baud_rate, settings.timeout())?;
+            return Ok(Box::new(source));
+        }
+        #[cfg(not(feature = "gps"))]
+        SourceKind::Gps => {
+            return Err(String::from(
+                "the gps source requires building with the gps feature",
+            ));
+        }
+        #[cfg(unix)]
+        SourceKind::Socket => {
+            return Ok(Box::new(SocketSource::new(&settings.socket_path)?));
+        }
+        #[cfg(not(unix))]
+        SourceKind::Socket => {
+            return Err(String::from("the socket source requires a Unix system"));
+        }
+    }
+}
+
+/// Reads every configured gauge into a `Data` message. Gauges that fail
+/// to read are sent as offline, so the others keep updating.
+pub fn sample(source: &mut dyn DataSource, configuration: &Configuration) -> Data {
+    source.refresh();
+
+    let displays = configuration
+        .displays
+        .iter()
+        .enumerate()
+        .map(|(display_index, display)| DisplayData {
+            gauges: display
+                .gauges
+                .iter()
+                .enumerate()
+                .map(|(index, gauge)| {
+                    if gauge.hidden {
+                        return GaugeData::new(GaugeData::OFFLINE_VALUE);
+                    }
+
+                    match source.read(display_index, index, gauge) {
+                        Ok(value) => {
+                            return GaugeData::new(gauge.displayed_value(value));
+                        }
+                        Err(error) => {
+                            warn!("Failed to read gauge {}: {}", gauge.id, error);
+                            return GaugeData::new(GaugeData::OFFLINE_VALUE);
+                        }
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    return Data {
+        displays,
+        timestamp: None,
+        tween_ms: None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One display with a 0-100 gauge for each of `ids`.
+    fn configuration(ids: &[&str]) -> Configuration {
+        let gauges: String = ids
+            .iter()
+            .map(|id| {
+                format!(
+                    "[[display1.gauges]]\nid = \"{}\"\nname = \"G\"\nunits = \"%\"\nformat = \"%.0f\"\nmin = 0.0\nmax = 100.0\nlow_value = 10.0\nhigh_value = 90.0\n",
+                    id
+                )
+            })
+            .collect();
+        return toml::from_str(&gauges).unwrap();
+    }
+
+    fn values(data: &Data) -> Vec<f32> {
+        return data.displays[0]
+            .gauges
+            .iter()
+            .map(|gauge| gauge.current_value)
+            .collect();
+    }
+
+    /// Reads 42 for every gauge but `broken`.
+    struct OneBroken {
+        broken: &'static str,
+    }
+
+    impl DataSource for OneBroken {
+        fn read(
+            &mut self,
+            _display: usize,
+            _index: usize,
+            gauge: &GaugeConfig,
+        ) -> Result<f32, String> {
+            if gauge.id == self.broken {
+                return Err(String::from("sensor unplugged"));
+            }
+            return Ok(42.0);
+        }
+    }
+
+    #[test]
+    fn failed_read_takes_only_its_gauge_offline() {
+        let configuration = configuration(&["coolant", "oil", "boost"]);
+        let mut source = OneBroken { broken: "oil" };
+
+        let mut data = None;
+        let logged = crate::test_log::capture(|| data = Some(sample(&mut source, &configuration)));
+
+        assert_eq!(
+            values(&data.unwrap()),
+            [42.0, GaugeData::OFFLINE_VALUE, 42.0]
+        );
+        assert_eq!(
+            logged,
+            [(
+                log::Level::Warn,
+                String::from("Failed to read gauge oil: sensor unplugged")
+            )]
+        );
+    }
+
+    /// Values of the first sample from the source `settings` create.
+    fn first_sample(settings: &Settings) -> Result<Vec<f32>, String> {
+        let mut source = create(settings)?;
+        return Ok(values(&sample(
+            source.as_mut(),
+            &configuration(&["oil", "rpm"]),
+        )));
+    }
+
+    #[test]
+    fn offline_source_reads_every_gauge_offline() {
+        let settings = Settings {
+            source: SourceKind::Offline,
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            first_sample(&settings),
+            Ok(vec![GaugeData::OFFLINE_VALUE; 2])
+        );
+    }
+
+    #[test]
+    fn safe_mode_shows_an_unavailable_source_offline() {
+        // No OBD-II adapter configured.
+        let settings = Settings {
+            source: SourceKind::Obd2,
+            obd2_port: None,
+            ..Settings::default()
+        };
+        assert!(first_sample(&settings).is_err());
+
+        let safe = Settings {
+            safe_mode: true,
+            ..settings
+        };
+        let mut sampled = Ok(Vec::new());
+        let logged = crate::test_log::capture(|| sampled = first_sample(&safe));
+
+        assert_eq!(sampled, Ok(vec![GaugeData::OFFLINE_VALUE; 2]));
+        assert!(logged
+            .iter()
+            .any(|(level, message)| *level == log::Level::Warn
+                && message.ends_with("; Showing gauges offline")));
+    }
+}
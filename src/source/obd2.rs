@@ -0,0 +1,331 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::dto::dto::{GaugeConfig, GaugeData};
+
+use super::DataSource;
+
+/// Prompt the adapter prints once it is ready for the next command.
+const PROMPT: u8 = b'>';
+
+/// Commands sent after opening the adapter: reset, then echo, linefeeds,
+/// spaces and headers off, then automatic protocol detection.
+const INIT_COMMANDS: [&str; 6] = ["ATZ", "ATE0", "ATL0", "ATS0", "ATH0", "ATSP0"];
+
+/// Mode 01 PID behind a gauge, picked by the gauge's `id`.
+struct Pid {
+    id: &'static str,
+    pid: u8,
+    decode: fn(&[u8]) -> Option<f32>,
+}
+
+const PIDS: [Pid; 12] = [
+    Pid {
+        id: "load",
+        pid: 0x04,
+        decode: percent,
+    },
+    Pid {
+        id: "coolant",
+        pid: 0x05,
+        decode: temperature,
+    },
+    Pid {
+        id: "map",
+        pid: 0x0B,
+        decode: single,
+    },
+    Pid {
+        id: "rpm",
+        pid: 0x0C,
+        decode: rpm,
+    },
+    Pid {
+        id: "speed",
+        pid: 0x0D,
+        decode: single,
+    },
+    Pid {
+        id: "intake",
+        pid: 0x0F,
+        decode: temperature,
+    },
+    Pid {
+        id: "maf",
+        pid: 0x10,
+        decode: maf,
+    },
+    Pid {
+        id: "throttle",
+        pid: 0x11,
+        decode: percent,
+    },
+    Pid {
+        id: "fuel",
+        pid: 0x2F,
+        decode: percent,
+    },
+    Pid {
+        id: "voltage",
+        pid: 0x42,
+        decode: voltage,
+    },
+    Pid {
+        id: "ambient",
+        pid: 0x46,
+        decode: temperature,
+    },
+    Pid {
+        id: "oil",
+        pid: 0x5C,
+        decode: temperature,
+    },
+];
+
+fn single(bytes: &[u8]) -> Option<f32> {
+    return bytes.first().map(|a| *a as f32);
+}
+
+fn double(bytes: &[u8]) -> Option<f32> {
+    match bytes {
+        [a, b, ..] => {
+            return Some((*a as f32) * 256.0 + *b as f32);
+        }
+        _ => {
+            return None;
+        }
+    }
+}
+
+fn percent(bytes: &[u8]) -> Option<f32> {
+    return single(bytes).map(|a| a * 100.0 / 255.0);
+}
+
+fn temperature(bytes: &[u8]) -> Option<f32> {
+    return single(bytes).map(|a| a - 40.0);
+}
+
+fn rpm(bytes: &[u8]) -> Option<f32> {
+    return double(bytes).map(|ab| ab / 4.0);
+}
+
+fn maf(bytes: &[u8]) -> Option<f32> {
+    return double(bytes).map(|ab| ab / 100.0);
+}
+
+fn voltage(bytes: &[u8]) -> Option<f32> {
+    return double(bytes).map(|ab| ab / 1000.0);
+}
+
+/// What the adapter answered to a PID query.
+enum Response {
+    Value(f32),
+    /// The car doesn't report this PID, so it isn't asked again.
+    Unsupported,
+    /// Garbled or missing answer; worth asking again next time.
+    Invalid,
+}
+
+/// Decodes an adapter reply such as `"41 05 7B\r\r>"` for the given PID.
+fn decode_response(pid: &Pid, reply: &str) -> Response {
+    let hex: String = reply
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '?')
+        .collect::<String>()
+        .to_ascii_uppercase();
+
+    if hex.contains("NODATA") || hex.contains('?') || hex.contains("UNABLETOCONNECT") {
+        return Response::Unsupported;
+    }
+
+    let header = format!("41{:02X}", pid.pid);
+    let Some(start) = hex.find(&header) else {
+        return Response::Invalid;
+    };
+
+    let payload = &hex[start + header.len()..];
+    let bytes: Vec<u8> = payload
+        .as_bytes()
+        .chunks_exact(2)
+        .map_while(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect();
+
+    match (pid.decode)(&bytes) {
+        Some(value) => {
+            return Response::Value(value);
+        }
+        None => {
+            return Response::Invalid;
+        }
+    }
+}
+
+/// Live engine data from an ELM327-style OBD-II adapter on its own serial
+/// port. Gauges are matched to PIDs by `id` (`coolant`, `rpm`, `speed`,
+/// ...); gauges without a PID, or whose PID the car doesn't support, read
+/// as offline.
+pub struct Obd2Source {
+    port: Box<dyn serialport::SerialPort>,
+    unsupported: HashSet<u8>,
+}
+
+impl Obd2Source {
+    pub fn new(port_name: &str, baud_rate: u32, timeout: Duration) -> Result<Obd2Source, String> {
+        let port = match serialport::new(port_name, baud_rate)
+            .timeout(timeout)
+            .open()
+        {
+            Ok(port) => port,
+            Err(error) => {
+                return Err(format!(
+                    "failed to open OBD-II adapter {}: {}",
+                    port_name, error
+                ));
+            }
+        };
+
+        let mut source = Obd2Source {
+            port,
+            unsupported: HashSet::new(),
+        };
+
+        for command in INIT_COMMANDS {
+            if let Err(error) = source.command(command) {
+                return Err(format!(
+                    "OBD-II adapter did not answer {}: {}",
+                    command, error
+                ));
+            }
+        }
+
+        info!("OBD-II adapter {} initialized", port_name);
+        return Ok(source);
+    }
+
+    /// Sends one command and collects the reply up to the prompt.
+    fn command(&mut self, command: &str) -> std::io::Result<String> {
+        self.port.write_all(command.as_bytes())?;
+        self.port.write_all(b"\r")?;
+
+        let mut reply = Vec::new();
+        let mut byte = [0u8; 1];
+
+        loop {
+            match self.port.read(&mut byte) {
+                Ok(0) => {
+                    break;
+                }
+                Ok(_) if byte[0] == PROMPT => {
+                    break;
+                }
+                Ok(_) => reply.push(byte[0]),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        let reply = String::from_utf8_lossy(&reply).into_owned();
+        debug!("OBD-II {} -> {:?}", command, reply);
+        return Ok(reply);
+    }
+
+    fn query(&mut self, pid: &Pid) -> Result<f32, String> {
+        // Reported once when first found, offline without noise after.
+        if self.unsupported.contains(&pid.pid) {
+            return Ok(GaugeData::OFFLINE_VALUE);
+        }
+
+        let reply = match self.command(&format!("01{:02X}", pid.pid)) {
+            Ok(reply) => reply,
+            Err(error) => {
+                return Err(format!("no OBD-II response: {}", error));
+            }
+        };
+
+        match decode_response(pid, &reply) {
+            Response::Value(value) => {
+                return Ok(value);
+            }
+            Response::Unsupported => {
+                self.unsupported.insert(pid.pid);
+                return Err(format!("PID {:02X} is not supported by the car", pid.pid));
+            }
+            Response::Invalid => {
+                return Err(format!("unexpected OBD-II response {:?}", reply));
+            }
+        }
+    }
+}
+
+impl DataSource for Obd2Source {
+    fn read(&mut self, _display: usize, _index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        match PIDS.iter().find(|pid| pid.id == gauge.id) {
+            Some(pid) => {
+                return self.query(pid);
+            }
+            None => {
+                return Ok(GaugeData::OFFLINE_VALUE);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(id: &str) -> &'static Pid {
+        return PIDS.iter().find(|pid| pid.id == id).unwrap();
+    }
+
+    fn value(id: &str, reply: &str) -> f32 {
+        match decode_response(pid(id), reply) {
+            Response::Value(value) => {
+                return value;
+            }
+            _ => {
+                panic!("{:?} did not decode as {}", reply, id);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_sample_replies() {
+        assert_eq!(value("coolant", "41 05 7B\r\r>"), 83.0);
+        assert_eq!(value("rpm", "41 0C 1A F8\r\r"), 1726.0);
+        assert_eq!(value("speed", "410D3C"), 60.0);
+        assert_eq!(value("throttle", "41 11 FF"), 100.0);
+        assert_eq!(value("maf", "41 10 01 F4"), 5.0);
+        assert_eq!(value("voltage", "41 42 36 B0"), 14.0);
+        assert_eq!(value("intake", "41 0f 28"), 0.0);
+    }
+
+    #[test]
+    fn skips_echo_and_searching_lines() {
+        assert_eq!(value("coolant", "0105\rSEARCHING...\r41 05 5A\r"), 50.0);
+    }
+
+    #[test]
+    fn unsupported_and_garbled_replies() {
+        assert!(matches!(
+            decode_response(pid("oil"), "NO DATA\r\r>"),
+            Response::Unsupported
+        ));
+        assert!(matches!(
+            decode_response(pid("rpm"), "?\r>"),
+            Response::Unsupported
+        ));
+        assert!(matches!(
+            decode_response(pid("rpm"), "41 0C 1A"),
+            Response::Invalid
+        ));
+        assert!(matches!(
+            decode_response(pid("rpm"), "41 05 7B"),
+            Response::Invalid
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+use crate::dto::dto::{GaugeConfig, GaugeData};
+
+use super::DataSource;
+
+/// Safe mode: every gauge offline, so the firmware shows that no sensor
+/// is available instead of made-up values.
+pub struct OfflineSource;
+
+impl DataSource for OfflineSource {
+    fn read(
+        &mut self,
+        _display: usize,
+        _index: usize,
+        _gauge: &GaugeConfig,
+    ) -> Result<f32, String> {
+        return Ok(GaugeData::OFFLINE_VALUE);
+    }
+}
@@ -0,0 +1,96 @@
+use rand::prelude::*;
+
+use crate::dto::dto::GaugeConfig;
+
+use super::DataSource;
+
+/// Point of each gauge's range the random values move around.
+const BASE: f32 = 0.5;
+
+/// Demo source moving every gauge to the same random point of its range,
+/// within `noise` (a fraction of the range) around mid-scale. A noise of 0
+/// holds the needles still; 1 swings them across the whole range.
+pub struct RandomSource {
+    noise: f32,
+    factor: f32,
+    rng: StdRng,
+}
+
+impl RandomSource {
+    /// With a `seed`, the same sequence of values on every run.
+    pub fn new(noise: f32, seed: Option<u64>) -> RandomSource {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        return RandomSource {
+            noise: noise.clamp(0.0, 1.0),
+            factor: BASE,
+            rng,
+        };
+    }
+}
+
+impl DataSource for RandomSource {
+    fn refresh(&mut self) {
+        let offset = self.rng.gen::<f32>() - 0.5;
+        self.factor = (BASE + offset * self.noise).clamp(0.0, 1.0);
+    }
+
+    fn read(&mut self, _display: usize, _index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        let (min, max) = gauge.measured_range();
+        return Ok(min + (max - min) * self.factor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 0-130 gauge, so mid-scale is 65.
+    fn gauge() -> GaugeConfig {
+        return toml::from_str(
+            r#"
+            id = "coolant"
+            name = "COOLANT"
+            units = "C"
+            format = "%.0f"
+            min = 0.0
+            max = 130.0
+            low_value = 60.0
+            high_value = 100.0
+            "#,
+        )
+        .unwrap();
+    }
+
+    /// Values read over `count` refreshes.
+    fn readings(source: &mut RandomSource, count: usize) -> Vec<f32> {
+        let gauge = gauge();
+        return (0..count)
+            .map(|_| {
+                source.refresh();
+                source.read(0, 0, &gauge).unwrap()
+            })
+            .collect();
+    }
+
+    #[test]
+    fn zero_noise_holds_the_value() {
+        let mut source = RandomSource::new(0.0, Some(7));
+
+        assert!(readings(&mut source, 50).iter().all(|value| *value == 65.0));
+    }
+
+    #[test]
+    fn noise_stays_within_its_share_of_the_range() {
+        let mut source = RandomSource::new(0.2, Some(7));
+
+        let values = readings(&mut source, 200);
+
+        // 0.2 of the range is 26, so 13 either side of mid-scale.
+        assert!(values.iter().all(|value| (52.0..=78.0).contains(value)));
+        assert!(values.iter().any(|value| *value != values[0]));
+    }
+}
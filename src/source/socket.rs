@@ -0,0 +1,207 @@
+use std::io::{ErrorKind, Read};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::dto::dto::{Data, GaugeConfig, GaugeData};
+use crate::transport::MESSAGE_END_BYTE;
+
+use super::DataSource;
+
+/// Values pushed by another local process, as newline-delimited JSON
+/// `Data` messages (the same shape the firmware receives) written to a
+/// Unix domain socket. One writer at a time; gauges read as offline until
+/// it sends its first message and again once it disconnects.
+pub struct SocketSource {
+    path: PathBuf,
+    listener: UnixListener,
+    client: Option<UnixStream>,
+    buffer: Vec<u8>,
+    latest: Option<Data>,
+}
+
+impl SocketSource {
+    pub fn new(path: &Path) -> Result<SocketSource, String> {
+        // A socket file left behind by a previous run would fail the bind.
+        if path.exists() {
+            if let Err(error) = std::fs::remove_file(path) {
+                return Err(format!(
+                    "failed to remove stale socket {}: {}",
+                    path.display(),
+                    error
+                ));
+            }
+        }
+
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(error) => {
+                return Err(format!("failed to bind {}: {}", path.display(), error));
+            }
+        };
+
+        if let Err(error) = listener.set_nonblocking(true) {
+            return Err(format!("failed to configure {}: {}", path.display(), error));
+        }
+
+        info!("Listening for data on {}", path.display());
+        return Ok(SocketSource {
+            path: path.to_path_buf(),
+            listener,
+            client: None,
+            buffer: Vec::new(),
+            latest: None,
+        });
+    }
+
+    fn accept(&mut self) {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(error) = stream.set_nonblocking(true) {
+                    warn!("Failed to configure data socket client: {}", error);
+                    return;
+                }
+                info!("Data socket client connected");
+                self.client = Some(stream);
+                self.buffer.clear();
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(error) => warn!("Failed to accept data socket client: {}", error),
+        }
+    }
+
+    /// Reads whatever the client sent, keeping the newest complete message.
+    fn receive(&mut self) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+
+        let mut chunk = [0; 1024];
+        loop {
+            match client.read(&mut chunk) {
+                Ok(0) => {
+                    info!("Data socket client disconnected");
+                    self.client = None;
+                    self.latest = None;
+                    break;
+                }
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    warn!("Data socket client failed: {}", error);
+                    self.client = None;
+                    self.latest = None;
+                    break;
+                }
+            }
+        }
+
+        while let Some(end) = self
+            .buffer
+            .iter()
+            .position(|byte| *byte == MESSAGE_END_BYTE)
+        {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+
+            match serde_json::from_slice::<Data>(&line) {
+                Ok(data) => self.latest = Some(data),
+                Err(error) => warn!("Ignoring malformed data from socket: {}", error),
+            }
+        }
+    }
+}
+
+impl Drop for SocketSource {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl DataSource for SocketSource {
+    fn refresh(&mut self) {
+        if self.client.is_none() {
+            self.accept();
+        }
+        self.receive();
+    }
+
+    fn read(&mut self, display: usize, index: usize, _gauge: &GaugeConfig) -> Result<f32, String> {
+        let value = self
+            .latest
+            .as_ref()
+            .and_then(|data| data.displays.get(display))
+            .and_then(|display| display.gauges.get(index))
+            .map(|gauge| gauge.current_value);
+
+        return Ok(value.unwrap_or(GaugeData::OFFLINE_VALUE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn gauge() -> GaugeConfig {
+        return toml::from_str(
+            "id = \"oil\"\nname = \"OIL\"\nunits = \"bar\"\nformat = \"%.1f\"\nmin = 0.0\nmax = 10.0\nlow_value = 1.0\nhigh_value = 8.0",
+        )
+        .unwrap();
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("gauges-{}-{}.sock", std::process::id(), name));
+    }
+
+    /// Refreshes until the gauge at `display`, `index` reads other than
+    /// `previous`, or a second has passed.
+    fn next_value(source: &mut SocketSource, display: usize, index: usize, previous: f32) -> f32 {
+        let gauge = gauge();
+        let deadline = Instant::now() + Duration::from_secs(1);
+
+        loop {
+            source.refresh();
+            let value = source.read(display, index, &gauge).unwrap();
+            if value != previous || Instant::now() > deadline {
+                return value;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn data_sent_to_the_socket_is_read() {
+        let path = socket_path("data");
+        let mut source = SocketSource::new(&path).unwrap();
+        let offline = GaugeData::OFFLINE_VALUE;
+        assert_eq!(source.read(0, 0, &gauge()).unwrap(), offline);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"{\"display1\":{\"gauges\":[]},\"display2\":{\"gauges\":[{\"current_value\":4.5}]}}\n")
+            .unwrap();
+
+        assert_eq!(next_value(&mut source, 1, 0, offline), 4.5);
+        // Not in the message.
+        assert_eq!(source.read(0, 0, &gauge()).unwrap(), offline);
+
+        // Malformed lines leave the last values in place.
+        client
+            .write_all(b"not json\n{\"display1\":{\"gauges\":[]},\"display2\":{\"gauges\":[{\"current_value\":")
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        source.refresh();
+        assert_eq!(source.read(1, 0, &gauge()).unwrap(), 4.5);
+        client.write_all(b"6.0}]}}\n").unwrap();
+        assert_eq!(next_value(&mut source, 1, 0, 4.5), 6.0);
+
+        drop(client);
+        assert_eq!(next_value(&mut source, 1, 0, 6.0), offline);
+
+        drop(source);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,126 @@
+use sysinfo::{Components, System};
+
+use crate::dto::dto::GaugeConfig;
+
+use super::DataSource;
+
+/// Host metrics, each as a fraction of its full scale.
+#[derive(Clone, Copy, Default)]
+struct Metrics {
+    /// None on hosts without a readable temperature sensor.
+    cpu_temperature: Option<f32>,
+    cpu_load: f32,
+    memory_used: f32,
+}
+
+/// Drives the gauges from host sensors for bench testing without a car:
+/// each gauge shows CPU temperature, CPU load or memory use, in turn by
+/// position, scaled onto the gauge's own range.
+pub struct SystemSensorSource {
+    system: System,
+    components: Components,
+    metrics: Metrics,
+}
+
+/// Temperature shown as a full gauge.
+const MAX_CPU_TEMPERATURE: f32 = 100.0;
+
+impl SystemSensorSource {
+    pub fn new() -> SystemSensorSource {
+        return SystemSensorSource {
+            system: System::new(),
+            components: Components::new_with_refreshed_list(),
+            metrics: Metrics::default(),
+        };
+    }
+}
+
+impl DataSource for SystemSensorSource {
+    fn refresh(&mut self) {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        self.components.refresh(false);
+
+        let cpu_temperature = self
+            .components
+            .iter()
+            .filter_map(|component| component.temperature())
+            .reduce(f32::max);
+        let total_memory = self.system.total_memory().max(1) as f32;
+
+        self.metrics = Metrics {
+            cpu_temperature: cpu_temperature.map(|temperature| temperature / MAX_CPU_TEMPERATURE),
+            cpu_load: self.system.global_cpu_usage() / 100.0,
+            memory_used: self.system.used_memory() as f32 / total_memory,
+        };
+    }
+
+    fn read(&mut self, display: usize, index: usize, gauge: &GaugeConfig) -> Result<f32, String> {
+        let fraction = match (display + index) % 3 {
+            0 => match self.metrics.cpu_temperature {
+                Some(temperature) => temperature,
+                None => {
+                    return Err(String::from("no CPU temperature sensor found"));
+                }
+            },
+            1 => self.metrics.cpu_load,
+            _ => self.metrics.memory_used,
+        };
+
+        let (min, max) = gauge.measured_range();
+        return Ok(min + (max - min) * fraction.clamp(0.0, 1.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dto::dto::GaugeData;
+    use crate::source;
+
+    #[test]
+    fn readings_stay_within_each_gauge_range() {
+        let configuration = crate::default_configuration();
+        let mut source = SystemSensorSource::new();
+
+        // CPU load is only known from the second refresh on.
+        source::sample(&mut source, &configuration);
+        let data = source::sample(&mut source, &configuration);
+
+        for (display, display_data) in configuration.displays.iter().zip(&data.displays) {
+            for (gauge, value) in display.gauges.iter().zip(&display_data.gauges) {
+                // Hosts without a temperature sensor show it offline.
+                if value.current_value == GaugeData::OFFLINE_VALUE {
+                    continue;
+                }
+                assert!(
+                    (gauge.min..=gauge.max).contains(&value.current_value),
+                    "{} read {} outside {}..{}",
+                    gauge.id,
+                    value.current_value,
+                    gauge.min,
+                    gauge.max
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn metrics_are_scaled_onto_the_gauge_range() {
+        let gauge = &crate::default_configuration().displays[0].gauges[0];
+        let mut source = SystemSensorSource::new();
+        source.metrics = Metrics {
+            cpu_temperature: None,
+            cpu_load: 0.5,
+            memory_used: 1.5,
+        };
+
+        assert!(source.read(0, 0, gauge).is_err());
+        assert_eq!(
+            source.read(0, 1, gauge),
+            Ok(gauge.min + (gauge.max - gauge.min) * 0.5)
+        );
+        // Clamped to a full gauge.
+        assert_eq!(source.read(0, 2, gauge), Ok(gauge.max));
+    }
+}
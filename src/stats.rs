@@ -0,0 +1,152 @@
+//! Running totals for the periodic status report, updated from wherever
+//! the events happen and logged from the serial loop.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+struct Stats {
+    started: Option<Instant>,
+    reported: Option<Instant>,
+    messages_in: u64,
+    messages_out: u64,
+    reconnects: u32,
+    last_error: Option<String>,
+}
+
+impl Stats {
+    const fn new() -> Stats {
+        return Stats {
+            started: None,
+            reported: None,
+            messages_in: 0,
+            messages_out: 0,
+            reconnects: 0,
+            last_error: None,
+        };
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.started = Some(now);
+        self.reported = Some(now);
+    }
+
+    /// The totals, if `interval` has passed since the last report.
+    fn report(&mut self, interval: Duration, now: Instant) -> Option<String> {
+        let (Some(started), Some(reported)) = (self.started, self.reported) else {
+            return None;
+        };
+
+        if now.duration_since(reported) < interval {
+            return None;
+        }
+        self.reported = Some(now);
+
+        return Some(format!(
+            "Up {}s, {} reconnects, {} messages in, {} out, last error: {}",
+            now.duration_since(started).as_secs(),
+            self.reconnects,
+            self.messages_in,
+            self.messages_out,
+            self.last_error.as_deref().unwrap_or("none")
+        ));
+    }
+}
+
+static STATS: Mutex<Stats> = Mutex::new(Stats::new());
+
+fn update(change: impl FnOnce(&mut Stats)) {
+    let mut stats = STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    change(&mut stats);
+}
+
+/// Starts the uptime clock.
+pub fn start() {
+    update(|stats| stats.start(Instant::now()));
+}
+
+pub fn message_in() {
+    update(|stats| stats.messages_in += 1);
+}
+
+pub fn message_out() {
+    update(|stats| stats.messages_out += 1);
+}
+
+pub fn reconnect() {
+    update(|stats| stats.reconnects += 1);
+}
+
+pub fn error(error: &dyn std::fmt::Display) {
+    update(|stats| stats.last_error = Some(error.to_string()));
+}
+
+/// Logs the totals if `interval` has passed since the last report.
+pub fn report_if_due(interval: Option<Duration>) {
+    let Some(interval) = interval else {
+        return;
+    };
+
+    update(|stats| {
+        if let Some(summary) = stats.report(interval, Instant::now()) {
+            info!("{}", summary);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_reflects_the_activity_so_far() {
+        let started = Instant::now();
+        let interval = Duration::from_secs(60);
+        let mut stats = Stats::new();
+        assert_eq!(stats.report(interval, started), None);
+
+        stats.start(started);
+        stats.messages_in += 5;
+        stats.messages_out += 7;
+        stats.reconnects += 2;
+        stats.last_error = Some(String::from("IO error: broken pipe"));
+
+        assert_eq!(
+            stats.report(interval, started + Duration::from_secs(30)),
+            None
+        );
+        assert_eq!(
+            stats
+                .report(interval, started + Duration::from_secs(90))
+                .as_deref(),
+            Some("Up 90s, 2 reconnects, 5 messages in, 7 out, last error: IO error: broken pipe")
+        );
+        // Next one a whole interval after that.
+        assert_eq!(
+            stats.report(interval, started + Duration::from_secs(120)),
+            None
+        );
+        stats.messages_in += 1;
+        assert_eq!(
+            stats
+                .report(interval, started + Duration::from_secs(150))
+                .as_deref(),
+            Some("Up 150s, 2 reconnects, 6 messages in, 7 out, last error: IO error: broken pipe")
+        );
+    }
+
+    #[test]
+    fn summary_without_errors_says_none() {
+        let started = Instant::now();
+        let mut stats = Stats::new();
+        stats.start(started);
+
+        assert_eq!(
+            stats.report(Duration::ZERO, started).as_deref(),
+            Some("Up 0s, 0 reconnects, 0 messages in, 0 out, last error: none")
+        );
+    }
+}
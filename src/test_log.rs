@@ -0,0 +1,45 @@
+//! Log lines captured per thread, so tests can check what was logged and
+//! at which level while other tests log concurrently.
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+use log::{Level, Log, Metadata, Record};
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+}
+
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        return true;
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED.with(|captured| {
+            if let Some(lines) = captured.borrow_mut().as_mut() {
+                lines.push((record.level(), record.args().to_string()));
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+static INSTALL: Once = Once::new();
+
+/// Runs `f`, returning what it logged on this thread.
+pub fn capture(f: impl FnOnce()) -> Vec<(Level, String)> {
+    INSTALL.call_once(|| {
+        log::set_logger(&LOGGER).expect("no other logger in tests");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    f();
+
+    return CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default());
+}
@@ -0,0 +1,234 @@
+//! Newline-delimited framing of the serial byte stream.
+
+use std::io::{ErrorKind, Read};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::dto::dto::InMessage;
+use crate::Error;
+
+pub const MESSAGE_END_BYTE: u8 = b'\n';
+
+/// Consecutive zero-byte reads taken to mean the device is gone. An
+/// unplugged USB serial device keeps reporting end of file instead of
+/// failing the read.
+const MAX_EMPTY_READS: u32 = 10;
+
+/// Splits a byte stream into newline-terminated frames, keeping partial
+/// frames across reads that time out.
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    /// Whether a delimiter has been seen yet. Bytes before the first one
+    /// are the tail of a frame sent before we connected and are dropped.
+    synchronized: bool,
+    empty_reads: u32,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        return FrameReader {
+            buffer: Vec::new(),
+            synchronized: false,
+            empty_reads: 0,
+        };
+    }
+
+    /// Returns the next complete frame, or `None` if the read timed out
+    /// before one arrived. A run of end-of-file reads fails with
+    /// `NotConnected`, so the port is abandoned and reopened.
+    ///
+    /// Frames already buffered are returned without touching the port, so
+    /// requests the firmware sent back to back in one chunk are each
+    /// answered, in the order they were sent.
+    pub fn read_frame<R: Read + ?Sized>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<String>, Error> {
+        loop {
+            if let Some(frame) = self.take_frame() {
+                return Ok(Some(String::from_utf8(frame)?));
+            }
+
+            let mut chunk = [0; 64];
+            match reader.read(&mut chunk) {
+                Ok(0) => {
+                    self.empty_reads += 1;
+                    if self.empty_reads >= MAX_EMPTY_READS {
+                        return Err(std::io::Error::new(
+                            ErrorKind::NotConnected,
+                            "device keeps reporting end of file",
+                        )
+                        .into());
+                    }
+                    return Ok(None);
+                }
+                Ok(size) => {
+                    self.empty_reads = 0;
+                    self.buffer.extend_from_slice(&chunk[..size]);
+                }
+                Err(error) if error.kind() == ErrorKind::TimedOut => {
+                    self.empty_reads = 0;
+                    return Ok(None);
+                }
+                Err(error) => {
+                    return Err(error.into());
+                }
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let end = self
+                .buffer
+                .iter()
+                .position(|byte| *byte == MESSAGE_END_BYTE)?;
+            let mut frame: Vec<u8> = self.buffer.drain(..=end).collect();
+            frame.pop();
+
+            if !self.synchronized {
+                self.synchronized = true;
+                continue;
+            }
+
+            // Firmware frames messages as "\n{...}\n", which yields empty
+            // frames between messages.
+            if !frame.is_empty() {
+                return Some(frame);
+            }
+        }
+    }
+}
+
+/// What to do after a frame that couldn't be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Resync {
+    /// Drop the bad frame and carry on with the next one.
+    Delimiter,
+    /// Also ignore everything until the firmware asks for the
+    /// configuration, for links where one bad frame means more follow.
+    Handshake,
+}
+
+/// Filters incoming messages according to the `Resync` strategy.
+pub struct Resynchronizer {
+    strategy: Resync,
+    waiting: bool,
+}
+
+impl Resynchronizer {
+    pub fn new(strategy: Resync) -> Resynchronizer {
+        return Resynchronizer {
+            strategy,
+            waiting: false,
+        };
+    }
+
+    /// Records a failure to read a frame. IO errors say nothing about
+    /// the framing and are ignored.
+    pub fn failed(&mut self, error: &Error) {
+        if matches!(error, Error::IO(_)) || self.strategy != Resync::Handshake || self.waiting {
+            return;
+        }
+
+        info!("Garbled frame; Waiting for the firmware to ask for the configuration");
+        self.waiting = true;
+    }
+
+    /// Whether to act on a decoded message.
+    pub fn accept(&mut self, message: &InMessage) -> bool {
+        if !self.waiting {
+            return true;
+        }
+
+        if let InMessage::NeedGaugeConfig {} = message {
+            info!("Resynchronized with the firmware");
+            self.waiting = false;
+            return true;
+        }
+
+        debug!("Ignoring {} while resynchronizing", message);
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out `chunks` one per read, then fails every read.
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            return Ok(chunk.len());
+        }
+    }
+
+    #[test]
+    fn buffered_frames_come_without_another_read() {
+        let mut port = Chunks(vec![b"\n{\"type\":2}\n{\"type\":1}\n"]);
+        let mut reader = FrameReader::new();
+
+        let first = reader.read_frame(&mut port).ok().flatten();
+        let second = reader.read_frame(&mut port).ok().flatten();
+
+        assert_eq!(first.as_deref(), Some("{\"type\":2}"));
+        assert_eq!(second.as_deref(), Some("{\"type\":1}"));
+        assert!(port.0.is_empty());
+    }
+
+    #[test]
+    fn partial_frame_waits_for_the_rest() {
+        let mut port = Chunks(vec![b"\n{\"ty", b"pe\":2}\n"]);
+        let mut reader = FrameReader::new();
+
+        let frame = reader.read_frame(&mut port).ok().flatten();
+
+        assert_eq!(frame.as_deref(), Some("{\"type\":2}"));
+    }
+
+    #[test]
+    fn connecting_mid_frame_drops_the_partial_frame() {
+        let mut port = Chunks(vec![b"pe\":2}\n{\"type\":1}\n"]);
+        let mut reader = FrameReader::new();
+
+        let frame = reader.read_frame(&mut port).ok().flatten();
+
+        assert_eq!(frame.as_deref(), Some("{\"type\":1}"));
+    }
+
+    fn garbled() -> Error {
+        return Error::UtfConversion(String::from_utf8(vec![0xff]).unwrap_err());
+    }
+
+    #[test]
+    fn delimiter_resync_carries_on_with_the_next_frame() {
+        let mut resynchronizer = Resynchronizer::new(Resync::Delimiter);
+
+        resynchronizer.failed(&garbled());
+
+        assert!(resynchronizer.accept(&InMessage::NeedGaugeData {}));
+    }
+
+    #[test]
+    fn handshake_resync_waits_for_a_config_request() {
+        let mut resynchronizer = Resynchronizer::new(Resync::Handshake);
+
+        // Lost bytes say nothing about the framing.
+        resynchronizer.failed(&Error::IO(ErrorKind::TimedOut.into()));
+        assert!(resynchronizer.accept(&InMessage::NeedGaugeData {}));
+
+        resynchronizer.failed(&garbled());
+        assert!(!resynchronizer.accept(&InMessage::NeedGaugeData {}));
+        assert!(resynchronizer.accept(&InMessage::NeedGaugeConfig {}));
+        assert!(resynchronizer.accept(&InMessage::NeedGaugeData {}));
+    }
+}
@@ -0,0 +1,120 @@
+//! Metric or imperial units on the displays. Sources always measure in
+//! metric; gauges in a known metric unit are converted, ranges and
+//! thresholds once when the configuration is loaded and readings as
+//! they are sampled, so values and color bands stay in step.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    /// °C, bar, km/h, ... as measured.
+    Metric,
+    /// °F, psi, mph, ...
+    Imperial,
+}
+
+/// Linear conversion from a metric unit to its imperial counterpart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conversion {
+    pub units: &'static str,
+    /// Replaces the gauge's `display_units`, if it has any.
+    pub display_units: &'static str,
+    scale: f32,
+    offset: f32,
+}
+
+const CELSIUS: Conversion = Conversion {
+    units: "F",
+    display_units: "°F",
+    scale: 1.8,
+    offset: 32.0,
+};
+
+/// Metric `units` of a gauge and how to show them in imperial units.
+const CONVERSIONS: [(&str, Conversion); 8] = [
+    ("C", CELSIUS),
+    ("°C", CELSIUS),
+    (
+        "bar",
+        Conversion {
+            units: "psi",
+            display_units: "psi",
+            scale: 14.503_774,
+            offset: 0.0,
+        },
+    ),
+    (
+        "kPa",
+        Conversion {
+            units: "psi",
+            display_units: "psi",
+            scale: 0.145_037_74,
+            offset: 0.0,
+        },
+    ),
+    (
+        "km/h",
+        Conversion {
+            units: "mph",
+            display_units: "mph",
+            scale: 0.621_371,
+            offset: 0.0,
+        },
+    ),
+    (
+        "km",
+        Conversion {
+            units: "mi",
+            display_units: "mi",
+            scale: 0.621_371,
+            offset: 0.0,
+        },
+    ),
+    (
+        "L",
+        Conversion {
+            units: "gal",
+            display_units: "gal",
+            scale: 0.264_172,
+            offset: 0.0,
+        },
+    ),
+    (
+        "m",
+        Conversion {
+            units: "ft",
+            display_units: "ft",
+            scale: 3.280_84,
+            offset: 0.0,
+        },
+    ),
+];
+
+impl Conversion {
+    /// How to show a gauge measured in `units` in imperial units; none for
+    /// units that are the same in both, such as rpm or %.
+    pub fn for_units(units: &str) -> Option<Conversion> {
+        return CONVERSIONS
+            .iter()
+            .find(|(metric, _)| *metric == units)
+            .map(|(_, conversion)| *conversion);
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        return value * self.scale + self.offset;
+    }
+
+    /// Back to the metric value, e.g. for sources working off the range.
+    pub fn revert(&self, value: f32) -> f32 {
+        return (value - self.offset) / self.scale;
+    }
+
+    /// For differences between values, such as a deadband.
+    pub fn apply_delta(&self, delta: f32) -> f32 {
+        return delta * self.scale;
+    }
+}